@@ -0,0 +1,14 @@
+#!/bin/sh
+# Compares capture and transmit rates of every backend on a veth pair.
+# Needs root, creates and removes the interfaces ants-bench0 and ants-bench1.
+set -e
+
+cd "$(dirname "$0")/.."
+cargo build --release --features tpacket-v3
+
+ip link add ants-bench0 type veth peer name ants-bench1
+trap 'ip link del ants-bench0' EXIT
+ip link set ants-bench0 up
+ip link set ants-bench1 up
+
+./target/release/ants -i ants-bench0 --benchmark ants-bench1 --log-level error
@@ -1,33 +1,49 @@
 extern crate pnet_base;
-extern crate pnet_datalink;
 extern crate pnet_packet;
 
+use crate::claims::{self, Claim, ClaimEvent, ClaimMacs, ClaimPolicy};
+use crate::config::DiscoveryConfig;
+use crate::control::PassiveMode;
+use crate::event_log::ReleaseReason;
+use crate::metrics::{self, Capture, Metrics};
+use crate::packet_decoder::{self, EthernetFrame, VlanTags};
+use crate::packet_io::{self, Frame, PacketIo};
+use crate::reload::Policies;
+use crate::shutdown::Shutdown;
 use pnet_base::MacAddr;
-use pnet_datalink::Channel::Ethernet;
-use pnet_datalink::{DataLinkReceiver, DataLinkSender};
 use pnet_packet::arp::{ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPacket};
-use pnet_packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet_packet::ethernet::{EtherTypes, MutableEthernetPacket};
 use pnet_packet::MutablePacket;
 use pnet_packet::Packet;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io;
 use std::net::{IpAddr, Ipv4Addr};
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 use std::thread;
 use std::time::{Duration, Instant};
-use tracing::{debug, error};
+use tracing::{debug, error, info};
+
+/// Unanswered ARP requests per (target, sender) pair
+type ArpRequestCounts = HashMap<(IpAddr, IpAddr), (u32, Instant)>;
 
 struct ArpInfo {
+    vlan_tags: VlanTags,
     sender_ip: Ipv4Addr,
     target_ip: Ipv4Addr,
     sender_mac: MacAddr,
     target_mac: MacAddr,
 }
 
-struct DataLinkChannel {
-    tx: Box<dyn DataLinkSender>,
-    rx: Box<dyn DataLinkReceiver>,
-    interface: pnet_datalink::NetworkInterface,
-    mac_address: MacAddr,
+/// ARP packets classified as in RFC 5227
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ArpKind {
+    /// Duplicate address detection, sender IP 0.0.0.0
+    Probe,
+    /// Gratuitous ARP claiming the sender IP, sender and target IP are equal
+    Announcement,
+    Request,
+    Reply,
+    Other,
 }
 
 pub trait ArpPacketTrait {
@@ -45,75 +61,268 @@ impl ArpPacketTrait for ArpPacket<'_> {
     }
 }
 
-/// Creates thread to handle arp requests and replies
-/// returns IPs which need to be tarpitted with mpsc channel
-pub fn start_arp_handling(interface_name: &str, passive_mode: bool) -> mpsc::Receiver<Ipv4Addr> {
-    let (tx, rx) = mpsc::channel();
+/// ARP side of ANTS without any I/O: learns unused IPs per VLAN,
+/// answers for them and releases them when their owner shows up
+pub struct ArpState {
+    arp_request_counts: HashMap<VlanTags, ArpRequestCounts>,
+    claimed: HashSet<Claim>,
+    discovery: DiscoveryConfig,
+    claim_policy: ClaimPolicy,
+    claim_macs: ClaimMacs,
+    passive_mode: PassiveMode,
+    metrics: Arc<Metrics>,
+}
+
+impl ArpState {
+    pub fn new(
+        discovery: DiscoveryConfig,
+        claim_policy: ClaimPolicy,
+        claim_macs: ClaimMacs,
+        passive_mode: PassiveMode,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        ArpState {
+            arp_request_counts: HashMap::new(),
+            claimed: HashSet::new(),
+            discovery,
+            claim_policy,
+            claim_macs,
+            passive_mode,
+            metrics,
+        }
+    }
+
+    /// Follows claims made or released elsewhere: restored from the state
+    /// file, sent on the control socket or learned by the other listener
+    pub fn apply_claim_event(&mut self, event: ClaimEvent) {
+        match event {
+            ClaimEvent::Claimed(claim) if claim.ip.is_ipv4() => {
+                self.claimed.insert(claim);
+            }
+            ClaimEvent::Claimed(_) => {}
+            ClaimEvent::Released(claim, _) => {
+                self.claimed.remove(&claim);
+            }
+        }
+    }
+
+    /// Thresholds of a reloaded configuration apply to IPs claimed from now
+    /// on, requests already counted are kept. Returns the releases of claimed
+    /// IPs its claim policy refuses.
+    pub fn apply_policies(&mut self, policies: &Policies) -> Vec<ClaimEvent> {
+        self.discovery = policies.discovery;
+        self.claim_policy = policies.claim_policy.clone();
+        claims::release_refused(&mut self.claimed, &self.claim_policy)
+    }
+
+    /// Runs one received frame through the ARP logic. Returns the resulting
+    /// claim event and, unless in passive mode, the ARP reply to send.
+    pub fn handle_frame(
+        &mut self,
+        frame: &[u8],
+        now: Instant,
+    ) -> Option<(ClaimEvent, Option<Vec<u8>>)> {
+        let ethernet_frame = packet_decoder::decode_ethernet(frame)?;
+        if is_arp_request(&ethernet_frame) {
+            Metrics::count(&self.metrics.arp_requests);
+        }
+        if let Some(claim) = release_claim(&ethernet_frame, &mut self.claimed, &self.claim_macs) {
+            return Some((
+                ClaimEvent::Released(claim, ReleaseReason::OwnerReturned),
+                None,
+            ));
+        }
 
-    let interface_name = interface_name.to_string();
+        let vlan_tags = ethernet_frame.vlan_tags;
+        // Requests for claimed IPs are answered at once, their owner is known to be gone
+        let request_threshold = if requests_claim(&ethernet_frame, &self.claimed) {
+            1
+        } else {
+            self.discovery.request_threshold
+        };
+        let arp_packet = process_arp_packet(
+            &ethernet_frame,
+            self.arp_request_counts.entry(vlan_tags).or_default(),
+            request_threshold,
+            self.discovery.request_timeout(),
+            now,
+        )?;
+        let arp_request_info = ArpInfo {
+            vlan_tags,
+            sender_ip: arp_packet.get_sender_proto_addr(),
+            target_ip: arp_packet.get_target_proto_addr(),
+            sender_mac: arp_packet.get_sender_hw_addr(),
+            target_mac: arp_packet.get_target_hw_addr(),
+        };
+
+        let claim = Claim {
+            vlan_tags,
+            ip: IpAddr::V4(arp_request_info.target_ip),
+        };
+        if let Err(refusal) = self.claim_policy.check(claim.ip) {
+            info!("Not claiming {}: {}", claim, refusal);
+            return None;
+        }
+        self.claimed.insert(claim);
+
+        let reply = if self.passive_mode.is_on() {
+            Metrics::count(&self.metrics.passive_suppressed);
+            None
+        } else {
+            Metrics::count(&self.metrics.arp_replies);
+            Some(create_arp_reply(
+                &arp_request_info,
+                self.claim_macs.mac_for(claim.ip),
+            ))
+        };
+        Some((ClaimEvent::Claimed(claim), reply))
+    }
+}
 
+/// Creates thread to handle arp requests and replies read from packet_io
+/// sends IPs which need to be tarpitted to claim_sender,
+/// IPs refused by the claim policy are neither answered nor claimed
+/// each VLAN seen on the interface is learned separately
+/// a claimed IP is released as soon as another host uses it in ARP
+pub fn start_arp_handling(
+    mut packet_io: Box<dyn PacketIo>,
+    mut arp_state: ArpState,
+    claim_sender: mpsc::Sender<ClaimEvent>,
+    claim_updates: mpsc::Receiver<ClaimEvent>,
+    policy_updates: mpsc::Receiver<Arc<Policies>>,
+    metrics: Arc<Metrics>,
+    shutdown: Shutdown,
+) -> thread::JoinHandle<()> {
     thread::spawn(move || {
-        let mut arp_request_counts: HashMap<(IpAddr, IpAddr), (u32, Instant)> = HashMap::new();
-        let mut channel = open_channel(interface_name);
+        let filter = packet_io::with_vlans("arp", packet_decoder::MAX_VLAN_TAGS);
+        if let Err(e) = packet_io.set_filter(&filter) {
+            error!("Failed to install ARP capture filter: {}", e);
+        }
+
+        let mut last_drops_poll = Instant::now();
         loop {
-            let arp_request_info = listen_arp(&mut arp_request_counts, &mut channel);
-            send_arp_reply(&arp_request_info, passive_mode, &mut channel);
+            if shutdown.is_requested() {
+                debug!("Shutting down, exiting ARP handling thread.");
+                break;
+            }
+            metrics::poll_capture_drops(
+                &metrics,
+                Capture::Arp,
+                packet_io.as_mut(),
+                &mut last_drops_poll,
+                Instant::now(),
+            );
+            // Applied before reading, so they arrive even when no frames pass the filter
+            while let Ok(event) = claim_updates.try_recv() {
+                arp_state.apply_claim_event(event);
+            }
+            while let Ok(policies) = policy_updates.try_recv() {
+                for event in arp_state.apply_policies(&policies) {
+                    let _ = claim_sender.send(event);
+                }
+            }
+            let frame = match packet_io.recv() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => {
+                    debug!("Capture ended, exiting ARP handling thread.");
+                    break;
+                }
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => {
+                    error!("An error occurred while reading: {}", e);
+                    continue;
+                }
+            };
+            let Some((event, reply)) = arp_state.handle_frame(frame.data, Instant::now()) else {
+                continue;
+            };
+            if let Some(reply) = reply {
+                if let Err(e) = packet_io.send(Frame::now(&reply)) {
+                    error!("Failed to send ARP reply: {}", e);
+                }
+            }
 
-            if tx.send(arp_request_info.target_ip).is_err() {
+            if claim_sender.send(event).is_err() {
                 error!("Receiver dropped, exiting ARP handling thread.");
                 break;
             }
         }
-    });
+    })
+}
 
-    rx
+fn is_arp_request(ethernet_frame: &EthernetFrame<'_>) -> bool {
+    ethernet_frame.ethertype == EtherTypes::Arp
+        && ArpPacket::new(ethernet_frame.payload)
+            .is_some_and(|arp_packet| classify_arp(&arp_packet) == ArpKind::Request)
 }
 
-fn listen_arp(
-    arp_request_counts: &mut HashMap<(IpAddr, IpAddr), (u32, Instant)>,
-    channel: &mut DataLinkChannel,
-) -> ArpInfo {
-    // A map to track ARP request counts for each target IP address
-    let request_threshold: u32 = 2;
-    let request_timeout = Duration::from_secs(5);
-
-    loop {
-        match channel.rx.next() {
-            Ok(packet) => {
-                let ethernet_packet = EthernetPacket::new(packet).unwrap();
-                if let Some(arp_packet) = process_arp_packet(
-                    &ethernet_packet,
-                    arp_request_counts,
-                    request_threshold,
-                    request_timeout,
-                ) {
-                    let sender_ip: Ipv4Addr = arp_packet.get_sender_proto_addr();
-                    let target_ip: Ipv4Addr = arp_packet.get_target_proto_addr();
-                    let sender_mac: MacAddr = arp_packet.get_sender_hw_addr();
-                    let target_mac: MacAddr = arp_packet.get_target_hw_addr();
-                    return ArpInfo {
-                        sender_ip,
-                        target_ip,
-                        sender_mac,
-                        target_mac,
-                    };
-                }
-            }
-            Err(e) => {
-                error!("An error occurred while reading: {}", e);
-            }
-        }
+fn requests_claim(ethernet_frame: &EthernetFrame<'_>, claimed: &HashSet<Claim>) -> bool {
+    ethernet_frame.ethertype == EtherTypes::Arp
+        && ArpPacket::new(ethernet_frame.payload).is_some_and(|arp_packet| {
+            classify_arp(&arp_packet) == ArpKind::Request
+                && claimed.contains(&Claim {
+                    vlan_tags: ethernet_frame.vlan_tags,
+                    ip: IpAddr::V4(arp_packet.get_target_proto_addr()),
+                })
+        })
+}
+
+/// Gives back a claimed IP when another MAC sends ARP from it,
+/// be it an announcement, a request or a reply, or probes for it
+fn release_claim(
+    ethernet_frame: &EthernetFrame<'_>,
+    claimed: &mut HashSet<Claim>,
+    claim_macs: &ClaimMacs,
+) -> Option<Claim> {
+    if ethernet_frame.ethertype != EtherTypes::Arp {
+        return None;
+    }
+    let arp_packet = ArpPacket::new(ethernet_frame.payload)?;
+    if arp_packet.get_hardware_type() != ArpHardwareTypes::Ethernet {
+        return None;
+    }
+
+    // A probing host is about to take the address, it is not defended
+    let used_ip = match classify_arp(&arp_packet) {
+        ArpKind::Probe => arp_packet.get_target_proto_addr(),
+        _ => arp_packet.get_sender_proto_addr(),
+    };
+    let claim = Claim {
+        vlan_tags: ethernet_frame.vlan_tags,
+        ip: IpAddr::V4(used_ip),
+    };
+    if claim_macs.is_own(arp_packet.get_sender_hw_addr(), claim.ip) || !claimed.remove(&claim) {
+        return None;
     }
+    debug!(
+        "{} is in use by {}, releasing it",
+        claim,
+        arp_packet.get_sender_hw_addr()
+    );
+    Some(claim)
 }
 
-fn send_arp_reply(arp_request_info: &ArpInfo, passive_mode: bool, channel: &mut DataLinkChannel) {
+/// Builds the reply claiming the requested IP for mac_address
+fn create_arp_reply(arp_request_info: &ArpInfo, mac_address: MacAddr) -> Vec<u8> {
     let arp_reply_info = ArpInfo {
+        vlan_tags: arp_request_info.vlan_tags,
         sender_ip: arp_request_info.target_ip,
         target_ip: arp_request_info.sender_ip,
-        sender_mac: channel.mac_address,
+        sender_mac: mac_address,
         target_mac: arp_request_info.sender_mac,
     };
 
+    debug!(
+        "ARP reply: {} is at {} to {} on {}",
+        arp_reply_info.sender_ip,
+        arp_reply_info.sender_mac,
+        arp_reply_info.target_ip,
+        arp_reply_info.vlan_tags
+    );
+    create_arp_reply_frame(&arp_reply_info)
+}
+
+fn create_arp_reply_frame(arp_reply_info: &ArpInfo) -> Vec<u8> {
     let mut ethernet_buffer = [0u8; 42]; // 14 bytes for Ethernet + 28 bytes for ARP
     let mut ethernet_packet = MutableEthernetPacket::new(&mut ethernet_buffer).unwrap();
 
@@ -121,47 +330,31 @@ fn send_arp_reply(arp_request_info: &ArpInfo, passive_mode: bool, channel: &mut
     ethernet_packet.set_source(arp_reply_info.sender_mac);
     ethernet_packet.set_ethertype(EtherTypes::Arp);
 
-    create_arp_packet(&mut ethernet_packet, &arp_reply_info);
-
-    if !passive_mode {
-        let _ = channel
-            .tx
-            .send_to(ethernet_packet.packet(), Some(channel.interface.clone()))
-            .expect("Failed to send ARP reply");
-    }
+    create_arp_packet(&mut ethernet_packet, arp_reply_info);
 
-    debug!(
-        "Sent ARP reply: {} is at {:?} from {}",
-        arp_reply_info.target_ip, arp_reply_info.target_ip, arp_reply_info.sender_ip
-    );
+    packet_decoder::tag_frame(ethernet_packet.packet(), arp_reply_info.vlan_tags)
 }
 
-fn open_channel(interface_name: String) -> DataLinkChannel {
-    let interface = get_interface(&interface_name);
-    let (tx, rx) = match pnet_datalink::channel(&interface, Default::default()) {
-        Ok(Ethernet(tx, rx)) => (tx, rx),
-        Ok(_) => panic!("Unhandled channel type"),
-        Err(e) => panic!("Error creating channel: {}", e),
+/// Gratuitous ARP broadcast from mac_address announcing ip at
+/// 00:00:00:00:00:00, hosts that take it stop sending the IP's traffic to ANTS
+pub fn create_claim_withdrawal(ip: Ipv4Addr, vlan_tags: VlanTags, mac_address: MacAddr) -> Vec<u8> {
+    let withdrawal_info = ArpInfo {
+        vlan_tags,
+        sender_ip: ip,
+        target_ip: ip,
+        sender_mac: MacAddr::zero(),
+        target_mac: MacAddr::broadcast(),
     };
+    let mut ethernet_buffer = [0u8; 42];
+    let mut ethernet_packet = MutableEthernetPacket::new(&mut ethernet_buffer).unwrap();
 
-    let mac_address = interface.mac.unwrap_or_default();
-
-    DataLinkChannel {
-        tx,
-        rx,
-        interface,
-        mac_address,
-    }
-}
+    ethernet_packet.set_destination(MacAddr::broadcast());
+    ethernet_packet.set_source(mac_address);
+    ethernet_packet.set_ethertype(EtherTypes::Arp);
 
-fn get_interface(interface_name: &str) -> pnet_datalink::NetworkInterface {
-    let interfaces = pnet_datalink::interfaces();
-    let interface: pnet_datalink::NetworkInterface = interfaces
-        .into_iter()
-        .find(|iface| iface.name == interface_name)
-        .unwrap_or_else(|| panic!("No such interface: {}", interface_name));
+    create_arp_packet(&mut ethernet_packet, &withdrawal_info);
 
-    interface
+    packet_decoder::tag_frame(ethernet_packet.packet(), vlan_tags)
 }
 
 fn create_arp_packet(ethernet_packet: &mut MutableEthernetPacket, arp_reply_info: &ArpInfo) {
@@ -179,13 +372,13 @@ fn create_arp_packet(ethernet_packet: &mut MutableEthernetPacket, arp_reply_info
 
 fn track_arp_request(
     arp_packet: &dyn ArpPacketTrait,
-    arp_request_count: &mut HashMap<(IpAddr, IpAddr), (u32, Instant)>,
+    arp_request_count: &mut ArpRequestCounts,
     request_threshold: u32,
     request_timeout: Duration,
+    now: Instant,
 ) -> bool {
     let target_ip = arp_packet.get_target_proto_addr();
     let sender_ip = arp_packet.get_sender_proto_addr();
-    let now = Instant::now();
     let entry = arp_request_count
         .entry((IpAddr::V4(target_ip), IpAddr::V4(sender_ip)))
         .or_insert((0, now));
@@ -209,13 +402,32 @@ fn track_arp_request(
     false
 }
 
+fn classify_arp(arp_packet: &ArpPacket) -> ArpKind {
+    let sender_ip = arp_packet.get_sender_proto_addr();
+    let target_ip = arp_packet.get_target_proto_addr();
+
+    match arp_packet.get_operation() {
+        ArpOperations::Request if sender_ip.is_unspecified() => ArpKind::Probe,
+        ArpOperations::Request | ArpOperations::Reply if sender_ip == target_ip => {
+            ArpKind::Announcement
+        }
+        ArpOperations::Request => ArpKind::Request,
+        ArpOperations::Reply => ArpKind::Reply,
+        _ => ArpKind::Other,
+    }
+}
+
 fn process_arp_packet<'a>(
-    ethernet_packet: &'a EthernetPacket<'a>,
-    arp_request_count: &'a mut HashMap<(IpAddr, IpAddr), (u32, Instant)>,
+    ethernet_frame: &EthernetFrame<'a>,
+    arp_request_count: &mut ArpRequestCounts,
     request_threshold: u32,
     request_timeout: Duration,
+    now: Instant,
 ) -> Option<ArpPacket<'a>> {
-    if let Some(arp_packet) = ArpPacket::new(ethernet_packet.payload()) {
+    if ethernet_frame.ethertype != EtherTypes::Arp {
+        return None;
+    }
+    if let Some(arp_packet) = ArpPacket::new(ethernet_frame.payload) {
         //Discard invalid packets
         if arp_packet.get_hardware_type() != pnet_packet::arp::ArpHardwareType(1) {
             return None;
@@ -224,25 +436,39 @@ fn process_arp_packet<'a>(
         let sender_ip = arp_packet.get_sender_proto_addr();
         let sender_hw = arp_packet.get_sender_hw_addr();
 
-        match arp_packet.get_operation() {
-            ArpOperations::Request => {
+        // Any ARP from a host answers the requests for its IP
+        if !sender_ip.is_unspecified() {
+            arp_request_count.retain(|(target, _), _| *target != IpAddr::V4(sender_ip));
+        }
+
+        match classify_arp(&arp_packet) {
+            ArpKind::Probe => {
+                // Never defend an address against duplicate address detection,
+                // the prober is about to use it
+                debug!("ARP Probe: {:?} is probing for {}", sender_hw, target_ip);
+                arp_request_count.retain(|(target, _), _| *target != IpAddr::V4(target_ip));
+            }
+            ArpKind::Announcement => {
+                debug!("ARP Announcement: {} is at {:?}", sender_ip, sender_hw);
+            }
+            ArpKind::Request => {
                 debug!("ARP Request: {} is asking for {}", sender_ip, target_ip);
                 let threshold_exceeded: bool = track_arp_request(
                     &arp_packet,
                     arp_request_count,
                     request_threshold,
                     request_timeout,
+                    now,
                 );
 
                 if threshold_exceeded {
                     return Some(arp_packet);
                 }
             }
-            ArpOperations::Reply => {
+            ArpKind::Reply => {
                 debug!("ARP Reply: {} is at {:?}", sender_ip, sender_hw);
-                arp_request_count.remove(&(IpAddr::V4(target_ip), IpAddr::V4(sender_ip)));
             }
-            _ => {}
+            ArpKind::Other => {}
         }
     }
     None
@@ -251,9 +477,11 @@ fn process_arp_packet<'a>(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::{Config, MacConfig, MacMode};
+    use crate::packet_decoder::decode_ethernet;
     use pnet::packet::arp::{ArpHardwareType, ArpOperation, ArpOperations, MutableArpPacket};
-    use pnet::packet::ethernet::{EthernetPacket, MutableEthernetPacket};
-    use std::collections::HashMap;
+    use pnet::packet::ethernet::MutableEthernetPacket;
+    use std::collections::{HashMap, HashSet};
     use std::net::{IpAddr, Ipv4Addr};
     use std::time::{Duration, Instant};
 
@@ -266,7 +494,7 @@ mod tests {
         operation: ArpOperation,
         sender_ip: Ipv4Addr,
         target_ip: Ipv4Addr,
-    ) -> EthernetPacket<'static> {
+    ) -> Vec<u8> {
         let mut packet_data = vec![0u8; TOTAL_PACKET_LEN];
         {
             let mut eth_packet = MutableEthernetPacket::new(&mut packet_data).unwrap();
@@ -280,12 +508,12 @@ mod tests {
             arp_packet.set_sender_proto_addr(sender_ip);
             arp_packet.set_target_proto_addr(target_ip);
         }
-        EthernetPacket::owned(packet_data).unwrap()
+        packet_data
     }
 
     #[test]
     fn test_process_arp_packet_request_below_threshold() {
-        let ethernet_packet = create_ethernet_with_arp(
+        let packet = create_ethernet_with_arp(
             ArpHardwareType(1),
             ArpOperations::Request,
             Ipv4Addr::new(192, 168, 0, 100),
@@ -296,11 +524,13 @@ mod tests {
         let request_threshold = 3;
         let request_timeout = Duration::from_secs(10);
 
+        let ethernet_frame = decode_ethernet(&packet).unwrap();
         let result = process_arp_packet(
-            &ethernet_packet,
+            &ethernet_frame,
             &mut arp_request_count,
             request_threshold,
             request_timeout,
+            Instant::now(),
         );
 
         assert!(result.is_none());
@@ -316,7 +546,7 @@ mod tests {
 
     #[test]
     fn test_process_arp_packet_request_exceed_threshold() {
-        let ethernet_packet = create_ethernet_with_arp(
+        let packet = create_ethernet_with_arp(
             ArpHardwareType(1),
             ArpOperations::Request,
             Ipv4Addr::new(192, 168, 0, 100),
@@ -336,11 +566,13 @@ mod tests {
             (2, Instant::now()),
         );
 
+        let ethernet_frame = decode_ethernet(&packet).unwrap();
         let result = process_arp_packet(
-            &ethernet_packet,
+            &ethernet_frame,
             &mut arp_request_count,
             request_threshold,
             request_timeout,
+            Instant::now(),
         );
 
         assert!(result.is_some());
@@ -348,11 +580,12 @@ mod tests {
 
     #[test]
     fn test_process_arp_packet_reply() {
-        let ethernet_packet = create_ethernet_with_arp(
+        // 192.168.0.1 answers the requests from 192.168.0.100
+        let packet = create_ethernet_with_arp(
             ArpHardwareType(1),
             ArpOperations::Reply,
-            Ipv4Addr::new(192, 168, 0, 100),
             Ipv4Addr::new(192, 168, 0, 1),
+            Ipv4Addr::new(192, 168, 0, 100),
         );
 
         let mut arp_request_count = HashMap::new();
@@ -368,11 +601,13 @@ mod tests {
             (2, Instant::now()),
         );
 
+        let ethernet_frame = decode_ethernet(&packet).unwrap();
         let result = process_arp_packet(
-            &ethernet_packet,
+            &ethernet_frame,
             &mut arp_request_count,
             request_threshold,
             request_timeout,
+            Instant::now(),
         );
 
         assert!(result.is_none());
@@ -381,7 +616,7 @@ mod tests {
 
     #[test]
     fn test_process_arp_packet_invalid_hardware_type() {
-        let ethernet_packet = create_ethernet_with_arp(
+        let packet = create_ethernet_with_arp(
             ArpHardwareType(2), // Invalid hardware type
             ArpOperations::Request,
             Ipv4Addr::new(192, 168, 0, 100),
@@ -392,17 +627,103 @@ mod tests {
         let request_threshold = 3;
         let request_timeout = Duration::from_secs(10);
 
+        let ethernet_frame = decode_ethernet(&packet).unwrap();
         let result = process_arp_packet(
-            &ethernet_packet,
+            &ethernet_frame,
             &mut arp_request_count,
             request_threshold,
             request_timeout,
+            Instant::now(),
         );
 
         assert!(result.is_none());
         assert!(arp_request_count.is_empty());
     }
 
+    #[test]
+    fn test_process_arp_packet_tagged_request() {
+        let packet = create_ethernet_with_arp(
+            ArpHardwareType(1),
+            ArpOperations::Request,
+            Ipv4Addr::new(192, 168, 0, 100),
+            Ipv4Addr::new(192, 168, 0, 1),
+        );
+        let vlan_tags = vlan_tags(&[100]);
+        let tagged = packet_decoder::tag_frame(&packet, vlan_tags);
+
+        let mut arp_request_count = HashMap::new();
+        let ethernet_frame = decode_ethernet(&tagged).unwrap();
+        let result = process_arp_packet(
+            &ethernet_frame,
+            &mut arp_request_count,
+            1,
+            Duration::from_secs(10),
+            Instant::now(),
+        );
+
+        assert_eq!(ethernet_frame.vlan_tags, vlan_tags);
+        assert_eq!(
+            result.unwrap().get_target_proto_addr(),
+            Ipv4Addr::new(192, 168, 0, 1)
+        );
+    }
+
+    #[test]
+    fn test_process_arp_packet_not_arp() {
+        let mut packet = create_ethernet_with_arp(
+            ArpHardwareType(1),
+            ArpOperations::Request,
+            Ipv4Addr::new(192, 168, 0, 100),
+            Ipv4Addr::new(192, 168, 0, 1),
+        );
+        packet[12..14].copy_from_slice(&EtherTypes::Ipv4.0.to_be_bytes());
+
+        let mut arp_request_count = HashMap::new();
+        let ethernet_frame = decode_ethernet(&packet).unwrap();
+        let result = process_arp_packet(
+            &ethernet_frame,
+            &mut arp_request_count,
+            1,
+            Duration::from_secs(10),
+            Instant::now(),
+        );
+
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_create_arp_reply_frame_keeps_vlan_tags() {
+        let arp_reply_info = ArpInfo {
+            vlan_tags: vlan_tags(&[10, 200]),
+            sender_ip: Ipv4Addr::new(192, 168, 0, 1),
+            target_ip: Ipv4Addr::new(192, 168, 0, 100),
+            sender_mac: MacAddr::new(0x00, 0x11, 0x22, 0x33, 0x44, 0x55),
+            target_mac: MacAddr::new(0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB),
+        };
+
+        let frame = create_arp_reply_frame(&arp_reply_info);
+
+        let ethernet_frame = decode_ethernet(&frame).unwrap();
+        assert_eq!(ethernet_frame.vlan_tags, arp_reply_info.vlan_tags);
+        assert_eq!(ethernet_frame.ethertype, EtherTypes::Arp);
+        assert_eq!(ethernet_frame.destination, arp_reply_info.target_mac);
+        let arp_packet = ArpPacket::new(ethernet_frame.payload).unwrap();
+        assert_eq!(arp_packet.get_operation(), ArpOperations::Reply);
+        assert_eq!(arp_packet.get_sender_proto_addr(), arp_reply_info.sender_ip);
+        assert_eq!(arp_packet.get_sender_hw_addr(), arp_reply_info.sender_mac);
+    }
+
+    fn vlan_tags(vids: &[u16]) -> VlanTags {
+        let mut vlan_tags = VlanTags::default();
+        for vid in vids {
+            vlan_tags.push(packet_decoder::VlanTag {
+                tpid: EtherTypes::Vlan.0,
+                vid: *vid,
+            });
+        }
+        vlan_tags
+    }
+
     // Mock implementation of the ArpPacketTrait for testing
     struct MockArpPacket {
         target_proto_addr: Ipv4Addr,
@@ -444,9 +765,10 @@ mod tests {
             &mut arp_request_count,
             request_threshold,
             request_timeout,
+            Instant::now(),
         );
 
-        assert_eq!(result, false);
+        assert!(!result);
         assert_eq!(
             arp_request_count[&(
                 IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)),
@@ -482,9 +804,10 @@ mod tests {
             &mut arp_request_count,
             request_threshold,
             request_timeout,
+            Instant::now(),
         );
 
-        assert_eq!(result, true);
+        assert!(result);
         assert!(arp_request_count.is_empty());
     }
 
@@ -512,9 +835,10 @@ mod tests {
             &mut arp_request_count,
             request_threshold,
             request_timeout,
+            Instant::now(),
         );
 
-        assert_eq!(result, false);
+        assert!(!result);
         assert_eq!(
             arp_request_count[&(
                 IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)),
@@ -524,4 +848,269 @@ mod tests {
             1
         );
     }
+
+    #[test]
+    fn test_process_arp_packet_gratuitous_is_not_claimed() {
+        let packet = create_ethernet_with_arp(
+            ArpHardwareType(1),
+            ArpOperations::Request,
+            Ipv4Addr::new(192, 168, 0, 1),
+            Ipv4Addr::new(192, 168, 0, 1),
+        );
+
+        let mut arp_request_count = HashMap::new();
+        let ethernet_frame = decode_ethernet(&packet).unwrap();
+        for _ in 0..3 {
+            let result = process_arp_packet(
+                &ethernet_frame,
+                &mut arp_request_count,
+                2,
+                Duration::from_secs(10),
+                Instant::now(),
+            );
+            assert!(result.is_none());
+        }
+    }
+
+    #[test]
+    fn test_release_claim_from_other_mac() {
+        let mut packet = create_ethernet_with_arp(
+            ArpHardwareType(1),
+            ArpOperations::Reply,
+            Ipv4Addr::new(192, 168, 0, 1),
+            Ipv4Addr::new(192, 168, 0, 100),
+        );
+        let owner_mac = MacAddr::new(0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB);
+        MutableArpPacket::new(&mut packet[ETH_HEADER_LEN..])
+            .unwrap()
+            .set_sender_hw_addr(owner_mac);
+        let claim = Claim {
+            vlan_tags: VlanTags::default(),
+            ip: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)),
+        };
+        let mut claimed = HashSet::from([claim]);
+        let ethernet_frame = decode_ethernet(&packet).unwrap();
+
+        // ARP sent by ANTS itself does not release anything
+        let own_macs = ClaimMacs::new(MacConfig::default(), owner_mac);
+        assert!(release_claim(&ethernet_frame, &mut claimed, &own_macs).is_none());
+        let other_macs = ClaimMacs::new(MacConfig::default(), MacAddr::zero());
+        assert_eq!(
+            release_claim(&ethernet_frame, &mut claimed, &other_macs),
+            Some(claim)
+        );
+        assert!(claimed.is_empty());
+    }
+
+    #[test]
+    fn test_release_claim_other_vlan() {
+        let packet = create_ethernet_with_arp(
+            ArpHardwareType(1),
+            ArpOperations::Request,
+            Ipv4Addr::new(192, 168, 0, 1),
+            Ipv4Addr::new(192, 168, 0, 1),
+        );
+        let claim = Claim {
+            vlan_tags: vlan_tags(&[10]),
+            ip: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)),
+        };
+        let mut claimed = HashSet::from([claim]);
+        let claim_macs = ClaimMacs::new(
+            MacConfig::default(),
+            MacAddr::new(0x00, 0x11, 0x22, 0x33, 0x44, 0x55),
+        );
+        let ethernet_frame = decode_ethernet(&packet).unwrap();
+
+        assert!(release_claim(&ethernet_frame, &mut claimed, &claim_macs).is_none());
+        assert_eq!(claimed.len(), 1);
+    }
+
+    #[test]
+    fn test_classify_arp() {
+        let unspecified = Ipv4Addr::UNSPECIFIED;
+        let host = Ipv4Addr::new(192, 168, 0, 1);
+        let other = Ipv4Addr::new(192, 168, 0, 100);
+        let cases = [
+            (ArpOperations::Request, unspecified, host, ArpKind::Probe),
+            (ArpOperations::Request, host, host, ArpKind::Announcement),
+            (ArpOperations::Reply, host, host, ArpKind::Announcement),
+            (ArpOperations::Request, other, host, ArpKind::Request),
+            (ArpOperations::Reply, host, other, ArpKind::Reply),
+            (ArpOperation(3), other, host, ArpKind::Other),
+        ];
+
+        for (operation, sender_ip, target_ip, kind) in cases {
+            let packet =
+                create_ethernet_with_arp(ArpHardwareType(1), operation, sender_ip, target_ip);
+            let arp_packet = ArpPacket::new(&packet[ETH_HEADER_LEN..]).unwrap();
+            assert_eq!(classify_arp(&arp_packet), kind);
+        }
+    }
+
+    #[test]
+    fn test_process_arp_packet_probe_is_never_answered() {
+        let request = create_ethernet_with_arp(
+            ArpHardwareType(1),
+            ArpOperations::Request,
+            Ipv4Addr::new(192, 168, 0, 100),
+            Ipv4Addr::new(192, 168, 0, 1),
+        );
+        let probe = create_ethernet_with_arp(
+            ArpHardwareType(1),
+            ArpOperations::Request,
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::new(192, 168, 0, 1),
+        );
+
+        let mut arp_request_count = HashMap::new();
+        let request_frame = decode_ethernet(&request).unwrap();
+        let probe_frame = decode_ethernet(&probe).unwrap();
+        let timeout = Duration::from_secs(10);
+
+        assert!(process_arp_packet(
+            &request_frame,
+            &mut arp_request_count,
+            2,
+            timeout,
+            Instant::now()
+        )
+        .is_none());
+        // Probes neither count towards the threshold nor leave earlier requests counted
+        for _ in 0..3 {
+            assert!(process_arp_packet(
+                &probe_frame,
+                &mut arp_request_count,
+                1,
+                timeout,
+                Instant::now()
+            )
+            .is_none());
+        }
+        assert!(arp_request_count.is_empty());
+    }
+
+    #[test]
+    fn test_release_claim_on_probe() {
+        let packet = create_ethernet_with_arp(
+            ArpHardwareType(1),
+            ArpOperations::Request,
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::new(192, 168, 0, 1),
+        );
+        let claim = Claim {
+            vlan_tags: VlanTags::default(),
+            ip: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)),
+        };
+        let mut claimed = HashSet::from([claim]);
+        let claim_macs = ClaimMacs::new(
+            MacConfig::default(),
+            MacAddr::new(0x00, 0x11, 0x22, 0x33, 0x44, 0x55),
+        );
+        let ethernet_frame = decode_ethernet(&packet).unwrap();
+
+        assert_eq!(
+            release_claim(&ethernet_frame, &mut claimed, &claim_macs),
+            Some(claim)
+        );
+    }
+
+    #[test]
+    fn test_virtual_mac_replies_do_not_release_their_claim() {
+        let mac_config = MacConfig {
+            mode: MacMode::Virtual,
+            ..MacConfig::default()
+        };
+        let claim_macs =
+            ClaimMacs::new(mac_config, MacAddr::new(0x00, 0x11, 0x22, 0x33, 0x44, 0x55));
+        let mut arp_state = ArpState::new(
+            DiscoveryConfig::default(),
+            ClaimPolicy::default(),
+            claim_macs,
+            PassiveMode::default(),
+            Arc::new(Metrics::default()),
+        );
+        let request = create_ethernet_with_arp(
+            ArpHardwareType(1),
+            ArpOperations::Request,
+            Ipv4Addr::new(192, 168, 0, 100),
+            Ipv4Addr::new(192, 168, 0, 2),
+        );
+        let now = Instant::now();
+
+        assert!(arp_state.handle_frame(&request, now).is_none());
+        let (_, reply) = arp_state.handle_frame(&request, now).unwrap();
+        let reply = reply.unwrap();
+        let arp_reply = ArpPacket::new(&reply[ETH_HEADER_LEN..]).unwrap();
+        let decoy_ip = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2));
+        assert_eq!(arp_reply.get_sender_hw_addr(), claim_macs.mac_for(decoy_ip));
+        assert_ne!(
+            arp_reply.get_sender_hw_addr(),
+            MacAddr::new(0x00, 0x11, 0x22, 0x33, 0x44, 0x55)
+        );
+
+        // The reply comes back through the capture
+        assert!(arp_state.handle_frame(&reply, now).is_none());
+        assert!(arp_state.claimed.contains(&Claim {
+            vlan_tags: VlanTags::default(),
+            ip: decoy_ip,
+        }));
+    }
+
+    #[test]
+    fn test_restored_claim_answered_at_once() {
+        let mut arp_state = ArpState::new(
+            DiscoveryConfig::default(),
+            ClaimPolicy::default(),
+            ClaimMacs::new(MacConfig::default(), MacAddr::zero()),
+            PassiveMode::default(),
+            Arc::new(Metrics::default()),
+        );
+        let claim = Claim {
+            vlan_tags: VlanTags::default(),
+            ip: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)),
+        };
+        arp_state.apply_claim_event(ClaimEvent::Claimed(claim));
+        let request = create_ethernet_with_arp(
+            ArpHardwareType(1),
+            ArpOperations::Request,
+            Ipv4Addr::new(192, 168, 0, 100),
+            Ipv4Addr::new(192, 168, 0, 2),
+        );
+
+        let (event, reply) = arp_state.handle_frame(&request, Instant::now()).unwrap();
+
+        assert_eq!(event, ClaimEvent::Claimed(claim));
+        assert!(reply.is_some());
+    }
+
+    #[test]
+    fn test_reload_releases_refused_claims() {
+        let mut arp_state = ArpState::new(
+            DiscoveryConfig::default(),
+            ClaimPolicy::default(),
+            ClaimMacs::new(MacConfig::default(), MacAddr::zero()),
+            PassiveMode::default(),
+            Arc::new(Metrics::default()),
+        );
+        let refused = Claim {
+            vlan_tags: VlanTags::default(),
+            ip: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)),
+        };
+        let kept = Claim {
+            vlan_tags: VlanTags::default(),
+            ip: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)),
+        };
+        arp_state.apply_claim_event(ClaimEvent::Claimed(refused));
+        arp_state.apply_claim_event(ClaimEvent::Claimed(kept));
+        let mut config = Config::default();
+        config.claims.never_claim = vec!["192.168.0.0/24".parse().unwrap()];
+
+        let released = arp_state.apply_policies(&Policies::new(&config));
+
+        assert_eq!(
+            released,
+            [ClaimEvent::Released(refused, ReleaseReason::Policy)]
+        );
+        assert_eq!(arp_state.claimed, HashSet::from([kept]));
+    }
 }
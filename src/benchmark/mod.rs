@@ -0,0 +1,103 @@
+// measures how many frames per second each capture backend receives and
+// sends, meant to be run on a veth pair as benches/veth.sh does
+use crate::config::Backend;
+use crate::packet_io::{self, Frame};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::error;
+
+/// How long each backend is measured in each direction
+const MEASURE_DURATION: Duration = Duration::from_secs(5);
+
+/// Frames handed to send_batch at once
+const BATCH_SIZE: usize = 64;
+
+/// Broadcast frame with the local experimental ethertype, nothing answers it
+fn benchmark_frame() -> [u8; 60] {
+    let mut frame = [0u8; 60];
+    frame[..6].copy_from_slice(&[0xFF; 6]);
+    frame[6..12].copy_from_slice(&[0x02, 0x00, 0x00, 0x00, 0x00, 0x03]);
+    frame[12..14].copy_from_slice(&[0x88, 0xB5]);
+    frame
+}
+
+/// Prints receive and transmit rates of every backend on interface_name,
+/// peer_name is the other end of the link and carries the generated traffic
+pub fn run_benchmark(interface_name: &str, peer_name: &str) {
+    println!(
+        "{:<10}{:>16}{:>16}",
+        "backend", "rx frames/s", "tx frames/s"
+    );
+    for &backend in Backend::ALL {
+        let received = measure_receive(backend, interface_name, peer_name);
+        let sent = measure_transmit(backend, interface_name);
+        println!(
+            "{:<10}{:>16}{:>16}",
+            backend.to_string(),
+            format_rate(received),
+            format_rate(sent)
+        );
+    }
+}
+
+fn format_rate(rate: io::Result<f64>) -> String {
+    match rate {
+        Ok(rate) => format!("{:.0}", rate),
+        Err(e) => {
+            error!("Benchmark failed: {}", e);
+            "failed".to_string()
+        }
+    }
+}
+
+/// Floods the peer with the fastest sender of this build and counts
+/// the frames the backend receives
+fn measure_receive(backend: Backend, interface_name: &str, peer_name: &str) -> io::Result<f64> {
+    let mut receiver = packet_io::try_open(backend, interface_name, false)?;
+    let mut generator = packet_io::try_open(*Backend::ALL.last().unwrap(), peer_name, false)?;
+
+    let running = Arc::new(AtomicBool::new(true));
+    let generating = running.clone();
+    let generator_thread = thread::spawn(move || {
+        let frame = benchmark_frame();
+        let batch = vec![Frame::now(&frame); BATCH_SIZE];
+        while generating.load(Ordering::Relaxed) {
+            if let Err(e) = generator.send_batch(&batch) {
+                error!("Generating traffic failed: {}", e);
+                break;
+            }
+        }
+    });
+
+    let start = Instant::now();
+    let mut received: u64 = 0;
+    while start.elapsed() < MEASURE_DURATION {
+        match receiver.recv()? {
+            Some(_) => received += 1,
+            None => break,
+        }
+    }
+    let elapsed = start.elapsed();
+    running.store(false, Ordering::Relaxed);
+    let _ = generator_thread.join();
+
+    Ok(received as f64 / elapsed.as_secs_f64())
+}
+
+fn measure_transmit(backend: Backend, interface_name: &str) -> io::Result<f64> {
+    let mut transmitter = packet_io::try_open(backend, interface_name, false)?;
+    let frame = benchmark_frame();
+    let batch = vec![Frame::now(&frame); BATCH_SIZE];
+
+    let start = Instant::now();
+    let mut sent: u64 = 0;
+    while start.elapsed() < MEASURE_DURATION {
+        transmitter.send_batch(&batch)?;
+        sent += BATCH_SIZE as u64;
+    }
+
+    Ok(sent as f64 / start.elapsed().as_secs_f64())
+}
@@ -0,0 +1,251 @@
+use crate::config::{ClaimsConfig, MacConfig, MacMode};
+use crate::event_log::ReleaseReason;
+use crate::packet_decoder::VlanTags;
+use ipnet::IpNet;
+use pnet_base::MacAddr;
+use std::collections::HashSet;
+use std::fmt;
+use std::net::IpAddr;
+use tracing::info;
+
+/// An unused address ANTS answers for, scoped to the VLAN it was learned on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Claim {
+    pub vlan_tags: VlanTags,
+    pub ip: IpAddr,
+}
+
+impl fmt::Display for Claim {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.ip, self.vlan_tags)
+    }
+}
+
+/// Sent when an address is claimed, or given back along with the reason
+/// why
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClaimEvent {
+    Claimed(Claim),
+    Released(Claim, ReleaseReason),
+}
+
+/// The rule that kept an address from being claimed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Refusal {
+    NeverClaim(IpNet),
+    OutsideOnlyClaim,
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refusal::NeverClaim(net) => write!(f, "matches never_claim {}", net),
+            Refusal::OutsideOnlyClaim => write!(f, "not within any only_claim range"),
+        }
+    }
+}
+
+/// Ranges ANTS must never claim, and the ranges it is limited to.
+/// never_claim wins over only_claim, an empty only_claim allows everything.
+#[derive(Clone, Debug, Default)]
+pub struct ClaimPolicy {
+    never_claim: Vec<IpNet>,
+    only_claim: Vec<IpNet>,
+}
+
+impl ClaimPolicy {
+    pub fn new(claims_config: &ClaimsConfig) -> Self {
+        ClaimPolicy {
+            never_claim: claims_config.never_claim.clone(),
+            only_claim: claims_config.only_claim.clone(),
+        }
+    }
+
+    /// Checks an address before it is claimed, Err names the rule that refused it
+    pub fn check(&self, ip: IpAddr) -> Result<(), Refusal> {
+        if let Some(net) = self.never_claim.iter().find(|net| net.contains(&ip)) {
+            return Err(Refusal::NeverClaim(*net));
+        }
+        if !self.only_claim.is_empty() && !self.only_claim.iter().any(|net| net.contains(&ip)) {
+            return Err(Refusal::OutsideOnlyClaim);
+        }
+        Ok(())
+    }
+
+    pub fn log_rules(&self) {
+        for net in &self.never_claim {
+            info!("Never claiming {}", net);
+        }
+        for net in &self.only_claim {
+            info!("Only claiming within {}", net);
+        }
+    }
+}
+
+/// Gives back the claims policy refuses, returning their releases
+pub fn release_refused(claimed: &mut HashSet<Claim>, policy: &ClaimPolicy) -> Vec<ClaimEvent> {
+    let mut released = Vec::new();
+    claimed.retain(|claim| match policy.check(claim.ip) {
+        Ok(()) => true,
+        Err(refusal) => {
+            info!("Releasing {}: {}", claim, refusal);
+            released.push(ClaimEvent::Released(*claim, ReleaseReason::Policy));
+            false
+        }
+    });
+    released
+}
+
+/// Picks the MAC address a claimed IP answers from
+#[derive(Clone, Copy, Debug)]
+pub struct ClaimMacs {
+    interface_mac: MacAddr,
+    mac_config: MacConfig,
+}
+
+impl ClaimMacs {
+    pub fn new(mac_config: MacConfig, interface_mac: MacAddr) -> Self {
+        ClaimMacs {
+            interface_mac,
+            mac_config,
+        }
+    }
+
+    pub fn mac_for(&self, ip: IpAddr) -> MacAddr {
+        match self.mac_config.mode {
+            MacMode::Interface => self.interface_mac,
+            MacMode::Virtual => virtual_mac(self.mac_config.seed, ip),
+            MacMode::Bogus => self.mac_config.bogus_mac,
+        }
+    }
+
+    /// True for frames ANTS itself sent from ip
+    pub fn is_own(&self, mac: MacAddr, ip: IpAddr) -> bool {
+        mac == self.interface_mac || mac == self.mac_for(ip)
+    }
+
+    /// Frames to other MACs than the interface's are only captured in promiscuous mode
+    pub fn needs_promiscuous(&self) -> bool {
+        self.mac_config.mode != MacMode::Interface
+    }
+}
+
+/// FNV-1a hash of the seed and the IP, for choices that must stay the
+/// same for an IP as long as the seed does
+pub fn ip_hash(seed: u64, ip: IpAddr) -> u64 {
+    let ip_octets = match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in seed.to_be_bytes().iter().chain(&ip_octets) {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Locally administered unicast MAC from the hash of the seed and the IP,
+/// the same IP and seed always give the same MAC
+fn virtual_mac(seed: u64, ip: IpAddr) -> MacAddr {
+    let [a, b, c, d, e, f, _, _] = ip_hash(seed, ip).to_be_bytes();
+    MacAddr(a & 0xfc | 0x02, b, c, d, e, f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(never_claim: &[&str], only_claim: &[&str]) -> ClaimPolicy {
+        ClaimPolicy::new(&ClaimsConfig {
+            never_claim: never_claim.iter().map(|net| net.parse().unwrap()).collect(),
+            only_claim: only_claim.iter().map(|net| net.parse().unwrap()).collect(),
+        })
+    }
+
+    #[test]
+    fn test_empty_policy_allows_everything() {
+        let policy = ClaimPolicy::default();
+
+        assert_eq!(policy.check("192.168.0.1".parse().unwrap()), Ok(()));
+        assert_eq!(policy.check("fd00::1".parse().unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn test_never_claim_names_matching_rule() {
+        let policy = policy(&["10.0.0.0/8", "192.168.0.1/32"], &[]);
+
+        assert_eq!(
+            policy.check("192.168.0.1".parse().unwrap()),
+            Err(Refusal::NeverClaim("192.168.0.1/32".parse().unwrap()))
+        );
+        assert_eq!(policy.check("192.168.0.2".parse().unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn test_only_claim() {
+        let policy = policy(&[], &["192.168.0.0/24", "fd00::/64"]);
+
+        assert_eq!(policy.check("192.168.0.2".parse().unwrap()), Ok(()));
+        assert_eq!(policy.check("fd00::2".parse().unwrap()), Ok(()));
+        assert_eq!(
+            policy.check("192.168.1.2".parse().unwrap()),
+            Err(Refusal::OutsideOnlyClaim)
+        );
+    }
+
+    #[test]
+    fn test_never_claim_wins_over_only_claim() {
+        let policy = policy(&["192.168.0.1/32"], &["192.168.0.0/24"]);
+
+        assert_eq!(
+            policy.check("192.168.0.1".parse().unwrap()),
+            Err(Refusal::NeverClaim("192.168.0.1/32".parse().unwrap()))
+        );
+    }
+
+    #[test]
+    fn test_virtual_macs_are_stable_and_locally_administered() {
+        let interface_mac = MacAddr(0x00, 0x11, 0x22, 0x33, 0x44, 0x55);
+        let mac_config = MacConfig {
+            mode: MacMode::Virtual,
+            ..MacConfig::default()
+        };
+        let claim_macs = ClaimMacs::new(mac_config, interface_mac);
+        let ip = "192.168.0.2".parse().unwrap();
+
+        let mac = claim_macs.mac_for(ip);
+        assert_eq!(mac, claim_macs.mac_for(ip));
+        assert_eq!(mac.0 & 0x03, 0x02);
+        assert_ne!(mac, claim_macs.mac_for("192.168.0.3".parse().unwrap()));
+        assert!(claim_macs.is_own(mac, ip));
+        assert!(claim_macs.is_own(interface_mac, ip));
+        assert!(claim_macs.needs_promiscuous());
+
+        let reseeded = ClaimMacs::new(
+            MacConfig {
+                seed: 1,
+                ..mac_config
+            },
+            interface_mac,
+        );
+        assert_ne!(mac, reseeded.mac_for(ip));
+    }
+
+    #[test]
+    fn test_interface_and_bogus_macs() {
+        let interface_mac = MacAddr(0x00, 0x11, 0x22, 0x33, 0x44, 0x55);
+        let ip = "fd00::2".parse().unwrap();
+
+        let claim_macs = ClaimMacs::new(MacConfig::default(), interface_mac);
+        assert_eq!(claim_macs.mac_for(ip), interface_mac);
+        assert!(!claim_macs.needs_promiscuous());
+
+        let mac_config = MacConfig {
+            mode: MacMode::Bogus,
+            ..MacConfig::default()
+        };
+        let claim_macs = ClaimMacs::new(mac_config, interface_mac);
+        assert_eq!(claim_macs.mac_for(ip), mac_config.bogus_mac);
+    }
+}
@@ -0,0 +1,920 @@
+use ipnet::IpNet;
+use pnet_base::MacAddr;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing_subscriber::EnvFilter;
+
+/// Commented configuration with every value at its default,
+/// printed by --print-default-config
+pub const DEFAULT_CONFIG: &str = r#"# ANTS configuration
+
+# Network interface to listen on, can be given with -i instead
+interface = ""
+# Do not send any responses
+passive = false
+# Logging level (debug, info, error)
+log_level = "info"
+# Capture and transmit with "pcap" (libpcap) or "pnet" (raw sockets), or
+# "tpacket" (TPACKET_V3 ring) when built with the tpacket-v3 feature
+backend = "pcap"
+
+[discovery]
+# Unanswered ARP requests or Neighbor Solicitations for an IP before it is claimed
+request_threshold = 2
+# Requests further apart than this many seconds start the count over
+request_timeout_secs = 5
+
+[claims]
+# CIDR ranges that are never claimed, e.g. ["192.168.0.1/32", "192.168.0.100/30"]
+# for the gateway and the DHCP pool
+never_claim = []
+# If not empty, only addresses within these CIDR ranges are claimed
+only_claim = []
+
+[mac]
+# MAC address replies for claimed IPs are sent from: "interface" for the
+# interface's own, "virtual" for a locally administered MAC derived from each
+# IP and seed, or "bogus" for bogus_mac on every IP like LaBrea. The last two
+# capture in promiscuous mode to receive frames sent to those addresses.
+mode = "interface"
+# Changes every virtual MAC. 0 picks a random seed, which is kept in
+# state.file so the MACs stay the same across restarts. Without a state file
+# it changes on every restart.
+seed = 0
+bogus_mac = "00:00:0f:ff:ff:ff"
+
+[tcp]
+# Delay before a SYN/ACK is sent, as if the decoy was a slow host
+syn_ack_delay_ms = 500
+# Window advertised in SYN/ACKs
+window = 1024
+# Seconds a claimed IP is tarpitted after the last SYN sent to it
+claim_expiry_secs = 120
+# Hold tarpitted connections open with zero-window ACKs
+persist = false
+# Seconds a connection is held open in persist mode
+flow_lifetime_secs = 14400
+# Connections held at once in persist mode
+max_flows = 262144
+# Replies per second to a single scanner
+rate_per_source = 10000
+
+[personality]
+# Operating systems whose TCP/IP stacks the decoys imitate in their replies,
+# so that nmap -O and p0f see real hosts: "linux" (5.x), "windows" (10) or
+# "cisco-ios". Every claimed IP is given one of them picked from its IP and
+# seed. Empty sends the same plain replies from every decoy, with TTL 64,
+# tcp.window and no TCP options.
+os = []
+# Changes the personality of every IP, 0 picks a random seed as under [mac]
+seed = 0
+
+[ports]
+# What a SYN to a claimed IP gets when no rule matches and the IP's profile
+# does not list the port: "tarpit" answers with a SYN/ACK, "persist" also
+# holds the connection open, "reset" answers with a RST/ACK like a closed
+# port and "drop" stays silent like a filtered one. tcp.persist turns every
+# "tarpit" into "persist".
+default_action = "tarpit"
+# Lists of open ports, every claimed IP is given one of them picked from its
+# IP and seed and tarpits those ports, e.g. [[22], [22, 80, 443],
+# [135, 139, 445, 3389]] with default_action = "reset"
+profiles = []
+# Changes the profile of every IP, 0 picks a random seed as under [mac]
+seed = 0
+# Rules are checked in order before profiles, the first one matching the
+# port, claimed IP and scanner decides. Empty lists match everything, ports
+# are numbers or "first-last" ranges.
+# [[ports.rules]]
+# ports = [23, "8000-8100"]
+# decoys = ["192.168.0.0/28"]
+# sources = ["10.0.0.0/8"]
+# action = "drop"
+
+[icmp]
+# Answer pings to claimed IPs so that ping sweeps find them alive
+enabled = false
+# TTL, or hop limit for IPv6, of echo replies, or 0 to send the TTL, DF
+# bit and IP ID the decoy's personality sends its SYN/ACKs with
+ttl = 0
+# Send the request's data back as a real host does, or reply without any
+echo_payload = true
+# Echo replies per second to a single scanner
+rate_per_source = 1000
+
+[scanners]
+# Classify the sources of SYNs to claimed IPs as horizontal sweeps,
+# vertical port scans, worm-like propagation or single probes, and
+# recognise masscan, ZMap and nmap by their packets
+enabled = true
+# A source's SYNs are judged together until it sends none for this many seconds
+window_secs = 300
+# Claimed IPs a source must send SYNs to for a horizontal sweep
+sweep_ips = 5
+# Ports a source must send SYNs to, on fewer than sweep_ips IPs, for a vertical scan
+scan_ports = 10
+# Sources sweeping the same single port within window_secs of each other
+# for worm-like propagation
+worm_sources = 3
+# Sources tracked at once, others are not classified until some go quiet
+max_sources = 65536
+
+[events]
+# Write every claim, SYN, reply and held flow as a line of JSON to this
+# file, "-" for stdout (logs then go to stderr), or "" to write none
+output = ""
+
+[metrics]
+# Address and port to serve Prometheus metrics on at /metrics, e.g.
+# "127.0.0.1:9100", or "" to serve none
+listen = ""
+
+[shutdown]
+# On SIGINT or SIGTERM, announce the claimed IPv4 addresses at MAC
+# 00:00:00:00:00:00 with gratuitous ARPs so hosts stop sending to ANTS
+# right away, instead of waiting for their ARP caches to age out
+gratuitous_arp = false
+
+[state]
+# Keep the claimed IPs in this file across restarts, or "" to keep them
+# only in memory. Claims not seen for tcp.claim_expiry_secs, answered
+# from another MAC than the [mac] settings now give or refused by
+# [claims] are dropped when it is read on start.
+file = ""
+# Seconds between writes of the file, it is also written on exit
+snapshot_secs = 30
+
+[control]
+# Unix socket `ants ctl` talks to, e.g. "/run/ants.sock", or "" for none.
+# Only the user ANTS runs as may connect.
+socket = ""
+"#;
+
+/// Error in the configuration, names the offending key
+#[derive(Debug, PartialEq, Eq)]
+pub struct ConfigError {
+    pub key: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(key: &str, message: &str) -> Self {
+        ConfigError {
+            key: key.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.key.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.key, self.message)
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub interface: String,
+    pub passive: bool,
+    pub log_level: String,
+    pub backend: Backend,
+    pub discovery: DiscoveryConfig,
+    pub claims: ClaimsConfig,
+    pub mac: MacConfig,
+    pub tcp: TcpConfig,
+    pub personality: PersonalityConfig,
+    pub ports: PortsConfig,
+    pub icmp: IcmpConfig,
+    pub scanners: ScannersConfig,
+    pub events: EventsConfig,
+    pub metrics: MetricsConfig,
+    pub shutdown: ShutdownConfig,
+    pub state: StateConfig,
+    pub control: ControlConfig,
+}
+
+/// Library used for live capture and transmit
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    Pcap,
+    Pnet,
+    #[cfg(feature = "tpacket-v3")]
+    Tpacket,
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Backend::Pcap => "pcap",
+            Backend::Pnet => "pnet",
+            #[cfg(feature = "tpacket-v3")]
+            Backend::Tpacket => "tpacket",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Backend {
+    /// Every backend this build supports
+    pub const ALL: &'static [Backend] = &[
+        Backend::Pcap,
+        Backend::Pnet,
+        #[cfg(feature = "tpacket-v3")]
+        Backend::Tpacket,
+    ];
+}
+
+/// How ARP and NDP decide that an IP is unused
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    pub request_threshold: u32,
+    pub request_timeout_secs: u64,
+}
+
+/// Address ranges checked before an IP is claimed
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClaimsConfig {
+    pub never_claim: Vec<IpNet>,
+    pub only_claim: Vec<IpNet>,
+}
+
+/// Which MAC address claimed IPs answer from
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MacMode {
+    #[default]
+    Interface,
+    Virtual,
+    Bogus,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MacConfig {
+    pub mode: MacMode,
+    pub seed: u64,
+    pub bogus_mac: MacAddr,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TcpConfig {
+    pub syn_ack_delay_ms: u64,
+    pub window: u16,
+    pub claim_expiry_secs: u64,
+    pub persist: bool,
+    pub flow_lifetime_secs: u64,
+    pub max_flows: usize,
+    pub rate_per_source: u32,
+}
+
+/// Operating system whose TCP/IP stack a decoy imitates
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Os {
+    Linux,
+    Windows,
+    CiscoIos,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersonalityConfig {
+    pub os: Vec<Os>,
+    pub seed: u64,
+}
+
+/// What a SYN to a claimed IP gets
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PortAction {
+    /// SYN/ACK, the flow is held open only in persist mode
+    #[default]
+    Tarpit,
+    /// RST/ACK, as from a closed port
+    Reset,
+    /// Nothing, as from a port behind a firewall
+    Drop,
+    /// SYN/ACK and the flow is held open with zero-window ACKs
+    Persist,
+}
+
+/// Inclusive range of ports, written as 22 or "8000-8100"
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.first..=self.last).contains(&port)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(range: &str) -> Result<Self, Self::Err> {
+        let parse_port = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("invalid port '{}'", port))
+        };
+        let (first, last) = match range.split_once('-') {
+            Some((first, last)) => (parse_port(first)?, parse_port(last)?),
+            None => (parse_port(range)?, parse_port(range)?),
+        };
+        if first > last {
+            return Err(format!("port range '{}' ends before it starts", range));
+        }
+        Ok(PortRange { first, last })
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PortRangeVisitor;
+
+        impl Visitor<'_> for PortRangeVisitor {
+            type Value = PortRange;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "a port number or a \"first-last\" range")
+            }
+
+            fn visit_i64<E: de::Error>(self, port: i64) -> Result<PortRange, E> {
+                let port =
+                    u16::try_from(port).map_err(|_| E::custom(format!("invalid port {}", port)))?;
+                Ok(PortRange {
+                    first: port,
+                    last: port,
+                })
+            }
+
+            fn visit_str<E: de::Error>(self, range: &str) -> Result<PortRange, E> {
+                range.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(PortRangeVisitor)
+    }
+}
+
+/// Matches SYNs to the listed ports and claimed IPs from the listed scanners
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PortRule {
+    #[serde(default)]
+    pub ports: Vec<PortRange>,
+    #[serde(default)]
+    pub decoys: Vec<IpNet>,
+    #[serde(default)]
+    pub sources: Vec<IpNet>,
+    pub action: PortAction,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PortsConfig {
+    pub default_action: PortAction,
+    pub profiles: Vec<Vec<PortRange>>,
+    pub seed: u64,
+    pub rules: Vec<PortRule>,
+}
+
+impl PortsConfig {
+    /// True if any port can hold flows open regardless of tcp.persist
+    pub fn uses_persist(&self) -> bool {
+        self.default_action == PortAction::Persist
+            || self
+                .rules
+                .iter()
+                .any(|rule| rule.action == PortAction::Persist)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct IcmpConfig {
+    pub enabled: bool,
+    pub ttl: u8,
+    pub echo_payload: bool,
+    pub rate_per_source: u32,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScannersConfig {
+    pub enabled: bool,
+    pub window_secs: u64,
+    pub sweep_ips: usize,
+    pub scan_ports: usize,
+    pub worm_sources: usize,
+    pub max_sources: usize,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventsConfig {
+    pub output: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub listen: String,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    pub gratuitous_arp: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StateConfig {
+    pub file: String,
+    pub snapshot_secs: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlConfig {
+    pub socket: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            interface: String::new(),
+            passive: false,
+            log_level: "info".to_string(),
+            backend: Backend::default(),
+            discovery: DiscoveryConfig::default(),
+            claims: ClaimsConfig::default(),
+            mac: MacConfig::default(),
+            tcp: TcpConfig::default(),
+            personality: PersonalityConfig::default(),
+            ports: PortsConfig::default(),
+            icmp: IcmpConfig::default(),
+            scanners: ScannersConfig::default(),
+            events: EventsConfig::default(),
+            metrics: MetricsConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            state: StateConfig::default(),
+            control: ControlConfig::default(),
+        }
+    }
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            request_threshold: 2,
+            request_timeout_secs: 5,
+        }
+    }
+}
+
+impl Default for MacConfig {
+    fn default() -> Self {
+        MacConfig {
+            mode: MacMode::Interface,
+            seed: 0,
+            // The address LaBrea answers from
+            bogus_mac: MacAddr(0x00, 0x00, 0x0f, 0xff, 0xff, 0xff),
+        }
+    }
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        TcpConfig {
+            syn_ack_delay_ms: 500,
+            window: 1024,
+            claim_expiry_secs: 120,
+            persist: false,
+            flow_lifetime_secs: 4 * 60 * 60,
+            max_flows: 262_144,
+            rate_per_source: 10_000,
+        }
+    }
+}
+
+impl Default for IcmpConfig {
+    fn default() -> Self {
+        IcmpConfig {
+            enabled: false,
+            ttl: 0,
+            echo_payload: true,
+            rate_per_source: 1000,
+        }
+    }
+}
+
+impl Default for ScannersConfig {
+    fn default() -> Self {
+        ScannersConfig {
+            enabled: true,
+            window_secs: 300,
+            sweep_ips: 5,
+            scan_ports: 10,
+            worm_sources: 3,
+            max_sources: 65536,
+        }
+    }
+}
+
+impl Default for StateConfig {
+    fn default() -> Self {
+        StateConfig {
+            file: String::new(),
+            snapshot_secs: 30,
+        }
+    }
+}
+
+impl MetricsConfig {
+    /// None when no metrics are served
+    pub fn listen_addr(&self) -> Option<SocketAddr> {
+        self.listen.parse().ok()
+    }
+}
+
+impl DiscoveryConfig {
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_secs)
+    }
+}
+
+impl TcpConfig {
+    pub fn syn_ack_delay(&self) -> Duration {
+        Duration::from_millis(self.syn_ack_delay_ms)
+    }
+
+    pub fn claim_expiry(&self) -> Duration {
+        Duration::from_secs(self.claim_expiry_secs)
+    }
+
+    pub fn flow_lifetime(&self) -> Duration {
+        Duration::from_secs(self.flow_lifetime_secs)
+    }
+}
+
+impl ScannersConfig {
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_secs)
+    }
+}
+
+impl StateConfig {
+    pub fn snapshot_interval(&self) -> Duration {
+        Duration::from_secs(self.snapshot_secs)
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| ConfigError::new("", &format!("cannot read {}: {}", path.display(), e)))?;
+        Config::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Config, ConfigError> {
+        toml::from_str(contents).map_err(|e| {
+            let key = e
+                .span()
+                .map_or_else(String::new, |span| key_at(contents, span.start));
+            ConfigError::new(&key, e.message().trim())
+        })
+    }
+
+    /// Listening live needs an interface, replaying a capture does not
+    pub fn require_interface(&self) -> Result<(), ConfigError> {
+        if self.interface.is_empty() {
+            return Err(ConfigError::new(
+                "interface",
+                "must be set in the config file or with -i",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks values that parse but make no sense
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if EnvFilter::try_new(&self.log_level).is_err() {
+            return Err(ConfigError::new(
+                "log_level",
+                &format!("invalid log level '{}'", self.log_level),
+            ));
+        }
+        if self.discovery.request_threshold == 0 {
+            return Err(ConfigError::new(
+                "discovery.request_threshold",
+                "must be at least 1",
+            ));
+        }
+        if self.discovery.request_timeout_secs == 0 {
+            return Err(ConfigError::new(
+                "discovery.request_timeout_secs",
+                "must be at least 1",
+            ));
+        }
+        if self.tcp.claim_expiry_secs == 0 {
+            return Err(ConfigError::new(
+                "tcp.claim_expiry_secs",
+                "must be at least 1",
+            ));
+        }
+        if (self.tcp.persist || self.ports.uses_persist()) && self.tcp.flow_lifetime_secs == 0 {
+            return Err(ConfigError::new(
+                "tcp.flow_lifetime_secs",
+                "must be at least 1 in persist mode",
+            ));
+        }
+        if (self.tcp.persist || self.ports.uses_persist()) && self.tcp.max_flows == 0 {
+            return Err(ConfigError::new(
+                "tcp.max_flows",
+                "must be at least 1 in persist mode",
+            ));
+        }
+        if self.tcp.rate_per_source == 0 {
+            return Err(ConfigError::new(
+                "tcp.rate_per_source",
+                "must be at least 1",
+            ));
+        }
+        if self.icmp.rate_per_source == 0 {
+            return Err(ConfigError::new(
+                "icmp.rate_per_source",
+                "must be at least 1",
+            ));
+        }
+        if self.scanners.window_secs == 0 {
+            return Err(ConfigError::new(
+                "scanners.window_secs",
+                "must be at least 1",
+            ));
+        }
+        // A single IP, port or source would make every scanner one
+        for (key, value) in [
+            ("scanners.sweep_ips", self.scanners.sweep_ips),
+            ("scanners.scan_ports", self.scanners.scan_ports),
+            ("scanners.worm_sources", self.scanners.worm_sources),
+        ] {
+            if value < 2 {
+                return Err(ConfigError::new(key, "must be at least 2"));
+            }
+        }
+        if self.state.snapshot_secs == 0 {
+            return Err(ConfigError::new(
+                "state.snapshot_secs",
+                "must be at least 1",
+            ));
+        }
+        if !self.metrics.listen.is_empty() && self.metrics.listen_addr().is_none() {
+            return Err(ConfigError::new(
+                "metrics.listen",
+                &format!("'{}' is not an address and port", self.metrics.listen),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The dotted key on the line of the config file at offset, or the line
+/// number when that line holds no key
+fn key_at(contents: &str, offset: usize) -> String {
+    let before = contents.get(..offset).unwrap_or(contents);
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    let line = contents[line_start..].lines().next().unwrap_or("").trim();
+    let table_name = |line: &str| {
+        line.starts_with('[').then(|| {
+            line.trim_matches(|c| c == '[' || c == ']')
+                .trim()
+                .to_string()
+        })
+    };
+    if let Some(table) = table_name(line) {
+        return table;
+    }
+    let Some((key, _)) = line.split_once('=') else {
+        return format!("line {}", before.matches('\n').count() + 1);
+    };
+    let key = key.trim();
+    match before[..line_start]
+        .lines()
+        .rev()
+        .find_map(|line| table_name(line.trim()))
+    {
+        Some(table) => format!("{}.{}", table, key),
+        None => key.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn valid_config() -> Config {
+        Config {
+            interface: "eth0".to_string(),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_default_config_template_matches_defaults() {
+        assert_eq!(Config::parse(DEFAULT_CONFIG).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_parse_partial_config() {
+        let config = Config::parse(
+            r#"
+            interface = "eth1"
+
+            [tcp]
+            window = 10
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.interface, "eth1");
+        assert_eq!(config.tcp.window, 10);
+        assert_eq!(config.tcp.syn_ack_delay_ms, 500);
+        assert_eq!(config.discovery, DiscoveryConfig::default());
+        assert_eq!(config.backend, Backend::Pcap);
+        assert_eq!(
+            Config::parse("backend = \"pnet\"\n").unwrap().backend,
+            Backend::Pnet
+        );
+    }
+
+    #[test]
+    fn test_parse_unknown_key_is_named() {
+        let error = Config::parse("[tcp]\nwindoww = 10\n").unwrap_err();
+
+        assert_eq!(error.key, "tcp.windoww");
+        assert!(error.message.contains("windoww"));
+
+        let error = Config::parse("[tcpp]\n").unwrap_err();
+        assert_eq!(error.key, "tcpp");
+
+        let error = Config::parse("passive = true\n\nwhat\n").unwrap_err();
+        assert_eq!(error.key, "line 3");
+    }
+
+    #[test]
+    fn test_parse_bad_value_is_named() {
+        let error = Config::parse("[tcp]\nwindow = 100000\n").unwrap_err();
+
+        assert_eq!(error.key, "tcp.window");
+    }
+
+    #[test]
+    fn test_parse_claim_ranges() {
+        let config = Config::parse(
+            r#"
+            [claims]
+            never_claim = ["192.168.0.1/32", "fd00::1/128"]
+            only_claim = ["192.168.0.0/24"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.claims.never_claim.len(), 2);
+        assert_eq!(config.claims.only_claim[0].to_string(), "192.168.0.0/24");
+        assert!(Config::parse("[claims]\nnever_claim = [\"192.168.0.1\"]\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_mac() {
+        let config = Config::parse(
+            r#"
+            [mac]
+            mode = "bogus"
+            bogus_mac = "02:00:00:00:00:99"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.mac.mode, MacMode::Bogus);
+        assert_eq!(config.mac.bogus_mac, MacAddr(0x02, 0, 0, 0, 0, 0x99));
+        assert!(Config::parse("[mac]\nbogus_mac = \"02:00\"\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_personality() {
+        let config = Config::parse(
+            r#"
+            [personality]
+            os = ["linux", "cisco-ios"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.personality.os, [Os::Linux, Os::CiscoIos]);
+        let error = Config::parse("[personality]\nos = [\"beos\"]").unwrap_err();
+        assert!(error.message.contains("beos"));
+    }
+
+    #[test]
+    fn test_parse_ports() {
+        let config = Config::parse(
+            r#"
+            [ports]
+            default_action = "reset"
+            profiles = [[22], [80, "8000-8080"]]
+
+            [[ports.rules]]
+            ports = [23]
+            sources = ["10.0.0.0/8"]
+            action = "drop"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.ports.default_action, PortAction::Reset);
+        assert_eq!(
+            config.ports.profiles[1],
+            [
+                PortRange {
+                    first: 80,
+                    last: 80
+                },
+                PortRange {
+                    first: 8000,
+                    last: 8080
+                }
+            ]
+        );
+        assert_eq!(config.ports.rules[0].action, PortAction::Drop);
+        assert!(config.ports.rules[0].decoys.is_empty());
+        assert!(!config.ports.uses_persist());
+
+        for bad_ports in ["[70000]", "[\"90-80\"]", "[\"http\"]"] {
+            let error = Config::parse(&format!("[ports]\nprofiles = [{}]", bad_ports));
+            assert!(error.is_err(), "{}", bad_ports);
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(valid_config().validate(), Ok(()));
+        assert_eq!(valid_config().require_interface(), Ok(()));
+
+        let error = Config::default().require_interface().unwrap_err();
+        assert_eq!(error.key, "interface");
+
+        let mut config = valid_config();
+        config.discovery.request_threshold = 0;
+        assert_eq!(
+            config.validate().unwrap_err().key,
+            "discovery.request_threshold"
+        );
+
+        let mut config = valid_config();
+        config.tcp.persist = true;
+        config.tcp.flow_lifetime_secs = 0;
+        assert_eq!(config.validate().unwrap_err().key, "tcp.flow_lifetime_secs");
+
+        let mut config = valid_config();
+        config.tcp.persist = true;
+        config.tcp.max_flows = 0;
+        assert_eq!(config.validate().unwrap_err().key, "tcp.max_flows");
+
+        let mut config = valid_config();
+        config.metrics.listen = "localhost".to_string();
+        assert_eq!(config.validate().unwrap_err().key, "metrics.listen");
+
+        let mut config = valid_config();
+        config.scanners.window_secs = 0;
+        assert_eq!(config.validate().unwrap_err().key, "scanners.window_secs");
+
+        let mut config = valid_config();
+        config.scanners.scan_ports = 1;
+        assert_eq!(config.validate().unwrap_err().key, "scanners.scan_ports");
+
+        let mut config = valid_config();
+        config.state.snapshot_secs = 0;
+        assert_eq!(config.validate().unwrap_err().key, "state.snapshot_secs");
+    }
+}
@@ -0,0 +1,493 @@
+use crate::claims::{Claim, ClaimEvent, ClaimPolicy};
+use crate::event_log::{Connection, ReleaseReason};
+use crate::packet_decoder::{VlanTag, VlanTags};
+use crate::reload::{Policies, ReloadRequest};
+use crate::state_file::StoredClaim;
+use clap::builder::BoolishValueParser;
+use clap::{ArgAction, Subcommand};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, error, info};
+
+/// Clients that send nothing for this long are disconnected
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the TCP thread gets to answer a query, it checks for them
+/// at least every packet_io::READ_TIMEOUT
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Tag protocol identifier of VLANs given by ID only that no claim is on
+const VLAN_TPID: u16 = 0x8100;
+
+/// Whether replies are held back, shared by the listeners so the control
+/// socket can switch it while ANTS runs
+#[derive(Clone, Debug, Default)]
+pub struct PassiveMode(Arc<AtomicBool>);
+
+impl PassiveMode {
+    pub fn new(on: bool) -> Self {
+        PassiveMode(Arc::new(AtomicBool::new(on)))
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, on: bool) {
+        self.0.store(on, Ordering::Relaxed);
+    }
+}
+
+/// Commands accepted on the control socket, sent as one JSON object per
+/// line with the command name in "command"
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Subcommand)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    /// List the claimed IPs
+    Claims,
+    /// List the flows held open in persist mode
+    Flows,
+    /// Start tarpitting an IP without waiting for unanswered requests
+    Claim {
+        ip: IpAddr,
+        /// VLAN ID the IP is claimed on, repeat for stacked tags outermost first
+        #[arg(long, value_name = "VID")]
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        vlan: Vec<u16>,
+    },
+    /// Stop tarpitting an IP
+    Release {
+        ip: IpAddr,
+        /// VLAN ID the IP was claimed on, repeat for stacked tags outermost first
+        #[arg(long, value_name = "VID")]
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        vlan: Vec<u16>,
+    },
+    /// Switch passive mode on or off
+    Passive {
+        #[arg(value_parser = BoolishValueParser::new(), action = ArgAction::Set)]
+        enabled: bool,
+    },
+    /// Replace the log filter, e.g. "debug" or "info,ants::tcp_listener=debug"
+    LogFilter { filter: String },
+    /// Read the configuration file again, as SIGHUP does
+    Reload,
+}
+
+/// A flow as listed on the control socket
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct HeldFlow {
+    #[serde(flatten)]
+    pub connection: Connection,
+    pub held_secs: u64,
+    pub idle_secs: u64,
+}
+
+/// Questions only the TCP thread can answer, it sends the answer back
+/// on the channel in the query
+pub enum Query {
+    Claims(mpsc::Sender<Vec<StoredClaim>>),
+    Flows(mpsc::Sender<Vec<HeldFlow>>),
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Response {
+    Claims(Vec<StoredClaim>),
+    Flows(Vec<HeldFlow>),
+    Done,
+    Error(String),
+}
+
+/// Replaces the log filter, or says why the filter is invalid
+pub type SetLogFilter = Arc<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+
+/// What the control socket acts on
+pub struct Control {
+    /// The channel ARP and NDP send their claims to
+    pub claim_sender: mpsc::Sender<ClaimEvent>,
+    pub claim_policy: ClaimPolicy,
+    /// Claim policies of reloaded configurations
+    pub policy_updates: mpsc::Receiver<Arc<Policies>>,
+    pub queries: mpsc::Sender<Query>,
+    pub passive_mode: PassiveMode,
+    pub set_log_filter: SetLogFilter,
+    pub reload_request: ReloadRequest,
+}
+
+impl Control {
+    fn handle(&mut self, request: Request) -> Response {
+        while let Ok(policies) = self.policy_updates.try_recv() {
+            self.claim_policy = policies.claim_policy.clone();
+        }
+        match request {
+            Request::Claims => self.query(Query::Claims, Response::Claims),
+            Request::Flows => self.query(Query::Flows, Response::Flows),
+            Request::Claim { ip, vlan } => {
+                match (self.claim(ip, &vlan), self.claim_policy.check(ip)) {
+                    (Err(e), _) => Response::Error(e),
+                    (_, Err(refusal)) => {
+                        Response::Error(format!("Not claiming {}: {}", ip, refusal))
+                    }
+                    (Ok(claim), Ok(())) => self.send_claim_event(ClaimEvent::Claimed(claim)),
+                }
+            }
+            Request::Release { ip, vlan } => match self.claim(ip, &vlan) {
+                Err(e) => Response::Error(e),
+                Ok(claim) => {
+                    self.send_claim_event(ClaimEvent::Released(claim, ReleaseReason::Manual))
+                }
+            },
+            Request::Passive { enabled } => {
+                self.passive_mode.set(enabled);
+                info!(
+                    "Switched to {} mode",
+                    if enabled { "passive" } else { "active" }
+                );
+                Response::Done
+            }
+            Request::LogFilter { filter } => match (self.set_log_filter)(&filter) {
+                Ok(()) => {
+                    info!("Log filter set to {}", filter);
+                    Response::Done
+                }
+                Err(e) => Response::Error(format!("Invalid log filter '{}': {}", filter, e)),
+            },
+            // Done once read, the outcome is logged
+            Request::Reload => {
+                self.reload_request.request();
+                Response::Done
+            }
+        }
+    }
+
+    fn query<T>(
+        &self,
+        query: impl FnOnce(mpsc::Sender<T>) -> Query,
+        response: impl FnOnce(T) -> Response,
+    ) -> Response {
+        match self.ask(query) {
+            Ok(answer) => response(answer),
+            Err(e) => Response::Error(e),
+        }
+    }
+
+    fn ask<T>(&self, query: impl FnOnce(mpsc::Sender<T>) -> Query) -> Result<T, String> {
+        let (answer_sender, answer) = mpsc::channel();
+        if self.queries.send(query(answer_sender)).is_err() {
+            return Err("The TCP listener has exited".to_string());
+        }
+        answer
+            .recv_timeout(QUERY_TIMEOUT)
+            .map_err(|_| "The TCP listener did not answer".to_string())
+    }
+
+    /// The claim of ip on the VLANs with IDs vlan. Their TPIDs are taken
+    /// from a claim on the same VLANs, preferably of ip, since QinQ
+    /// segments tag with other TPIDs than 802.1Q.
+    fn claim(&self, ip: IpAddr, vlan: &[u16]) -> Result<Claim, String> {
+        let dot1q = claim(ip, vlan).ok_or_else(|| too_many_tags(vlan))?;
+        if vlan.is_empty() {
+            return Ok(dot1q);
+        }
+        let claims = self.ask(Query::Claims)?;
+        let same_vlans = claims
+            .iter()
+            .filter_map(StoredClaim::claim)
+            .filter(|claimed| {
+                claimed
+                    .vlan_tags
+                    .as_slice()
+                    .iter()
+                    .map(|tag| tag.vid)
+                    .eq(vlan.iter().copied())
+            });
+        Ok(match same_vlans.max_by_key(|claimed| claimed.ip == ip) {
+            Some(claimed) => Claim {
+                vlan_tags: claimed.vlan_tags,
+                ip,
+            },
+            None => dot1q,
+        })
+    }
+
+    fn send_claim_event(&self, event: ClaimEvent) -> Response {
+        match self.claim_sender.send(event) {
+            Ok(()) => Response::Done,
+            Err(_) => Response::Error("ANTS is shutting down".to_string()),
+        }
+    }
+}
+
+/// The claim of ip on the 802.1Q VLANs with IDs vlan
+fn claim(ip: IpAddr, vlan: &[u16]) -> Option<Claim> {
+    let mut vlan_tags = VlanTags::default();
+    for &vid in vlan {
+        if !vlan_tags.push(VlanTag {
+            tpid: VLAN_TPID,
+            vid,
+        }) {
+            return None;
+        }
+    }
+    Some(Claim { vlan_tags, ip })
+}
+
+fn too_many_tags(vlan: &[u16]) -> String {
+    format!("{} VLAN tags are more than ANTS decodes", vlan.len())
+}
+
+/// Serves the control socket at path from its own thread. Only the user
+/// ANTS runs as may connect.
+pub fn start_control_server(path: &Path, mut control: Control) -> io::Result<()> {
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "another process is listening on it",
+        ));
+    }
+    // Left behind by an ANTS that did not exit cleanly
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let listener = bind_private(path)?;
+    info!("Control socket listening on {}", path.display());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| handle_client(stream, &mut control));
+            if let Err(e) = result {
+                debug!("Control connection failed: {}", e);
+            }
+        }
+        error!("Control server stopped");
+    });
+    Ok(())
+}
+
+/// Binds the socket in a directory only this user can enter and moves it
+/// to path once its permissions are tightened, so it is never reachable
+/// with the permissions of the umask
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file name"))?;
+    let mut dir_name = std::ffi::OsString::from(".");
+    dir_name.push(name);
+    dir_name.push(format!(".{}", process::id()));
+    let dir = path.with_file_name(dir_name);
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let private_path = dir.join(name);
+    let result = UnixListener::bind(&private_path).and_then(|listener| {
+        fs::set_permissions(&private_path, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&private_path, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&private_path);
+    fs::remove_dir(&dir)?;
+    result
+}
+
+fn handle_client(stream: UnixStream, control: &mut Control) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut writer = &stream;
+    for line in BufReader::new(&stream).lines() {
+        let response = match serde_json::from_str(&line?) {
+            Ok(request) => control.handle(request),
+            Err(e) => Response::Error(format!("Invalid request: {}", e)),
+        };
+        serde_json::to_writer(writer, &response)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Sends request to the ANTS listening on socket, returns the claims or
+/// flows it listed as JSON objects
+pub fn send_request(socket: &Path, request: &Request) -> io::Result<Vec<serde_json::Value>> {
+    let stream = UnixStream::connect(socket)?;
+    let mut writer = &stream;
+    serde_json::to_writer(writer, request)?;
+    writer.write_all(b"\n")?;
+
+    let mut response = String::new();
+    BufReader::new(&stream).read_line(&mut response)?;
+    match serde_json::from_str(&response)? {
+        serde_json::Value::Object(mut object) => {
+            if let Some(serde_json::Value::String(message)) = object.remove("error") {
+                return Err(io::Error::other(message));
+            }
+            match object.into_iter().next() {
+                Some((_, serde_json::Value::Array(entries))) => Ok(entries),
+                _ => Err(io::Error::other("Unexpected response")),
+            }
+        }
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// The socket `ants ctl` talks to: the one given, or control.socket from
+/// the configuration
+pub fn socket_path(socket: Option<PathBuf>, configured: &str) -> Option<PathBuf> {
+    socket.or_else(|| (!configured.is_empty()).then(|| PathBuf::from(configured)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ClaimsConfig, Config};
+    use pnet_base::MacAddr;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_request_format() {
+        let request = Request::Claim {
+            ip: Ipv4Addr::new(192, 168, 0, 2).into(),
+            vlan: vec![100],
+        };
+        let json = serde_json::to_string(&request).unwrap();
+
+        assert_eq!(
+            json,
+            r#"{"command":"claim","ip":"192.168.0.2","vlan":[100]}"#
+        );
+        assert_eq!(serde_json::from_str::<Request>(&json).unwrap(), request);
+        assert_eq!(
+            serde_json::from_str::<Request>(r#"{"command":"passive","enabled":true}"#).unwrap(),
+            Request::Passive { enabled: true }
+        );
+    }
+
+    #[test]
+    fn test_control_server() {
+        let socket = std::env::temp_dir().join(format!("ants-control-{}.sock", std::process::id()));
+        let (claim_sender, claims) = mpsc::channel();
+        let (queries, query_receiver) = mpsc::channel();
+        let (policy_sender, policy_updates) = mpsc::channel();
+        let passive_mode = PassiveMode::default();
+        let reload_request = ReloadRequest::default();
+        let control = Control {
+            claim_sender,
+            claim_policy: ClaimPolicy::new(&ClaimsConfig::default()),
+            policy_updates,
+            queries,
+            passive_mode: passive_mode.clone(),
+            set_log_filter: Arc::new(|filter| match filter {
+                "debug" => Ok(()),
+                _ => Err("unknown".to_string()),
+            }),
+            reload_request: reload_request.clone(),
+        };
+        start_control_server(&socket, control).unwrap();
+        let mode = fs::metadata(&socket).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2));
+        // Learned on a QinQ segment
+        let qinq = vec![
+            VlanTag {
+                tpid: 0x88a8,
+                vid: 10,
+            },
+            VlanTag {
+                tpid: VLAN_TPID,
+                vid: 20,
+            },
+        ];
+        let stored = StoredClaim {
+            ip,
+            vlan: qinq.clone(),
+            mac: MacAddr::zero(),
+            first_seen: 0,
+            last_seen: 0,
+        };
+        // Stands in for the TCP thread
+        thread::spawn(move || {
+            for query in query_receiver {
+                match query {
+                    Query::Claims(answer) => {
+                        let _ = answer.send(vec![stored.clone()]);
+                    }
+                    Query::Flows(answer) => {
+                        let _ = answer.send(Vec::new());
+                    }
+                }
+            }
+        });
+
+        let claimed = send_request(&socket, &Request::Claim { ip, vlan: vec![] }).unwrap();
+        assert!(claimed.is_empty());
+        assert_eq!(
+            claims.try_recv().unwrap(),
+            ClaimEvent::Claimed(Claim {
+                vlan_tags: VlanTags::default(),
+                ip,
+            })
+        );
+        assert!(send_request(&socket, &Request::Flows).unwrap().is_empty());
+
+        send_request(&socket, &Request::Release { ip, vlan: vec![] }).unwrap();
+        assert_eq!(
+            claims.try_recv().unwrap(),
+            ClaimEvent::Released(
+                Claim {
+                    vlan_tags: VlanTags::default(),
+                    ip,
+                },
+                ReleaseReason::Manual
+            )
+        );
+
+        // VLAN IDs take the TPIDs of the claim on them
+        let release = Request::Release {
+            ip,
+            vlan: vec![10, 20],
+        };
+        send_request(&socket, &release).unwrap();
+        let ClaimEvent::Released(released, _) = claims.try_recv().unwrap() else {
+            panic!("Expected a release");
+        };
+        assert_eq!(released.vlan_tags.as_slice(), qinq);
+        let other_ip = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 3));
+        let claim = Request::Claim {
+            ip: other_ip,
+            vlan: vec![10, 20],
+        };
+        send_request(&socket, &claim).unwrap();
+        let ClaimEvent::Claimed(claimed) = claims.try_recv().unwrap() else {
+            panic!("Expected a claim");
+        };
+        assert_eq!(claimed.vlan_tags.as_slice(), qinq);
+
+        send_request(&socket, &Request::Passive { enabled: true }).unwrap();
+        assert!(passive_mode.is_on());
+
+        let filter = Request::LogFilter {
+            filter: "nonsense".to_string(),
+        };
+        let e = send_request(&socket, &filter).unwrap_err();
+        assert_eq!(e.to_string(), "Invalid log filter 'nonsense': unknown");
+
+        send_request(&socket, &Request::Reload).unwrap();
+        assert!(reload_request.take());
+        let mut config = Config::default();
+        config.claims.never_claim = vec!["192.168.0.0/24".parse().unwrap()];
+        policy_sender
+            .send(Arc::new(Policies::new(&config)))
+            .unwrap();
+        let e = send_request(&socket, &Request::Claim { ip, vlan: vec![] }).unwrap_err();
+        assert!(e.to_string().starts_with("Not claiming 192.168.0.2"));
+
+        fs::remove_file(&socket).unwrap();
+    }
+}
@@ -0,0 +1,364 @@
+use crate::config::{EventsConfig, PortAction};
+use crate::packet_decoder::{SynOptions, VlanTags};
+use crate::scan_classifier::Classification;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+/// Written in every event as "schema", raised when a field changes meaning
+/// or goes away. New fields and events do not change it.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Why a claimed IP is no longer tarpitted
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReleaseReason {
+    /// The IP's owner answered for it again
+    OwnerReturned,
+    /// Nobody connected to it for tcp.claim_expiry_secs
+    Expired,
+    /// ANTS is shutting down
+    Shutdown,
+    /// Released on the control socket
+    Manual,
+    /// The claim policy of a reloaded configuration refuses it
+    Policy,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplyKind {
+    SynAck,
+    RstAck,
+    ZeroWindowAck,
+    EchoReply,
+}
+
+/// Why ANTS stopped holding a flow
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// The scanner sent a RST
+    Reset,
+    /// Held for tcp.flow_lifetime_secs
+    Expired,
+    /// The decoy IP was given back to its owner
+    Released,
+    /// ANTS is shutting down
+    Shutdown,
+}
+
+/// A TCP connection between a scanner and a claimed IP
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Connection {
+    pub scanner_ip: IpAddr,
+    pub scanner_port: u16,
+    pub decoy_ip: IpAddr,
+    pub decoy_port: u16,
+    #[serde(skip_serializing_if = "VlanTags::is_empty")]
+    pub vlan: VlanTags,
+}
+
+/// Something ANTS saw or did, written with its name in "event"
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    IpClaimed {
+        ip: IpAddr,
+        #[serde(skip_serializing_if = "VlanTags::is_empty")]
+        vlan: VlanTags,
+    },
+    IpReleased {
+        ip: IpAddr,
+        #[serde(skip_serializing_if = "VlanTags::is_empty")]
+        vlan: VlanTags,
+        reason: ReleaseReason,
+    },
+    /// A SYN to a claimed IP, with what the port policy does with it
+    SynReceived {
+        #[serde(flatten)]
+        connection: Connection,
+        ttl: u8,
+        window: u16,
+        options: SynOptions,
+        action: PortAction,
+    },
+    /// A reply queued to be sent delay_ms from now, ports are left out
+    /// of echo replies
+    ReplySent {
+        kind: ReplyKind,
+        scanner_ip: IpAddr,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        scanner_port: Option<u16>,
+        decoy_ip: IpAddr,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        decoy_port: Option<u16>,
+        #[serde(skip_serializing_if = "VlanTags::is_empty")]
+        vlan: VlanTags,
+        delay_ms: u64,
+    },
+    FlowHeld {
+        #[serde(flatten)]
+        connection: Connection,
+    },
+    FlowClosed {
+        #[serde(flatten)]
+        connection: Connection,
+        reason: CloseReason,
+        held_secs: u64,
+    },
+    /// What a scanner's SYNs to claimed IPs add up to, written when it changes
+    ScannerClassified {
+        #[serde(flatten)]
+        classification: Classification,
+    },
+}
+
+impl Event {
+    pub fn reply_sent(kind: ReplyKind, connection: Connection, delay: Duration) -> Self {
+        Event::ReplySent {
+            kind,
+            scanner_ip: connection.scanner_ip,
+            scanner_port: Some(connection.scanner_port),
+            decoy_ip: connection.decoy_ip,
+            decoy_port: Some(connection.decoy_port),
+            vlan: connection.vlan,
+            delay_ms: delay.as_millis() as u64,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    schema: u32,
+    /// Seconds since the Unix epoch
+    time: f64,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// Writes events as JSON lines, or nothing when no output is configured.
+/// Events are buffered, the owner calls flush periodically and on exit.
+pub struct EventLog {
+    output: Option<Box<dyn Write + Send>>,
+    /// Instant matching start_time, the time since the Unix epoch
+    start: Instant,
+    start_time: Duration,
+}
+
+impl EventLog {
+    /// Event times are start_time plus the time since start
+    pub fn new(
+        output: Option<Box<dyn Write + Send>>,
+        start: Instant,
+        start_time: Duration,
+    ) -> Self {
+        EventLog {
+            output,
+            start,
+            start_time,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.output.is_some()
+    }
+
+    pub fn record(&mut self, now: Instant, event: Event) {
+        let Some(output) = &mut self.output else {
+            return;
+        };
+        let time = self.start_time + now.saturating_duration_since(self.start);
+        let record = Record {
+            schema: SCHEMA_VERSION,
+            time: time.as_micros() as f64 / 1e6,
+            event: &event,
+        };
+        let written = serde_json::to_writer(&mut *output, &record)
+            .map_err(io::Error::from)
+            .and_then(|()| output.write_all(b"\n"));
+        self.check(written);
+    }
+
+    /// Writes out the buffered events
+    pub fn flush(&mut self) {
+        if let Some(output) = &mut self.output {
+            let flushed = output.flush();
+            self.check(flushed);
+        }
+    }
+
+    fn check(&mut self, written: io::Result<()>) {
+        if let Err(e) = written {
+            error!(
+                "Writing event log failed, no more events are written: {}",
+                e
+            );
+            self.output = None;
+        }
+    }
+}
+
+/// Opens the configured output for appending, None when no events are written.
+/// Writes are buffered so the TCP thread does not make a system call per event.
+pub fn open_output(events_config: &EventsConfig) -> io::Result<Option<Box<dyn Write + Send>>> {
+    Ok(match events_config.output.as_str() {
+        "" => None,
+        "-" => Some(Box::new(BufWriter::new(io::stdout()))),
+        path => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            Some(Box::new(BufWriter::new(file)))
+        }
+    })
+}
+
+/// Time since the Unix epoch on the system clock
+pub fn wall_clock() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Output shared with a test, which reads back the events written to it
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct CapturedEvents(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl CapturedEvents {
+    pub fn event_log(&self, start: Instant) -> EventLog {
+        EventLog::new(Some(Box::new(self.clone())), start, Duration::ZERO)
+    }
+
+    pub fn take(&self) -> Vec<serde_json::Value> {
+        let output = std::mem::take(&mut *self.0.lock().unwrap());
+        serde_json::Deserializer::from_slice(&output)
+            .into_iter()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    /// Names of the events written since the last call
+    pub fn take_names(&self) -> Vec<String> {
+        self.take()
+            .into_iter()
+            .map(|event| event["event"].as_str().unwrap().to_string())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+impl Write for CapturedEvents {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_decoder::VlanTag;
+    use serde_json::json;
+    use std::net::Ipv4Addr;
+
+    fn connection() -> Connection {
+        let mut vlan = VlanTags::default();
+        vlan.push(VlanTag {
+            tpid: 0x8100,
+            vid: 100,
+        });
+        Connection {
+            scanner_ip: Ipv4Addr::new(10, 0, 0, 1).into(),
+            scanner_port: 40000,
+            decoy_ip: Ipv4Addr::new(192, 168, 0, 2).into(),
+            decoy_port: 22,
+            vlan,
+        }
+    }
+
+    #[test]
+    fn test_event_schema() {
+        let start = Instant::now();
+        let captured = CapturedEvents::default();
+        let mut event_log = EventLog::new(
+            Some(Box::new(captured.clone())),
+            start,
+            Duration::from_secs(1_700_000_000),
+        );
+
+        event_log.record(
+            start + Duration::from_micros(1_500_250),
+            Event::SynReceived {
+                connection: connection(),
+                ttl: 52,
+                window: 1024,
+                options: SynOptions {
+                    mss: Some(1460),
+                    ..SynOptions::default()
+                },
+                action: PortAction::Tarpit,
+            },
+        );
+        event_log.record(
+            start,
+            Event::IpClaimed {
+                ip: Ipv4Addr::new(192, 168, 0, 2).into(),
+                vlan: VlanTags::default(),
+            },
+        );
+
+        assert_eq!(
+            captured.take(),
+            [
+                json!({
+                    "schema": 1,
+                    "time": 1_700_000_001.500_25,
+                    "event": "syn_received",
+                    "scanner_ip": "10.0.0.1",
+                    "scanner_port": 40000,
+                    "decoy_ip": "192.168.0.2",
+                    "decoy_port": 22,
+                    "vlan": [100],
+                    "ttl": 52,
+                    "window": 1024,
+                    "options": {"mss": 1460, "sack_permitted": false, "window_scale": false},
+                    "action": "tarpit",
+                }),
+                json!({
+                    "schema": 1,
+                    "time": 1_700_000_000.0,
+                    "event": "ip_claimed",
+                    "ip": "192.168.0.2",
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_events_written_on_flush() {
+        let start = Instant::now();
+        let captured = CapturedEvents::default();
+        let output = BufWriter::new(captured.clone());
+        let mut event_log = EventLog::new(Some(Box::new(output)), start, Duration::ZERO);
+
+        event_log.record(
+            start,
+            Event::IpClaimed {
+                ip: Ipv4Addr::new(192, 168, 0, 2).into(),
+                vlan: VlanTags::default(),
+            },
+        );
+        assert!(captured.take().is_empty());
+
+        event_log.flush();
+        assert_eq!(captured.take_names(), ["ip_claimed"]);
+    }
+}
@@ -0,0 +1,401 @@
+use crate::claims::{Claim, ClaimMacs};
+use crate::config::IcmpConfig;
+use crate::control::PassiveMode;
+use crate::metrics::Metrics;
+use crate::packet_decoder::{self, VlanTags};
+use crate::rate_limiter::{self, RateLimiter};
+use crate::tcp_listener::IpFields;
+use pnet_base::MacAddr;
+use pnet_packet::ethernet::{EtherTypes, MutableEthernetPacket};
+use pnet_packet::icmp::{self, IcmpPacket, IcmpTypes, MutableIcmpPacket};
+use pnet_packet::icmpv6::{self, Icmpv6Packet, Icmpv6Types, MutableIcmpv6Packet};
+use pnet_packet::ip::IpNextHeaderProtocols;
+use pnet_packet::ipv4::{self, Ipv4Flags, MutableIpv4Packet};
+use pnet_packet::ipv6::MutableIpv6Packet;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::debug;
+
+/// Type, code, checksum, identifier and sequence number of an echo message
+const ECHO_HEADER_LEN: usize = 8;
+
+/// Passes only IPv4 echo requests, ICMPv6 ones are told apart in userspace
+pub const ECHO_REQUEST_FILTER: &str = "icmp[icmptype] == icmp-echo";
+
+/// An ICMP or ICMPv6 echo request with a valid checksum
+struct EchoRequest<'a> {
+    src_mac: MacAddr,
+    vlan_tags: VlanTags,
+    src_ip: IpAddr,
+    dst_ip: IpAddr,
+    /// The ICMP message from its type field to the end of the data
+    message: &'a [u8],
+}
+
+/// Answers pings to claimed IPs without any I/O, so that ping sweeps
+/// find them alive and go on to scan them
+pub struct IcmpResponder {
+    rate_limiter: RateLimiter,
+    icmp_config: IcmpConfig,
+    claim_macs: ClaimMacs,
+    passive_mode: PassiveMode,
+    metrics: Arc<Metrics>,
+}
+
+impl IcmpResponder {
+    pub fn new(
+        icmp_config: IcmpConfig,
+        claim_macs: ClaimMacs,
+        passive_mode: PassiveMode,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        IcmpResponder {
+            rate_limiter: RateLimiter::new(
+                icmp_config.rate_per_source,
+                rate_limiter::DEFAULT_MAX_SOURCES,
+            ),
+            icmp_config,
+            claim_macs,
+            passive_mode,
+            metrics,
+        }
+    }
+
+    pub fn set_config(&mut self, icmp_config: IcmpConfig) {
+        self.rate_limiter.set_rate(icmp_config.rate_per_source);
+        self.icmp_config = icmp_config;
+    }
+
+    /// Returns the echo reply to send if the frame is an echo request to
+    /// an IP is_claimed accepts, None in passive mode. The reply's IP header
+    /// is filled in from ip_fields of the decoy unless icmp.ttl overrides it.
+    pub fn handle_frame(
+        &mut self,
+        frame: &[u8],
+        is_claimed: impl FnOnce(&Claim) -> bool,
+        ip_fields: impl FnOnce(IpAddr) -> IpFields,
+        now: Instant,
+    ) -> Option<Vec<u8>> {
+        let request = decode_echo_request(frame)?;
+        let claim = Claim {
+            vlan_tags: request.vlan_tags,
+            ip: request.dst_ip,
+        };
+        if !is_claimed(&claim) {
+            return None;
+        }
+        if !self.rate_limiter.allow(request.src_ip, now) {
+            debug!("ICMP rate limit exceeded for {}", request.src_ip);
+            return None;
+        }
+        if self.passive_mode.is_on() {
+            Metrics::count(&self.metrics.passive_suppressed);
+            return None;
+        }
+
+        let ip = match self.icmp_config.ttl {
+            0 => ip_fields(request.dst_ip),
+            ttl => IpFields {
+                ttl,
+                dont_fragment: false,
+                id: 0,
+            },
+        };
+        debug!("Echo reply from {} to {}", request.dst_ip, request.src_ip);
+        Some(create_echo_reply_frame(
+            &request,
+            self.claim_macs.mac_for(request.dst_ip),
+            ip,
+            self.icmp_config.echo_payload,
+        ))
+    }
+}
+
+/// Decodes an ICMP or ICMPv6 echo request, dropping ones with a bad checksum.
+/// Fragmented requests are dropped too as their data can't be echoed.
+fn decode_echo_request(frame: &[u8]) -> Option<EchoRequest<'_>> {
+    let ethernet_frame = packet_decoder::decode_ethernet(frame)?;
+    let datagram = packet_decoder::decode_ip_datagram(&ethernet_frame)?;
+    if datagram.more_fragments || datagram.payload.len() < ECHO_HEADER_LEN {
+        return None;
+    }
+
+    let is_echo_request = match (datagram.src_ip, datagram.dst_ip) {
+        (IpAddr::V4(_), IpAddr::V4(_)) => {
+            let icmp_packet = IcmpPacket::new(datagram.payload)?;
+            datagram.protocol == IpNextHeaderProtocols::Icmp
+                && icmp_packet.get_icmp_type() == IcmpTypes::EchoRequest
+                && icmp_packet.get_checksum() == icmp::checksum(&icmp_packet)
+        }
+        (IpAddr::V6(src_ip), IpAddr::V6(dst_ip)) => {
+            let icmp_packet = Icmpv6Packet::new(datagram.payload)?;
+            datagram.protocol == IpNextHeaderProtocols::Icmpv6
+                && icmp_packet.get_icmpv6_type() == Icmpv6Types::EchoRequest
+                && icmp_packet.get_checksum() == icmpv6::checksum(&icmp_packet, &src_ip, &dst_ip)
+        }
+        _ => false,
+    };
+    if !is_echo_request {
+        return None;
+    }
+
+    Some(EchoRequest {
+        src_mac: ethernet_frame.source,
+        vlan_tags: ethernet_frame.vlan_tags,
+        src_ip: datagram.src_ip,
+        dst_ip: datagram.dst_ip,
+        message: datagram.payload,
+    })
+}
+
+/// Builds the echo reply on the VLAN the request arrived on. The identifier
+/// and sequence number are always echoed, the data only if echo_payload is set.
+fn create_echo_reply_frame(
+    request: &EchoRequest<'_>,
+    src_mac: MacAddr,
+    ip: IpFields,
+    echo_payload: bool,
+) -> Vec<u8> {
+    let message_len = if echo_payload {
+        request.message.len()
+    } else {
+        ECHO_HEADER_LEN
+    };
+    let (ethertype, ip_header_len) = match request.src_ip {
+        IpAddr::V4(_) => (EtherTypes::Ipv4, 20),
+        IpAddr::V6(_) => (EtherTypes::Ipv6, 40),
+    };
+    let icmp_offset = 14 + ip_header_len;
+    // Padded to the minimum Ethernet frame size
+    let mut eth_buffer = vec![0u8; (icmp_offset + message_len).max(60)];
+
+    {
+        let mut ethernet_packet = MutableEthernetPacket::new(&mut eth_buffer).unwrap();
+        ethernet_packet.set_source(src_mac);
+        ethernet_packet.set_destination(request.src_mac);
+        ethernet_packet.set_ethertype(ethertype);
+    }
+    eth_buffer[icmp_offset..icmp_offset + message_len]
+        .copy_from_slice(&request.message[..message_len]);
+
+    match (request.dst_ip, request.src_ip) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            {
+                let mut ipv4_packet =
+                    MutableIpv4Packet::new(&mut eth_buffer[14..icmp_offset]).unwrap();
+                ipv4_packet.set_version(4);
+                ipv4_packet.set_header_length(5);
+                ipv4_packet.set_total_length((ip_header_len + message_len) as u16);
+                ipv4_packet.set_ttl(ip.ttl);
+                ipv4_packet.set_identification(ip.id);
+                if ip.dont_fragment {
+                    ipv4_packet.set_flags(Ipv4Flags::DontFragment);
+                }
+                ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+                ipv4_packet.set_source(src_ip);
+                ipv4_packet.set_destination(dst_ip);
+                let ipv4_checksum = ipv4::checksum(&ipv4_packet.to_immutable());
+                ipv4_packet.set_checksum(ipv4_checksum);
+            }
+
+            let mut icmp_packet =
+                MutableIcmpPacket::new(&mut eth_buffer[icmp_offset..icmp_offset + message_len])
+                    .unwrap();
+            icmp_packet.set_icmp_type(IcmpTypes::EchoReply);
+            let icmp_checksum = icmp::checksum(&icmp_packet.to_immutable());
+            icmp_packet.set_checksum(icmp_checksum);
+        }
+        (IpAddr::V6(src_ip), IpAddr::V6(dst_ip)) => {
+            {
+                let mut ipv6_packet =
+                    MutableIpv6Packet::new(&mut eth_buffer[14..icmp_offset]).unwrap();
+                ipv6_packet.set_version(6);
+                ipv6_packet.set_payload_length(message_len as u16);
+                ipv6_packet.set_next_header(IpNextHeaderProtocols::Icmpv6);
+                ipv6_packet.set_hop_limit(ip.ttl);
+                ipv6_packet.set_source(src_ip);
+                ipv6_packet.set_destination(dst_ip);
+            }
+
+            let mut icmp_packet =
+                MutableIcmpv6Packet::new(&mut eth_buffer[icmp_offset..icmp_offset + message_len])
+                    .unwrap();
+            icmp_packet.set_icmpv6_type(Icmpv6Types::EchoReply);
+            let icmp_checksum = icmpv6::checksum(&icmp_packet.to_immutable(), &src_ip, &dst_ip);
+            icmp_packet.set_checksum(icmp_checksum);
+        }
+        _ => unreachable!(),
+    }
+
+    packet_decoder::tag_frame(&eth_buffer, request.vlan_tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MacConfig;
+    use crate::test_frames::{self, DECOY_IP, SCANNER_IP, SCANNER_MAC};
+    use pnet_packet::ipv4::Ipv4Packet;
+    use std::net::Ipv6Addr;
+
+    const ANTS_MAC: MacAddr = MacAddr(0x02, 0x00, 0x00, 0x00, 0x00, 0x02);
+    const PAYLOAD: &[u8] = b"abcdefghijklmnopqrstuvwabcdefghi";
+
+    fn responder(icmp_config: IcmpConfig) -> IcmpResponder {
+        IcmpResponder::new(
+            icmp_config,
+            ClaimMacs::new(MacConfig::default(), ANTS_MAC),
+            PassiveMode::default(),
+            Arc::new(Metrics::default()),
+        )
+    }
+
+    #[test]
+    fn test_echo_reply_to_claimed_ip() {
+        let icmp_config = IcmpConfig {
+            ttl: 128,
+            ..IcmpConfig::default()
+        };
+        let request = test_frames::icmp_echo_request(ANTS_MAC, PAYLOAD);
+
+        let reply = responder(icmp_config)
+            .handle_frame(
+                &request,
+                |claim| claim.ip == DECOY_IP,
+                |_| IpFields::PLAIN,
+                Instant::now(),
+            )
+            .unwrap();
+
+        let ethernet_frame = packet_decoder::decode_ethernet(&reply).unwrap();
+        assert_eq!(ethernet_frame.source, ANTS_MAC);
+        assert_eq!(ethernet_frame.destination, SCANNER_MAC);
+        let datagram = packet_decoder::decode_ip_datagram(&ethernet_frame).unwrap();
+        assert_eq!(datagram.src_ip, IpAddr::V4(DECOY_IP));
+        assert_eq!(datagram.dst_ip, IpAddr::V4(SCANNER_IP));
+        assert_eq!(datagram.ttl, 128);
+        let icmp_packet = IcmpPacket::new(datagram.payload).unwrap();
+        assert_eq!(icmp_packet.get_icmp_type(), IcmpTypes::EchoReply);
+        assert_eq!(icmp_packet.get_checksum(), icmp::checksum(&icmp_packet));
+        // Identifier, sequence number and data are those of the request
+        assert_eq!(&datagram.payload[4..], &request[38..]);
+    }
+
+    #[test]
+    fn test_echo_reply_without_payload() {
+        let icmp_config = IcmpConfig {
+            echo_payload: false,
+            ..IcmpConfig::default()
+        };
+        let request = test_frames::icmp_echo_request(ANTS_MAC, PAYLOAD);
+
+        let reply = responder(icmp_config)
+            .handle_frame(&request, |_| true, |_| IpFields::PLAIN, Instant::now())
+            .unwrap();
+
+        let ipv4_packet = Ipv4Packet::new(&reply[14..]).unwrap();
+        assert_eq!(
+            usize::from(ipv4_packet.get_total_length()),
+            20 + ECHO_HEADER_LEN
+        );
+        assert_eq!(&reply[38..42], &request[38..42]);
+    }
+
+    #[test]
+    fn test_echo_reply_ipv6() {
+        let scanner_ip = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0x100);
+        let decoy_ip = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0x2);
+        let mut request = vec![0u8; 14 + 40 + ECHO_HEADER_LEN + PAYLOAD.len()];
+        {
+            let mut ethernet_packet = MutableEthernetPacket::new(&mut request).unwrap();
+            ethernet_packet.set_source(SCANNER_MAC);
+            ethernet_packet.set_destination(ANTS_MAC);
+            ethernet_packet.set_ethertype(EtherTypes::Ipv6);
+        }
+        {
+            let mut ipv6_packet = MutableIpv6Packet::new(&mut request[14..54]).unwrap();
+            ipv6_packet.set_version(6);
+            ipv6_packet.set_payload_length((ECHO_HEADER_LEN + PAYLOAD.len()) as u16);
+            ipv6_packet.set_next_header(IpNextHeaderProtocols::Icmpv6);
+            ipv6_packet.set_hop_limit(64);
+            ipv6_packet.set_source(scanner_ip);
+            ipv6_packet.set_destination(decoy_ip);
+        }
+        request[62..].copy_from_slice(PAYLOAD);
+        let mut icmp_packet = MutableIcmpv6Packet::new(&mut request[54..]).unwrap();
+        icmp_packet.set_icmpv6_type(Icmpv6Types::EchoRequest);
+        let icmp_checksum = icmpv6::checksum(&icmp_packet.to_immutable(), &scanner_ip, &decoy_ip);
+        icmp_packet.set_checksum(icmp_checksum);
+
+        let reply = responder(IcmpConfig::default())
+            .handle_frame(
+                &request,
+                |claim| claim.ip == decoy_ip,
+                |_| IpFields::PLAIN,
+                Instant::now(),
+            )
+            .unwrap();
+
+        let ethernet_frame = packet_decoder::decode_ethernet(&reply).unwrap();
+        let datagram = packet_decoder::decode_ip_datagram(&ethernet_frame).unwrap();
+        assert_eq!(datagram.dst_ip, IpAddr::V6(scanner_ip));
+        assert_eq!(datagram.ttl, 64);
+        let icmp_packet = Icmpv6Packet::new(datagram.payload).unwrap();
+        assert_eq!(icmp_packet.get_icmpv6_type(), Icmpv6Types::EchoReply);
+        assert_eq!(
+            icmp_packet.get_checksum(),
+            icmpv6::checksum(&icmp_packet, &decoy_ip, &scanner_ip)
+        );
+        assert_eq!(&datagram.payload[8..], PAYLOAD);
+    }
+
+    #[test]
+    fn test_no_reply_to_unclaimed_ip_or_over_rate() {
+        let icmp_config = IcmpConfig {
+            rate_per_source: 1,
+            ..IcmpConfig::default()
+        };
+        let mut responder = responder(icmp_config);
+        let request = test_frames::icmp_echo_request(ANTS_MAC, PAYLOAD);
+        let now = Instant::now();
+
+        assert!(responder
+            .handle_frame(&request, |_| false, |_| IpFields::PLAIN, now)
+            .is_none());
+        assert!(responder
+            .handle_frame(&request, |_| true, |_| IpFields::PLAIN, now)
+            .is_some());
+        assert!(responder
+            .handle_frame(&request, |_| true, |_| IpFields::PLAIN, now)
+            .is_none());
+
+        let mut corrupted = request.clone();
+        corrupted[36] ^= 0xff;
+        let now = now + std::time::Duration::from_secs(1);
+        assert!(responder
+            .handle_frame(&corrupted, |_| true, |_| IpFields::PLAIN, now)
+            .is_none());
+    }
+
+    #[test]
+    fn test_passive_mode_counts_suppressed_reply() {
+        let metrics = Arc::new(Metrics::default());
+        let passive_mode = PassiveMode::default();
+        passive_mode.set(true);
+        let mut responder = IcmpResponder::new(
+            IcmpConfig::default(),
+            ClaimMacs::new(MacConfig::default(), ANTS_MAC),
+            passive_mode,
+            metrics.clone(),
+        );
+        let request = test_frames::icmp_echo_request(ANTS_MAC, PAYLOAD);
+
+        assert!(responder
+            .handle_frame(&request, |_| true, |_| IpFields::PLAIN, Instant::now())
+            .is_none());
+        assert!(metrics
+            .render()
+            .contains("\nants_passive_suppressed_total 1\n"));
+    }
+}
@@ -1,68 +1,243 @@
 mod arp_listener;
+mod benchmark;
+mod claims;
+mod config;
+mod control;
+mod event_log;
+mod icmp_responder;
+mod metrics;
+mod ndp_listener;
+mod packet_decoder;
+mod packet_io;
+mod port_policy;
+mod rate_limiter;
+mod reload;
+mod replay;
+mod scan_classifier;
+mod shutdown;
+mod state_file;
 mod tarpitter;
 mod tcp_listener;
+#[cfg(test)]
+mod test_frames;
 
-use std::env;
+use clap::{Parser, Subcommand};
+use config::Config;
+use control::SetLogFilter;
+use std::path::PathBuf;
 use std::process;
+use std::sync::Arc;
 use tracing::info;
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, EnvFilter};
 
-fn init_tracing(log_level: &str) {
+/// Logs go to stderr when events are written to stdout. Returns what
+/// replaces the filter while ANTS runs.
+fn init_tracing(log_level: &str, events_on_stdout: bool) -> SetLogFilter {
     let filter = EnvFilter::try_new(log_level).unwrap_or_else(|_| {
         eprintln!("Invalid log level: {}. Defaulting to 'info'.", log_level);
         EnvFilter::new("info")
     });
 
-    tracing_subscriber::fmt().with_env_filter(filter).init();
+    let writer = if events_on_stdout {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+    let (filter, filter_handle) = tracing_subscriber::reload::Layer::new(filter);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer().with_writer(writer))
+        .init();
+
+    Arc::new(move |log_level| {
+        let filter = EnvFilter::try_new(log_level).map_err(|e| e.to_string())?;
+        filter_handle.reload(filter).map_err(|e| e.to_string())
+    })
 }
 
-fn parse_arguments() -> (bool, String, String) {
-    let args: Vec<String> = env::args().collect();
+/// Command line flags, these override values from the config file
+#[derive(Parser, Debug)]
+#[command(about = "TCP tarpit for unused IP addresses")]
+struct Cli {
+    /// Read configuration from this TOML file
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
 
-    let passive_mode = args.contains(&"--passive".to_string()) || args.contains(&"-p".to_string());
+    /// Print a commented configuration with default values and exit
+    #[arg(long)]
+    print_default_config: bool,
 
-    let interface_index = args.iter().position(|arg| arg == "-i");
-    let interface_name = match interface_index {
-        Some(index) => {
-            if let Some(name) = args.get(index + 1) {
-                name.clone()
-            } else {
-                eprintln!("Error: No value provided for '-i' flag.");
-                process::exit(1);
-            }
+    /// Network interface to use
+    #[arg(short = 'i', value_name = "INTERFACE")]
+    interface: Option<String>,
+
+    /// Do not send any responses
+    #[arg(short, long)]
+    passive: bool,
+
+    /// Logging level (debug, info, error)
+    #[arg(long, value_name = "LEVEL")]
+    log_level: Option<String>,
+
+    /// Hold tarpitted connections open with zero-window ACKs
+    #[arg(long)]
+    persist: bool,
+
+    /// Seconds a connection is held open in persist mode
+    #[arg(long, value_name = "SECONDS")]
+    flow_lifetime: Option<u64>,
+
+    /// Replay a recorded capture instead of listening on an interface
+    #[arg(long, value_name = "FILE")]
+    read_pcap: Option<PathBuf>,
+
+    /// Write every frame ANTS would have sent during the replay to this capture
+    #[arg(long, value_name = "FILE", requires = "read_pcap")]
+    write_pcap: Option<PathBuf>,
+
+    /// Measure frames per second of every capture backend on -i, with
+    /// traffic generated on PEER at the other end of the link, and exit
+    #[arg(long, value_name = "PEER")]
+    benchmark: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Talk to a running ANTS over its control socket
+    Ctl {
+        /// Control socket to connect to, defaults to control.socket of --config
+        #[arg(long, value_name = "SOCKET")]
+        socket: Option<PathBuf>,
+
+        #[command(subcommand)]
+        request: control::Request,
+    },
+}
+
+impl Cli {
+    fn apply_overrides(&self, config: &mut Config) {
+        if let Some(interface) = &self.interface {
+            config.interface = interface.clone();
         }
-        None => {
-            eprintln!("Error: The '-i <interface_name>' flag is mandatory.");
-            process::exit(1);
+        if self.passive {
+            config.passive = true;
         }
-    };
+        if let Some(log_level) = &self.log_level {
+            config.log_level = log_level.clone();
+        }
+        if self.persist {
+            config.tcp.persist = true;
+        }
+        if let Some(flow_lifetime) = self.flow_lifetime {
+            config.tcp.flow_lifetime_secs = flow_lifetime;
+        }
+    }
+}
 
-    let log_level_index = args.iter().position(|arg| arg == "--log-level");
-    let log_level = match log_level_index {
-        Some(index) => {
-            if let Some(level) = args.get(index + 1) {
-                level.clone()
-            } else {
-                eprintln!("Error: No value provided for '--log-level' flag.");
-                process::exit(1);
-            }
+/// The configuration file with the command line overrides applied,
+/// also used when the configuration is reloaded
+fn read_config(cli: &Cli) -> Result<Config, String> {
+    let mut config = match &cli.config {
+        Some(path) => {
+            Config::load(path).map_err(|e| format!("Error in {}: {}", path.display(), e))?
         }
-        None => "info".to_string(),
+        None => Config::default(),
+    };
+    cli.apply_overrides(&mut config);
+
+    let validated = if cli.read_pcap.is_some() {
+        config.validate()
+    } else {
+        config.require_interface().and_then(|()| config.validate())
     };
+    validated.map_err(|e| format!("Invalid configuration: {}", e))?;
+    Ok(config)
+}
+
+fn load_config(cli: &Cli) -> Config {
+    read_config(cli).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    })
+}
 
-    (passive_mode, interface_name, log_level)
+/// Sends request to a running ANTS and prints the claims or flows it
+/// lists as JSON lines
+fn run_ctl(cli: &Cli, socket: Option<PathBuf>, request: &control::Request) {
+    let configured = match &cli.config {
+        Some(path) => {
+            Config::load(path)
+                .unwrap_or_else(|e| {
+                    eprintln!("Error in {}: {}", path.display(), e);
+                    process::exit(1);
+                })
+                .control
+                .socket
+        }
+        None => String::new(),
+    };
+    let Some(socket) = control::socket_path(socket, &configured) else {
+        eprintln!("No control socket, give --socket or --config with control.socket set");
+        process::exit(1);
+    };
+    match control::send_request(&socket, request) {
+        Ok(entries) => {
+            for entry in entries {
+                println!("{}", entry);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}: {}", socket.display(), e);
+            process::exit(1);
+        }
+    }
 }
 
 fn main() {
-    let (passive_mode, interface_name, log_level) = parse_arguments();
+    let cli = Cli::parse();
+    if cli.print_default_config {
+        print!("{}", config::DEFAULT_CONFIG);
+        return;
+    }
+    if let Some(Command::Ctl { socket, request }) = &cli.command {
+        run_ctl(&cli, socket.clone(), request);
+        return;
+    }
 
-    init_tracing(&log_level);
+    let config = load_config(&cli);
+
+    let set_log_filter = init_tracing(&config.log_level, config.events.output == "-");
+
+    if let Some(peer) = &cli.benchmark {
+        benchmark::run_benchmark(&config.interface, peer);
+        return;
+    }
+
+    if let Some(input) = &cli.read_pcap {
+        info!("Replaying {}", input.display());
+        if let Err(e) = replay::replay_capture(&config, input, cli.write_pcap.as_deref()) {
+            eprintln!("Replay failed: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
 
     info!(
         "Starting tarpitting in {} mode",
-        if passive_mode { "passive" } else { "active" }
+        if config.passive { "passive" } else { "active" }
     );
-    info!("Interface: {}", interface_name);
+    info!("Interface: {}", config.interface);
+    if config.tcp.persist {
+        info!(
+            "Persist mode: holding connections for {} seconds",
+            config.tcp.flow_lifetime_secs
+        );
+    }
 
-    tarpitter::start_tarpitting(passive_mode, &interface_name);
+    tarpitter::start_tarpitting(&config, set_log_filter, Box::new(move || read_config(&cli)));
 }
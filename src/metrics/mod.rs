@@ -0,0 +1,333 @@
+use crate::packet_io::PacketIo;
+use crate::scan_classifier::{ScanKind, Tool};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info};
+
+/// How often listeners read the capture drop counters of their handles
+pub const DROPS_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Scrapes that send nothing for this long are given up on
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The capture handles ANTS reads from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capture {
+    Arp,
+    Ndp,
+    Tcp,
+}
+
+const CAPTURES: [(Capture, &str); 3] = [
+    (Capture::Arp, "arp"),
+    (Capture::Ndp, "ndp"),
+    (Capture::Tcp, "tcp"),
+];
+
+const SCAN_KINDS: [(ScanKind, &str); 4] = [
+    (ScanKind::SingleProbe, "single_probe"),
+    (ScanKind::HorizontalSweep, "horizontal_sweep"),
+    (ScanKind::VerticalScan, "vertical_scan"),
+    (ScanKind::WormLike, "worm_like"),
+];
+
+const TOOLS: [(Tool, &str); 3] = [
+    (Tool::Masscan, "masscan"),
+    (Tool::Zmap, "zmap"),
+    (Tool::Nmap, "nmap"),
+];
+
+/// Counters shared by the listener threads. Every update is a single
+/// relaxed atomic operation, the hot path never takes a lock.
+pub struct Metrics {
+    pub claimed_ips: AtomicU64,
+    pub arp_requests: AtomicU64,
+    pub arp_replies: AtomicU64,
+    pub syns: AtomicU64,
+    pub syn_acks: AtomicU64,
+    pub held_flows: AtomicU64,
+    pub passive_suppressed: AtomicU64,
+    pub transmit_dropped: AtomicU64,
+    capture_drops: [AtomicU64; CAPTURES.len()],
+    scanners: [AtomicU64; SCAN_KINDS.len()],
+    scanner_tools: [AtomicU64; TOOLS.len()],
+    syns_by_port: Box<[AtomicU64]>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            claimed_ips: AtomicU64::new(0),
+            arp_requests: AtomicU64::new(0),
+            arp_replies: AtomicU64::new(0),
+            syns: AtomicU64::new(0),
+            syn_acks: AtomicU64::new(0),
+            held_flows: AtomicU64::new(0),
+            passive_suppressed: AtomicU64::new(0),
+            transmit_dropped: AtomicU64::new(0),
+            capture_drops: Default::default(),
+            scanners: Default::default(),
+            scanner_tools: Default::default(),
+            syns_by_port: (0..=u16::MAX).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set(gauge: &AtomicU64, value: usize) {
+        gauge.store(value as u64, Ordering::Relaxed);
+    }
+
+    pub fn count_syn(&self, port: u16) {
+        Metrics::count(&self.syns);
+        Metrics::count(&self.syns_by_port[usize::from(port)]);
+    }
+
+    pub fn count_classification(&self, kind: ScanKind, tool: Option<Tool>) {
+        Metrics::count(&self.scanners[kind as usize]);
+        if let Some(tool) = tool {
+            Metrics::count(&self.scanner_tools[tool as usize]);
+        }
+    }
+
+    /// Reads the drop counter of a capture handle, backends without one are skipped
+    pub fn update_capture_drops(&self, capture: Capture, packet_io: &mut dyn PacketIo) {
+        if let Some(dropped) = packet_io.dropped() {
+            self.capture_drops[capture as usize].store(dropped, Ordering::Relaxed);
+        }
+    }
+
+    /// Totals logged when ANTS exits
+    pub fn summary(&self) -> String {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        format!(
+            "{} ARP requests seen, {} ARP replies sent, {} SYNs seen, {} SYN/ACKs sent, \
+             {} replies suppressed in passive mode",
+            load(&self.arp_requests),
+            load(&self.arp_replies),
+            load(&self.syns),
+            load(&self.syn_acks),
+            load(&self.passive_suppressed)
+        )
+    }
+
+    /// All metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: &AtomicU64| {
+            let _ = writeln!(text, "# HELP {} {}", name, help);
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+            let _ = writeln!(text, "{} {}", name, value.load(Ordering::Relaxed));
+        };
+        metric(
+            "ants_claimed_ips",
+            "gauge",
+            "IPs currently tarpitted",
+            &self.claimed_ips,
+        );
+        metric(
+            "ants_arp_requests_total",
+            "counter",
+            "ARP requests seen",
+            &self.arp_requests,
+        );
+        metric(
+            "ants_arp_replies_total",
+            "counter",
+            "ARP replies sent for claimed IPs",
+            &self.arp_replies,
+        );
+        metric("ants_syns_total", "counter", "TCP SYNs seen", &self.syns);
+        metric(
+            "ants_syn_acks_total",
+            "counter",
+            "SYN/ACKs sent",
+            &self.syn_acks,
+        );
+        metric(
+            "ants_held_flows",
+            "gauge",
+            "Connections held open in persist mode",
+            &self.held_flows,
+        );
+        metric(
+            "ants_passive_suppressed_total",
+            "counter",
+            "Replies not sent because of passive mode",
+            &self.passive_suppressed,
+        );
+        metric(
+            "ants_transmit_dropped_total",
+            "counter",
+            "Replies dropped because the transmit queue was full",
+            &self.transmit_dropped,
+        );
+
+        text.push_str("# HELP ants_capture_dropped_frames_total Frames the kernel dropped before ANTS read them\n");
+        text.push_str("# TYPE ants_capture_dropped_frames_total counter\n");
+        for (capture, name) in CAPTURES {
+            let dropped = self.capture_drops[capture as usize].load(Ordering::Relaxed);
+            let _ = writeln!(
+                text,
+                "ants_capture_dropped_frames_total{{capture=\"{}\"}} {}",
+                name, dropped
+            );
+        }
+
+        text.push_str(
+            "# HELP ants_scanners_total Scanners classified, by what their SYNs add up to\n",
+        );
+        text.push_str("# TYPE ants_scanners_total counter\n");
+        for (kind, name) in SCAN_KINDS {
+            let classified = self.scanners[kind as usize].load(Ordering::Relaxed);
+            let _ = writeln!(
+                text,
+                "ants_scanners_total{{kind=\"{}\"}} {}",
+                name, classified
+            );
+        }
+
+        text.push_str("# HELP ants_scanner_tools_total Scanners classified whose SYNs carry the traits of a known tool\n");
+        text.push_str("# TYPE ants_scanner_tools_total counter\n");
+        for (tool, name) in TOOLS {
+            let classified = self.scanner_tools[tool as usize].load(Ordering::Relaxed);
+            let _ = writeln!(
+                text,
+                "ants_scanner_tools_total{{tool=\"{}\"}} {}",
+                name, classified
+            );
+        }
+
+        text.push_str("# HELP ants_port_syns_total TCP SYNs seen per destination port\n");
+        text.push_str("# TYPE ants_port_syns_total counter\n");
+        for (port, syns) in self.syns_by_port.iter().enumerate() {
+            let syns = syns.load(Ordering::Relaxed);
+            if syns > 0 {
+                let _ = writeln!(text, "ants_port_syns_total{{port=\"{}\"}} {}", port, syns);
+            }
+        }
+        text
+    }
+}
+
+/// Reads a handle's drop counter once DROPS_POLL_INTERVAL has passed since last_poll
+pub fn poll_capture_drops(
+    metrics: &Metrics,
+    capture: Capture,
+    packet_io: &mut dyn PacketIo,
+    last_poll: &mut Instant,
+    now: Instant,
+) {
+    if now.duration_since(*last_poll) >= DROPS_POLL_INTERVAL {
+        metrics.update_capture_drops(capture, packet_io);
+        *last_poll = now;
+    }
+}
+
+/// Serves GET /metrics on listen from its own thread
+pub fn start_metrics_server(listen: SocketAddr, metrics: Arc<Metrics>) -> io::Result<()> {
+    let listener = TcpListener::bind(listen)?;
+    info!("Serving metrics on http://{}/metrics", listen);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| handle_scrape(stream, &metrics));
+            if let Err(e) = result {
+                debug!("Metrics request failed: {}", e);
+            }
+        }
+        error!("Metrics server stopped");
+    });
+    Ok(())
+}
+
+fn handle_scrape(stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers are not needed but are read so the client sees a clean close
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let path = request_line.split_whitespace().nth(1);
+    let (status, body) = if request_line.starts_with("GET ") && path == Some("/metrics") {
+        ("200 OK", metrics.render())
+    } else {
+        (
+            "404 Not Found",
+            "Not found, metrics are at /metrics\n".to_string(),
+        )
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        Metrics::set(&metrics.claimed_ips, 3);
+        metrics.count_syn(22);
+        metrics.count_syn(22);
+        metrics.count_syn(443);
+        metrics.count_classification(ScanKind::HorizontalSweep, Some(Tool::Masscan));
+
+        let text = metrics.render();
+
+        assert!(text.contains("# TYPE ants_claimed_ips gauge\nants_claimed_ips 3\n"));
+        assert!(text.contains("\nants_syns_total 3\n"));
+        assert!(text.contains("\nants_port_syns_total{port=\"22\"} 2\n"));
+        assert!(text.contains("\nants_port_syns_total{port=\"443\"} 1\n"));
+        assert!(!text.contains("port=\"80\""));
+        assert!(text.contains("\nants_capture_dropped_frames_total{capture=\"tcp\"} 0\n"));
+        assert!(text.contains("\nants_scanners_total{kind=\"horizontal_sweep\"} 1\n"));
+        assert!(text.contains("\nants_scanner_tools_total{tool=\"masscan\"} 1\n"));
+        assert!(text.contains("\nants_scanner_tools_total{tool=\"zmap\"} 0\n"));
+    }
+
+    #[test]
+    fn test_metrics_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let listen = listener.local_addr().unwrap();
+        drop(listener);
+        let metrics = Arc::new(Metrics::default());
+        Metrics::count(&metrics.arp_requests);
+        start_metrics_server(listen, metrics).unwrap();
+
+        let scrape = |path: &str| {
+            let mut stream = TcpStream::connect(listen).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: ants\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = scrape("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\nants_arp_requests_total 1\n"));
+        assert!(scrape("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,682 @@
+use crate::claims::{self, Claim, ClaimEvent, ClaimMacs, ClaimPolicy};
+use crate::config::DiscoveryConfig;
+use crate::control::PassiveMode;
+use crate::event_log::ReleaseReason;
+use crate::metrics::{self, Capture, Metrics};
+use crate::packet_decoder::{self, EthernetFrame, VlanTags};
+use crate::packet_io::{self, Frame, PacketIo};
+use crate::reload::Policies;
+use crate::shutdown::Shutdown;
+use pnet_base::MacAddr;
+use pnet_packet::ethernet::{EtherTypes, MutableEthernetPacket};
+use pnet_packet::icmpv6::ndp::{
+    MutableNeighborAdvertPacket, NdpOptionTypes, NeighborAdvertFlags, NeighborAdvertPacket,
+    NeighborSolicitPacket,
+};
+use pnet_packet::icmpv6::{self, Icmpv6Packet, Icmpv6Types};
+use pnet_packet::ip::IpNextHeaderProtocols;
+use pnet_packet::ipv6::MutableIpv6Packet;
+use pnet_packet::Packet;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info};
+
+/// Unanswered Neighbor Solicitations per (target, sender) pair
+type SolicitCounts = HashMap<(IpAddr, IpAddr), (u32, Instant)>;
+
+/// Hop limit of every Neighbor Discovery message, anything else was routed
+const NDP_HOP_LIMIT: u8 = 255;
+
+/// Ethernet + IPv6 + Neighbor Advertisement with a target link-layer address option
+const NEIGHBOR_ADVERT_FRAME_LEN: usize = 14 + 40 + 24 + 8;
+
+/// A Neighbor Solicitation that went unanswered often enough to be claimed
+struct NdpInfo {
+    vlan_tags: VlanTags,
+    sender_ip: Ipv6Addr,
+    target_ip: Ipv6Addr,
+    sender_mac: MacAddr,
+}
+
+/// The Neighbor Discovery messages ANTS cares about
+enum NdpMessage {
+    Solicit {
+        src_ip: Ipv6Addr,
+        target_ip: Ipv6Addr,
+    },
+    Advert {
+        target_ip: Ipv6Addr,
+    },
+}
+
+/// NDP side of ANTS without any I/O, the IPv6 counterpart of arp_listener::ArpState
+pub struct NdpState {
+    solicit_counts: HashMap<VlanTags, SolicitCounts>,
+    claimed: HashSet<Claim>,
+    discovery: DiscoveryConfig,
+    claim_policy: ClaimPolicy,
+    claim_macs: ClaimMacs,
+    passive_mode: PassiveMode,
+    metrics: Arc<Metrics>,
+}
+
+impl NdpState {
+    pub fn new(
+        discovery: DiscoveryConfig,
+        claim_policy: ClaimPolicy,
+        claim_macs: ClaimMacs,
+        passive_mode: PassiveMode,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        NdpState {
+            solicit_counts: HashMap::new(),
+            claimed: HashSet::new(),
+            discovery,
+            claim_policy,
+            claim_macs,
+            passive_mode,
+            metrics,
+        }
+    }
+
+    /// Follows claims made or released elsewhere: restored from the state
+    /// file, sent on the control socket or learned by the other listener
+    pub fn apply_claim_event(&mut self, event: ClaimEvent) {
+        match event {
+            ClaimEvent::Claimed(claim) if claim.ip.is_ipv6() => {
+                self.claimed.insert(claim);
+            }
+            ClaimEvent::Claimed(_) => {}
+            ClaimEvent::Released(claim, _) => {
+                self.claimed.remove(&claim);
+            }
+        }
+    }
+
+    /// Thresholds of a reloaded configuration apply to IPs claimed from now
+    /// on, requests already counted are kept. Returns the releases of claimed
+    /// IPs its claim policy refuses.
+    pub fn apply_policies(&mut self, policies: &Policies) -> Vec<ClaimEvent> {
+        self.discovery = policies.discovery;
+        self.claim_policy = policies.claim_policy.clone();
+        claims::release_refused(&mut self.claimed, &self.claim_policy)
+    }
+
+    /// Runs one received frame through the NDP logic. Returns the resulting
+    /// claim event and, unless in passive mode, the Neighbor Advertisement to send.
+    pub fn handle_frame(
+        &mut self,
+        frame: &[u8],
+        now: Instant,
+    ) -> Option<(ClaimEvent, Option<Vec<u8>>)> {
+        let ethernet_frame = packet_decoder::decode_ethernet(frame)?;
+        if let Some(claim) = release_claim(&ethernet_frame, &mut self.claimed, &self.claim_macs) {
+            return Some((
+                ClaimEvent::Released(claim, ReleaseReason::OwnerReturned),
+                None,
+            ));
+        }
+
+        // Solicitations for claimed IPs are answered at once, their owner is known to be gone
+        let request_threshold = if solicits_claim(&ethernet_frame, &self.claimed) {
+            1
+        } else {
+            self.discovery.request_threshold
+        };
+        let solicit_info = process_ndp_packet(
+            &ethernet_frame,
+            self.solicit_counts
+                .entry(ethernet_frame.vlan_tags)
+                .or_default(),
+            request_threshold,
+            self.discovery.request_timeout(),
+            now,
+        )?;
+
+        let claim = Claim {
+            vlan_tags: solicit_info.vlan_tags,
+            ip: IpAddr::V6(solicit_info.target_ip),
+        };
+        if let Err(refusal) = self.claim_policy.check(claim.ip) {
+            info!("Not claiming {}: {}", claim, refusal);
+            return None;
+        }
+        self.claimed.insert(claim);
+
+        if self.passive_mode.is_on() {
+            Metrics::count(&self.metrics.passive_suppressed);
+        }
+        let advert = (!self.passive_mode.is_on()).then(|| {
+            let mac_address = self.claim_macs.mac_for(claim.ip);
+            debug!(
+                "Neighbor Advertisement: {} is at {} to {} on {}",
+                solicit_info.target_ip, mac_address, solicit_info.sender_ip, solicit_info.vlan_tags
+            );
+            create_neighbor_advert_frame(&solicit_info, mac_address)
+        });
+        Some((ClaimEvent::Claimed(claim), advert))
+    }
+}
+
+/// Creates thread to handle Neighbor Solicitations and Advertisements,
+/// the IPv6 counterpart of arp_listener::start_arp_handling.
+/// Claimed addresses are sent to claim_sender unless the claim policy refuses
+/// them, and released when another host advertises or solicits from them.
+pub fn start_ndp_handling(
+    mut packet_io: Box<dyn PacketIo>,
+    mut ndp_state: NdpState,
+    claim_sender: mpsc::Sender<ClaimEvent>,
+    claim_updates: mpsc::Receiver<ClaimEvent>,
+    policy_updates: mpsc::Receiver<Arc<Policies>>,
+    metrics: Arc<Metrics>,
+    shutdown: Shutdown,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let filter = packet_io::with_vlans("icmp6", packet_decoder::MAX_VLAN_TAGS);
+        if let Err(e) = packet_io.set_filter(&filter) {
+            error!("Failed to install NDP capture filter: {}", e);
+        }
+
+        let mut last_drops_poll = Instant::now();
+        loop {
+            if shutdown.is_requested() {
+                debug!("Shutting down, exiting NDP handling thread.");
+                break;
+            }
+            metrics::poll_capture_drops(
+                &metrics,
+                Capture::Ndp,
+                packet_io.as_mut(),
+                &mut last_drops_poll,
+                Instant::now(),
+            );
+            // Applied before reading, so they arrive even when no frames pass the filter
+            while let Ok(event) = claim_updates.try_recv() {
+                ndp_state.apply_claim_event(event);
+            }
+            while let Ok(policies) = policy_updates.try_recv() {
+                for event in ndp_state.apply_policies(&policies) {
+                    let _ = claim_sender.send(event);
+                }
+            }
+            let frame = match packet_io.recv() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => {
+                    debug!("Capture ended, exiting NDP handling thread.");
+                    break;
+                }
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => {
+                    error!("An error occurred while reading: {}", e);
+                    continue;
+                }
+            };
+            let Some((event, advert)) = ndp_state.handle_frame(frame.data, Instant::now()) else {
+                continue;
+            };
+            if let Some(advert) = advert {
+                if let Err(e) = packet_io.send(Frame::now(&advert)) {
+                    error!("Failed to send Neighbor Advertisement: {}", e);
+                }
+            }
+
+            if claim_sender.send(event).is_err() {
+                error!("Receiver dropped, exiting NDP handling thread.");
+                break;
+            }
+        }
+    })
+}
+
+/// Gives back a claimed IP when another MAC advertises it, solicits from
+/// it or runs duplicate address detection for it
+fn release_claim(
+    ethernet_frame: &EthernetFrame<'_>,
+    claimed: &mut HashSet<Claim>,
+    claim_macs: &ClaimMacs,
+) -> Option<Claim> {
+    // A host running duplicate address detection is about to take the address, it is not defended
+    let owner_ip = match decode_ndp_message(ethernet_frame)? {
+        NdpMessage::Solicit { src_ip, target_ip } if src_ip.is_unspecified() => target_ip,
+        NdpMessage::Solicit { src_ip, .. } => src_ip,
+        NdpMessage::Advert { target_ip } => target_ip,
+    };
+
+    let claim = Claim {
+        vlan_tags: ethernet_frame.vlan_tags,
+        ip: IpAddr::V6(owner_ip),
+    };
+    if claim_macs.is_own(ethernet_frame.source, claim.ip) || !claimed.remove(&claim) {
+        return None;
+    }
+    debug!(
+        "{} is in use by {}, releasing it",
+        claim, ethernet_frame.source
+    );
+    Some(claim)
+}
+
+fn solicits_claim(ethernet_frame: &EthernetFrame<'_>, claimed: &HashSet<Claim>) -> bool {
+    matches!(
+        decode_ndp_message(ethernet_frame),
+        Some(NdpMessage::Solicit { target_ip, .. }) if claimed.contains(&Claim {
+            vlan_tags: ethernet_frame.vlan_tags,
+            ip: IpAddr::V6(target_ip),
+        })
+    )
+}
+
+/// Builds a solicited Neighbor Advertisement answering for the solicited target
+fn create_neighbor_advert_frame(solicit_info: &NdpInfo, mac_address: MacAddr) -> Vec<u8> {
+    let mut ethernet_buffer = [0u8; NEIGHBOR_ADVERT_FRAME_LEN];
+
+    {
+        let mut ethernet_packet = MutableEthernetPacket::new(&mut ethernet_buffer).unwrap();
+        ethernet_packet.set_destination(solicit_info.sender_mac);
+        ethernet_packet.set_source(mac_address);
+        ethernet_packet.set_ethertype(EtherTypes::Ipv6);
+    }
+
+    {
+        let mut ipv6_packet = MutableIpv6Packet::new(&mut ethernet_buffer[14..54]).unwrap();
+        ipv6_packet.set_version(6);
+        ipv6_packet.set_payload_length((NEIGHBOR_ADVERT_FRAME_LEN - 54) as u16);
+        ipv6_packet.set_next_header(IpNextHeaderProtocols::Icmpv6);
+        ipv6_packet.set_hop_limit(NDP_HOP_LIMIT);
+        ipv6_packet.set_source(solicit_info.target_ip);
+        ipv6_packet.set_destination(solicit_info.sender_ip);
+    }
+
+    {
+        let icmp_buffer = &mut ethernet_buffer[54..];
+        // Target link-layer address option, length is in units of 8 bytes
+        icmp_buffer[24] = NdpOptionTypes::TargetLLAddr.0;
+        icmp_buffer[25] = 1;
+        icmp_buffer[26..32].copy_from_slice(&mac_address.octets());
+
+        let mut advert_packet = MutableNeighborAdvertPacket::new(icmp_buffer).unwrap();
+        advert_packet.set_icmpv6_type(Icmpv6Types::NeighborAdvert);
+        advert_packet.set_flags(NeighborAdvertFlags::Solicited | NeighborAdvertFlags::Override);
+        advert_packet.set_target_addr(solicit_info.target_ip);
+
+        let icmp_checksum = icmpv6::checksum(
+            &Icmpv6Packet::new(advert_packet.packet()).unwrap(),
+            &solicit_info.target_ip,
+            &solicit_info.sender_ip,
+        );
+        advert_packet.set_checksum(icmp_checksum);
+    }
+
+    packet_decoder::tag_frame(&ethernet_buffer, solicit_info.vlan_tags)
+}
+
+fn track_solicit(
+    target_ip: Ipv6Addr,
+    sender_ip: Ipv6Addr,
+    solicit_counts: &mut SolicitCounts,
+    request_threshold: u32,
+    request_timeout: Duration,
+    now: Instant,
+) -> bool {
+    let key = (IpAddr::V6(target_ip), IpAddr::V6(sender_ip));
+    let entry = solicit_counts.entry(key).or_insert((0, now));
+
+    if now.duration_since(entry.1) > request_timeout {
+        entry.0 = 0;
+    }
+
+    entry.0 += 1;
+    entry.1 = now;
+
+    if entry.0 >= request_threshold {
+        debug!(
+            "Detected {} unanswered Neighbor Solicitations for {}",
+            request_threshold, target_ip
+        );
+        solicit_counts.remove(&key);
+
+        return true;
+    }
+    false
+}
+
+/// Decodes a Neighbor Solicitation or Advertisement, dropping routed
+/// messages and ones with a bad checksum
+fn decode_ndp_message(ethernet_frame: &EthernetFrame<'_>) -> Option<NdpMessage> {
+    let datagram = packet_decoder::decode_ip_datagram(ethernet_frame)?;
+    let (IpAddr::V6(src_ip), IpAddr::V6(dst_ip)) = (datagram.src_ip, datagram.dst_ip) else {
+        return None;
+    };
+    //Discard routed or invalid packets
+    if datagram.protocol != IpNextHeaderProtocols::Icmpv6 || datagram.ttl != NDP_HOP_LIMIT {
+        return None;
+    }
+    let icmp_packet = Icmpv6Packet::new(datagram.payload)?;
+    if icmp_packet.get_checksum() != icmpv6::checksum(&icmp_packet, &src_ip, &dst_ip) {
+        return None;
+    }
+
+    match icmp_packet.get_icmpv6_type() {
+        Icmpv6Types::NeighborSolicit => {
+            let solicit_packet = NeighborSolicitPacket::new(datagram.payload)?;
+            Some(NdpMessage::Solicit {
+                src_ip,
+                target_ip: solicit_packet.get_target_addr(),
+            })
+        }
+        Icmpv6Types::NeighborAdvert => {
+            let advert_packet = NeighborAdvertPacket::new(datagram.payload)?;
+            Some(NdpMessage::Advert {
+                target_ip: advert_packet.get_target_addr(),
+            })
+        }
+        _ => None,
+    }
+}
+
+fn process_ndp_packet(
+    ethernet_frame: &EthernetFrame<'_>,
+    solicit_counts: &mut SolicitCounts,
+    request_threshold: u32,
+    request_timeout: Duration,
+    now: Instant,
+) -> Option<NdpInfo> {
+    match decode_ndp_message(ethernet_frame)? {
+        NdpMessage::Solicit { src_ip, target_ip } => {
+            // Duplicate address detection comes from the unspecified address
+            // and cannot be answered
+            if src_ip.is_unspecified() {
+                debug!("Duplicate address detection for {}", target_ip);
+                return None;
+            }
+            debug!(
+                "Neighbor Solicitation: {} is asking for {}",
+                src_ip, target_ip
+            );
+            if track_solicit(
+                target_ip,
+                src_ip,
+                solicit_counts,
+                request_threshold,
+                request_timeout,
+                now,
+            ) {
+                return Some(NdpInfo {
+                    vlan_tags: ethernet_frame.vlan_tags,
+                    sender_ip: src_ip,
+                    target_ip,
+                    sender_mac: ethernet_frame.source,
+                });
+            }
+        }
+        NdpMessage::Advert { target_ip } => {
+            debug!(
+                "Neighbor Advertisement: {} is at {}",
+                target_ip, ethernet_frame.source
+            );
+            solicit_counts.retain(|(target, _), _| *target != IpAddr::V6(target_ip));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MacConfig;
+    use crate::packet_decoder::{decode_ethernet, VlanTag};
+    use pnet_packet::icmpv6::ndp::MutableNeighborSolicitPacket;
+    use pnet_packet::icmpv6::Icmpv6Type;
+
+    const SCANNER_MAC: MacAddr = MacAddr(0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB);
+    const SCANNER_IP: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0x100);
+    const TARGET_IP: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0x1);
+
+    fn create_ndp_frame(
+        icmpv6_type: Icmpv6Type,
+        src_ip: Ipv6Addr,
+        target_ip: Ipv6Addr,
+        hop_limit: u8,
+    ) -> Vec<u8> {
+        let mut frame = vec![0u8; 14 + 40 + 24];
+        {
+            let mut eth_packet = MutableEthernetPacket::new(&mut frame).unwrap();
+            eth_packet.set_source(SCANNER_MAC);
+            eth_packet.set_ethertype(EtherTypes::Ipv6);
+        }
+        // Solicited-node multicast address of the target
+        let mut dst_segments = [0xff02, 0, 0, 0, 0, 1, 0xff00, 0];
+        let target_segments = target_ip.segments();
+        dst_segments[6] |= target_segments[6] & 0xff;
+        dst_segments[7] = target_segments[7];
+        let dst_ip = Ipv6Addr::from(dst_segments);
+        {
+            let mut ipv6_packet = MutableIpv6Packet::new(&mut frame[14..54]).unwrap();
+            ipv6_packet.set_version(6);
+            ipv6_packet.set_payload_length(24);
+            ipv6_packet.set_next_header(IpNextHeaderProtocols::Icmpv6);
+            ipv6_packet.set_hop_limit(hop_limit);
+            ipv6_packet.set_source(src_ip);
+            ipv6_packet.set_destination(dst_ip);
+        }
+        {
+            let mut solicit_packet = MutableNeighborSolicitPacket::new(&mut frame[54..]).unwrap();
+            solicit_packet.set_icmpv6_type(icmpv6_type);
+            solicit_packet.set_target_addr(target_ip);
+            let icmp_checksum = icmpv6::checksum(
+                &Icmpv6Packet::new(solicit_packet.packet()).unwrap(),
+                &src_ip,
+                &dst_ip,
+            );
+            solicit_packet.set_checksum(icmp_checksum);
+        }
+        frame
+    }
+
+    fn process(frame: &[u8], solicit_counts: &mut SolicitCounts, now: Instant) -> Option<NdpInfo> {
+        let ethernet_frame = decode_ethernet(frame).unwrap();
+        process_ndp_packet(
+            &ethernet_frame,
+            solicit_counts,
+            2,
+            Duration::from_secs(5),
+            now,
+        )
+    }
+
+    #[test]
+    fn test_process_ndp_packet_solicit_exceed_threshold() {
+        let frame = create_ndp_frame(
+            Icmpv6Types::NeighborSolicit,
+            SCANNER_IP,
+            TARGET_IP,
+            NDP_HOP_LIMIT,
+        );
+        let mut solicit_counts = HashMap::new();
+        let now = Instant::now();
+
+        assert!(process(&frame, &mut solicit_counts, now).is_none());
+        let ndp_info = process(&frame, &mut solicit_counts, now).unwrap();
+
+        assert_eq!(ndp_info.target_ip, TARGET_IP);
+        assert_eq!(ndp_info.sender_ip, SCANNER_IP);
+        assert_eq!(ndp_info.sender_mac, SCANNER_MAC);
+        assert!(solicit_counts.is_empty());
+    }
+
+    #[test]
+    fn test_process_ndp_packet_solicit_timeout_resets_count() {
+        let frame = create_ndp_frame(
+            Icmpv6Types::NeighborSolicit,
+            SCANNER_IP,
+            TARGET_IP,
+            NDP_HOP_LIMIT,
+        );
+        let mut solicit_counts = HashMap::new();
+        let now = Instant::now();
+
+        assert!(process(&frame, &mut solicit_counts, now).is_none());
+        assert!(process(&frame, &mut solicit_counts, now + Duration::from_secs(6)).is_none());
+    }
+
+    #[test]
+    fn test_process_ndp_packet_advert_clears_counts() {
+        let solicit = create_ndp_frame(
+            Icmpv6Types::NeighborSolicit,
+            SCANNER_IP,
+            TARGET_IP,
+            NDP_HOP_LIMIT,
+        );
+        let advert = create_ndp_frame(
+            Icmpv6Types::NeighborAdvert,
+            TARGET_IP,
+            TARGET_IP,
+            NDP_HOP_LIMIT,
+        );
+        let mut solicit_counts = HashMap::new();
+        let now = Instant::now();
+
+        assert!(process(&solicit, &mut solicit_counts, now).is_none());
+        assert!(process(&advert, &mut solicit_counts, now).is_none());
+        assert!(solicit_counts.is_empty());
+    }
+
+    #[test]
+    fn test_process_ndp_packet_ignores_routed_and_dad() {
+        let routed = create_ndp_frame(Icmpv6Types::NeighborSolicit, SCANNER_IP, TARGET_IP, 64);
+        let dad = create_ndp_frame(
+            Icmpv6Types::NeighborSolicit,
+            Ipv6Addr::UNSPECIFIED,
+            TARGET_IP,
+            NDP_HOP_LIMIT,
+        );
+        let mut solicit_counts = HashMap::new();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(process(&routed, &mut solicit_counts, now).is_none());
+            assert!(process(&dad, &mut solicit_counts, now).is_none());
+        }
+        assert!(solicit_counts.is_empty());
+    }
+
+    #[test]
+    fn test_process_ndp_packet_bad_checksum() {
+        let mut frame = create_ndp_frame(
+            Icmpv6Types::NeighborSolicit,
+            SCANNER_IP,
+            TARGET_IP,
+            NDP_HOP_LIMIT,
+        );
+        frame[56] ^= 0xff;
+        let mut solicit_counts = HashMap::new();
+
+        assert!(process(&frame, &mut solicit_counts, Instant::now()).is_none());
+        assert!(solicit_counts.is_empty());
+    }
+
+    #[test]
+    fn test_create_neighbor_advert_frame() {
+        let mut vlan_tags = VlanTags::default();
+        vlan_tags.push(VlanTag {
+            tpid: 0x8100,
+            vid: 10,
+        });
+        let solicit_info = NdpInfo {
+            vlan_tags,
+            sender_ip: SCANNER_IP,
+            target_ip: TARGET_IP,
+            sender_mac: SCANNER_MAC,
+        };
+        let mac_address = MacAddr::new(0x00, 0x11, 0x22, 0x33, 0x44, 0x55);
+
+        let frame = create_neighbor_advert_frame(&solicit_info, mac_address);
+
+        let ethernet_frame = decode_ethernet(&frame).unwrap();
+        assert_eq!(ethernet_frame.vlan_tags, vlan_tags);
+        assert_eq!(ethernet_frame.destination, SCANNER_MAC);
+        let datagram = packet_decoder::decode_ip_datagram(&ethernet_frame).unwrap();
+        assert_eq!(datagram.src_ip, IpAddr::V6(TARGET_IP));
+        assert_eq!(datagram.dst_ip, IpAddr::V6(SCANNER_IP));
+        assert_eq!(datagram.ttl, NDP_HOP_LIMIT);
+
+        let icmp_packet = Icmpv6Packet::new(datagram.payload).unwrap();
+        assert_eq!(
+            icmp_packet.get_checksum(),
+            icmpv6::checksum(&icmp_packet, &TARGET_IP, &SCANNER_IP)
+        );
+        let advert_packet = NeighborAdvertPacket::new(datagram.payload).unwrap();
+        assert_eq!(advert_packet.get_icmpv6_type(), Icmpv6Types::NeighborAdvert);
+        assert_eq!(
+            advert_packet.get_flags(),
+            NeighborAdvertFlags::Solicited | NeighborAdvertFlags::Override
+        );
+        assert_eq!(advert_packet.get_target_addr(), TARGET_IP);
+        assert_eq!(
+            advert_packet.get_options()[0].option_type,
+            NdpOptionTypes::TargetLLAddr
+        );
+        assert_eq!(advert_packet.get_options()[0].data, mac_address.octets());
+    }
+
+    #[test]
+    fn test_release_claim_on_advert_from_other_mac() {
+        let advert = create_ndp_frame(
+            Icmpv6Types::NeighborAdvert,
+            TARGET_IP,
+            TARGET_IP,
+            NDP_HOP_LIMIT,
+        );
+        let claim = Claim {
+            vlan_tags: VlanTags::default(),
+            ip: IpAddr::V6(TARGET_IP),
+        };
+        let mut claimed = HashSet::from([claim]);
+        let ethernet_frame = decode_ethernet(&advert).unwrap();
+
+        // Advertisements sent by ANTS itself do not release anything
+        let own_macs = ClaimMacs::new(MacConfig::default(), SCANNER_MAC);
+        assert!(release_claim(&ethernet_frame, &mut claimed, &own_macs).is_none());
+        let other_macs = ClaimMacs::new(MacConfig::default(), MacAddr::zero());
+        assert_eq!(
+            release_claim(&ethernet_frame, &mut claimed, &other_macs),
+            Some(claim)
+        );
+        assert!(claimed.is_empty());
+    }
+
+    #[test]
+    fn test_release_claim_on_duplicate_address_detection() {
+        let dad = create_ndp_frame(
+            Icmpv6Types::NeighborSolicit,
+            Ipv6Addr::UNSPECIFIED,
+            TARGET_IP,
+            NDP_HOP_LIMIT,
+        );
+        let claim = Claim {
+            vlan_tags: VlanTags::default(),
+            ip: IpAddr::V6(TARGET_IP),
+        };
+        let mut ndp_state = NdpState::new(
+            DiscoveryConfig::default(),
+            ClaimPolicy::default(),
+            ClaimMacs::new(MacConfig::default(), MacAddr::zero()),
+            PassiveMode::default(),
+            Arc::new(Metrics::default()),
+        );
+        ndp_state.apply_claim_event(ClaimEvent::Claimed(claim));
+
+        let (event, advert) = ndp_state.handle_frame(&dad, Instant::now()).unwrap();
+        assert_eq!(
+            event,
+            ClaimEvent::Released(claim, ReleaseReason::OwnerReturned)
+        );
+        assert!(advert.is_none());
+        assert!(ndp_state.claimed.is_empty());
+        assert!(ndp_state.handle_frame(&dad, Instant::now()).is_none());
+    }
+}
@@ -0,0 +1,831 @@
+use pnet_base::MacAddr;
+use pnet_packet::ethernet::{EtherType, EtherTypes, EthernetPacket};
+use pnet_packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet_packet::ipv4::{self, Ipv4Flags, Ipv4Packet};
+use pnet_packet::ipv6::Ipv6Packet;
+use pnet_packet::tcp::{self, TcpPacket};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::net::IpAddr;
+
+/// Tag protocol identifiers accepted in front of the payload ethertype
+const VLAN_TPIDS: [EtherType; 3] = [EtherTypes::Vlan, EtherTypes::PBridge, EtherTypes::QinQ];
+
+/// Deepest tag stack handled, enough for QinQ
+pub const MAX_VLAN_TAGS: usize = 2;
+
+/// TCP option kinds from RFC 9293, RFC 7323 and RFC 2018
+pub const TCP_OPTION_END: u8 = 0;
+pub const TCP_OPTION_NOP: u8 = 1;
+pub const TCP_OPTION_MSS: u8 = 2;
+pub const TCP_OPTION_WINDOW_SCALE: u8 = 3;
+pub const TCP_OPTION_SACK_PERMITTED: u8 = 4;
+pub const TCP_OPTION_TIMESTAMP: u8 = 8;
+
+/// Option kinds kept per segment, more than any common stack sends
+pub const MAX_OPTION_KINDS: usize = 12;
+
+/// A single 802.1Q tag
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct VlanTag {
+    pub tpid: u16,
+    pub vid: u16,
+}
+
+/// The VLAN tags of a frame, outermost first. Empty for untagged frames.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct VlanTags {
+    tags: [VlanTag; MAX_VLAN_TAGS],
+    len: usize,
+}
+
+impl VlanTags {
+    /// Appends an inner tag, returns false if the stack is already full
+    pub fn push(&mut self, tag: VlanTag) -> bool {
+        if self.len == MAX_VLAN_TAGS {
+            return false;
+        }
+        self.tags[self.len] = tag;
+        self.len += 1;
+        true
+    }
+
+    pub fn as_slice(&self) -> &[VlanTag] {
+        &self.tags[..self.len]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Serialized as the list of VLAN IDs, outermost first
+impl Serialize for VlanTags {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.as_slice().iter().map(|tag| tag.vid))
+    }
+}
+
+impl fmt::Display for VlanTags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "untagged");
+        }
+        write!(f, "vlan ")?;
+        for (i, tag) in self.as_slice().iter().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+            write!(f, "{}", tag.vid)?;
+        }
+        Ok(())
+    }
+}
+
+/// The kinds of a segment's TCP options in the order they were sent, NOPs
+/// included, up to the end-of-options option or a malformed one
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OptionKinds {
+    kinds: [u8; MAX_OPTION_KINDS],
+    len: usize,
+}
+
+impl OptionKinds {
+    /// Appends a kind, returns false if MAX_OPTION_KINDS are already kept
+    pub fn push(&mut self, kind: u8) -> bool {
+        if self.len == MAX_OPTION_KINDS {
+            return false;
+        }
+        self.kinds[self.len] = kind;
+        self.len += 1;
+        true
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.kinds[..self.len]
+    }
+}
+
+/// Ethernet header with any VLAN tags stripped off
+pub struct EthernetFrame<'a> {
+    pub source: MacAddr,
+    pub destination: MacAddr,
+    pub vlan_tags: VlanTags,
+    pub ethertype: EtherType,
+    pub payload: &'a [u8],
+}
+
+/// An IPv4 or IPv6 datagram with its header and extension headers removed
+pub struct IpDatagram<'a> {
+    pub src_ip: IpAddr,
+    pub dst_ip: IpAddr,
+    /// TTL for IPv4, hop limit for IPv6
+    pub ttl: u8,
+    /// Identification of IPv4 datagrams
+    pub id: Option<u16>,
+    pub protocol: IpNextHeaderProtocol,
+    /// Set for the first fragment of a fragmented datagram
+    pub more_fragments: bool,
+    pub payload: &'a [u8],
+}
+
+/// The TCP options of a SYN that decide which ones a SYN/ACK may carry
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct SynOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mss: Option<u16>,
+    pub sack_permitted: bool,
+    pub window_scale: bool,
+    /// TSval of the timestamp option
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u32>,
+}
+
+/// The fields of a TCP segment over IPv4 or IPv6 that ANTS needs to answer it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TcpSegment {
+    pub src_mac: MacAddr,
+    pub dst_mac: MacAddr,
+    pub vlan_tags: VlanTags,
+    pub src_ip: IpAddr,
+    pub dst_ip: IpAddr,
+    /// TTL for IPv4, hop limit for IPv6
+    pub ttl: u8,
+    /// IPv4 identification, None over IPv6
+    pub ip_id: Option<u16>,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub sequence: u32,
+    pub acknowledgement: u32,
+    pub flags: u8,
+    pub window: u16,
+    pub options: SynOptions,
+    pub option_kinds: OptionKinds,
+    pub payload_len: usize,
+}
+
+/// Decodes the Ethernet header, stripping 802.1Q and QinQ tags.
+/// Frames with more than MAX_VLAN_TAGS tags are not decoded.
+pub fn decode_ethernet(frame: &[u8]) -> Option<EthernetFrame<'_>> {
+    let ethernet_packet = EthernetPacket::new(frame)?;
+    let header_len = EthernetPacket::minimum_packet_size();
+
+    let mut ethertype = ethernet_packet.get_ethertype();
+    let mut offset = header_len;
+    let mut vlan_tags = VlanTags::default();
+
+    while VLAN_TPIDS.contains(&ethertype) {
+        // Tag control information followed by the next ethertype
+        let tag = frame.get(offset..offset + 4)?;
+        let pushed = vlan_tags.push(VlanTag {
+            tpid: ethertype.0,
+            vid: u16::from_be_bytes([tag[0], tag[1]]) & 0x0fff,
+        });
+        if !pushed {
+            return None;
+        }
+        ethertype = EtherType(u16::from_be_bytes([tag[2], tag[3]]));
+        offset += 4;
+    }
+
+    Some(EthernetFrame {
+        source: ethernet_packet.get_source(),
+        destination: ethernet_packet.get_destination(),
+        vlan_tags,
+        ethertype,
+        payload: &frame[offset..],
+    })
+}
+
+/// Inserts VLAN tags after the MAC addresses of an untagged frame, so a
+/// reply leaves on the same VLAN as the request it answers
+pub fn tag_frame(frame: &[u8], vlan_tags: VlanTags) -> Vec<u8> {
+    let mut tagged = Vec::with_capacity(frame.len() + vlan_tags.as_slice().len() * 4);
+    tagged.extend_from_slice(&frame[..12]);
+    for tag in vlan_tags.as_slice() {
+        tagged.extend_from_slice(&tag.tpid.to_be_bytes());
+        tagged.extend_from_slice(&tag.vid.to_be_bytes());
+    }
+    tagged.extend_from_slice(&frame[12..]);
+    tagged
+}
+
+/// Decodes the IP header of an Ethernet payload.
+///
+/// Returns None for anything that is not a well-formed datagram: other
+/// ethertypes, bad IPv4 header checksums, truncated headers and
+/// non-first fragments. IPv6 extension headers are skipped.
+pub fn decode_ip_datagram<'a>(ethernet_frame: &EthernetFrame<'a>) -> Option<IpDatagram<'a>> {
+    match ethernet_frame.ethertype {
+        EtherTypes::Ipv4 => decode_ipv4(ethernet_frame.payload),
+        EtherTypes::Ipv6 => decode_ipv6(ethernet_frame.payload),
+        _ => None,
+    }
+}
+
+fn decode_ipv4(payload: &[u8]) -> Option<IpDatagram<'_>> {
+    let ipv4_packet = Ipv4Packet::new(payload)?;
+    if ipv4_packet.get_version() != 4 {
+        return None;
+    }
+
+    let header_len = usize::from(ipv4_packet.get_header_length()) * 4;
+    let total_len = usize::from(ipv4_packet.get_total_length());
+    if header_len < Ipv4Packet::minimum_packet_size()
+        || total_len < header_len
+        || total_len > payload.len()
+    {
+        return None;
+    }
+
+    if ipv4::checksum(&ipv4_packet) != ipv4_packet.get_checksum() {
+        return None;
+    }
+
+    if ipv4_packet.get_fragment_offset() != 0 {
+        return None;
+    }
+
+    Some(IpDatagram {
+        src_ip: IpAddr::V4(ipv4_packet.get_source()),
+        dst_ip: IpAddr::V4(ipv4_packet.get_destination()),
+        ttl: ipv4_packet.get_ttl(),
+        id: Some(ipv4_packet.get_identification()),
+        protocol: ipv4_packet.get_next_level_protocol(),
+        more_fragments: ipv4_packet.get_flags() & Ipv4Flags::MoreFragments != 0,
+        // Ethernet padding after the IP datagram is not part of the payload
+        payload: &payload[header_len..total_len],
+    })
+}
+
+fn decode_ipv6(payload: &[u8]) -> Option<IpDatagram<'_>> {
+    let ipv6_packet = Ipv6Packet::new(payload)?;
+    if ipv6_packet.get_version() != 6 {
+        return None;
+    }
+
+    let header_len = Ipv6Packet::minimum_packet_size();
+    let total_len = header_len + usize::from(ipv6_packet.get_payload_length());
+    if total_len > payload.len() {
+        return None;
+    }
+
+    let mut protocol = ipv6_packet.get_next_header();
+    let mut offset = header_len;
+    let mut more_fragments = false;
+
+    loop {
+        let extension = &payload[offset..total_len];
+        let extension_len = match protocol {
+            IpNextHeaderProtocols::Hopopt
+            | IpNextHeaderProtocols::Ipv6Route
+            | IpNextHeaderProtocols::Ipv6Opts => (usize::from(*extension.get(1)?) + 1) * 8,
+            IpNextHeaderProtocols::Ipv6Frag => {
+                let fragment = extension.get(..8)?;
+                let fragment_offset = u16::from_be_bytes([fragment[2], fragment[3]]) >> 3;
+                if fragment_offset != 0 {
+                    return None;
+                }
+                more_fragments = fragment[3] & 1 != 0;
+                8
+            }
+            IpNextHeaderProtocols::Ah => (usize::from(*extension.get(1)?) + 2) * 4,
+            _ => break,
+        };
+        if extension_len > extension.len() {
+            return None;
+        }
+        protocol = IpNextHeaderProtocol(extension[0]);
+        offset += extension_len;
+    }
+
+    Some(IpDatagram {
+        src_ip: IpAddr::V6(ipv6_packet.get_source()),
+        dst_ip: IpAddr::V6(ipv6_packet.get_destination()),
+        ttl: ipv6_packet.get_hop_limit(),
+        id: None,
+        protocol,
+        more_fragments,
+        payload: &payload[offset..total_len],
+    })
+}
+
+/// Decodes an Ethernet frame carrying TCP over IPv4 or IPv6.
+///
+/// Returns None for anything that is not a well-formed TCP segment: other
+/// protocols, bad checksums, truncated headers and non-first fragments.
+/// The TCP checksum of a first fragment can't be verified and is skipped.
+pub fn decode_tcp_segment(frame: &[u8]) -> Option<TcpSegment> {
+    let ethernet_frame = decode_ethernet(frame)?;
+    let datagram = decode_ip_datagram(&ethernet_frame)?;
+    if datagram.protocol != IpNextHeaderProtocols::Tcp {
+        return None;
+    }
+
+    let tcp_packet = TcpPacket::new(datagram.payload)?;
+    let tcp_header_len = usize::from(tcp_packet.get_data_offset()) * 4;
+    let segment_len = datagram.payload.len();
+    if tcp_header_len < TcpPacket::minimum_packet_size() || tcp_header_len > segment_len {
+        return None;
+    }
+
+    if !datagram.more_fragments {
+        let checksum = match (datagram.src_ip, datagram.dst_ip) {
+            (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+                tcp::ipv4_checksum(&tcp_packet, &src_ip, &dst_ip)
+            }
+            (IpAddr::V6(src_ip), IpAddr::V6(dst_ip)) => {
+                tcp::ipv6_checksum(&tcp_packet, &src_ip, &dst_ip)
+            }
+            _ => return None,
+        };
+        if checksum != tcp_packet.get_checksum() {
+            return None;
+        }
+    }
+
+    let (options, option_kinds) =
+        decode_options(&datagram.payload[TcpPacket::minimum_packet_size()..tcp_header_len]);
+    Some(TcpSegment {
+        src_mac: ethernet_frame.source,
+        dst_mac: ethernet_frame.destination,
+        vlan_tags: ethernet_frame.vlan_tags,
+        src_ip: datagram.src_ip,
+        dst_ip: datagram.dst_ip,
+        ttl: datagram.ttl,
+        ip_id: datagram.id,
+        src_port: tcp_packet.get_source(),
+        dst_port: tcp_packet.get_destination(),
+        sequence: tcp_packet.get_sequence(),
+        acknowledgement: tcp_packet.get_acknowledgement(),
+        flags: tcp_packet.get_flags(),
+        window: tcp_packet.get_window(),
+        options,
+        option_kinds,
+        payload_len: segment_len - tcp_header_len,
+    })
+}
+
+/// Picks the options a SYN/ACK may answer from the options of a segment
+/// and notes the order they came in, stopping at the end-of-options option
+/// or a malformed one
+fn decode_options(mut options: &[u8]) -> (SynOptions, OptionKinds) {
+    let mut syn_options = SynOptions::default();
+    let mut option_kinds = OptionKinds::default();
+    while let Some(&kind) = options.first() {
+        match kind {
+            TCP_OPTION_END => break,
+            TCP_OPTION_NOP => {
+                // Kinds past the ones kept do not tell stacks apart
+                let _ = option_kinds.push(kind);
+                options = &options[1..];
+                continue;
+            }
+            _ => {}
+        }
+        let Some(&len) = options.get(1) else {
+            break;
+        };
+        let len = usize::from(len);
+        if len < 2 || len > options.len() {
+            break;
+        }
+        let _ = option_kinds.push(kind);
+        match (kind, len) {
+            (TCP_OPTION_MSS, 4) => {
+                syn_options.mss = Some(u16::from_be_bytes([options[2], options[3]]))
+            }
+            (TCP_OPTION_WINDOW_SCALE, 3) => syn_options.window_scale = true,
+            (TCP_OPTION_SACK_PERMITTED, 2) => syn_options.sack_permitted = true,
+            (TCP_OPTION_TIMESTAMP, 10) => {
+                syn_options.timestamp = Some(u32::from_be_bytes([
+                    options[2], options[3], options[4], options[5],
+                ]));
+            }
+            _ => {}
+        }
+        options = &options[len..];
+    }
+    (syn_options, option_kinds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet_packet::ethernet::MutableEthernetPacket;
+    use pnet_packet::ipv4::MutableIpv4Packet;
+    use pnet_packet::ipv6::MutableIpv6Packet;
+    use pnet_packet::tcp::{MutableTcpPacket, TcpFlags};
+    use pnet_packet::MutablePacket;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const SRC_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 100);
+    const DST_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+    const SRC_IP6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0x100);
+    const DST_IP6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0x2);
+
+    /// Knobs for the frames built by build_frame
+    struct FrameSpec<'a> {
+        vlan_ids: &'a [u16],
+        ip_options: &'a [u8],
+        ip_flags: u8,
+        fragment_offset: u16,
+        tcp_flags: u8,
+        tcp_options: &'a [u8],
+        payload: &'a [u8],
+    }
+
+    impl Default for FrameSpec<'_> {
+        fn default() -> Self {
+            FrameSpec {
+                vlan_ids: &[],
+                ip_options: &[],
+                ip_flags: Ipv4Flags::DontFragment,
+                fragment_offset: 0,
+                tcp_flags: TcpFlags::SYN,
+                tcp_options: &[],
+                payload: &[],
+            }
+        }
+    }
+
+    fn build_frame(spec: &FrameSpec) -> Vec<u8> {
+        let tags_len = spec.vlan_ids.len() * 4;
+        let ip_header_len = 20 + spec.ip_options.len();
+        let tcp_header_len = 20 + spec.tcp_options.len();
+        let ip_total_len = ip_header_len + tcp_header_len + spec.payload.len();
+        let mut frame = vec![0u8; 14 + tags_len + ip_total_len];
+
+        {
+            let mut ethernet_packet = MutableEthernetPacket::new(&mut frame).unwrap();
+            ethernet_packet.set_source(MacAddr::new(1, 2, 3, 4, 5, 6));
+            ethernet_packet.set_destination(MacAddr::new(6, 5, 4, 3, 2, 1));
+        }
+
+        // Outer tags are S-tags in QinQ, the innermost one is a C-tag
+        let mut offset = 12;
+        for (i, vid) in spec.vlan_ids.iter().enumerate() {
+            let tpid = if i + 1 < spec.vlan_ids.len() {
+                EtherTypes::PBridge
+            } else {
+                EtherTypes::Vlan
+            };
+            frame[offset..offset + 2].copy_from_slice(&tpid.0.to_be_bytes());
+            frame[offset + 2..offset + 4].copy_from_slice(&vid.to_be_bytes());
+            offset += 4;
+        }
+        frame[offset..offset + 2].copy_from_slice(&EtherTypes::Ipv4.0.to_be_bytes());
+        offset += 2;
+
+        frame[offset + 20..offset + ip_header_len].copy_from_slice(spec.ip_options);
+        {
+            let mut ipv4_packet =
+                MutableIpv4Packet::new(&mut frame[offset..offset + ip_header_len]).unwrap();
+            ipv4_packet.set_version(4);
+            ipv4_packet.set_header_length((ip_header_len / 4) as u8);
+            ipv4_packet.set_total_length(ip_total_len as u16);
+            ipv4_packet.set_ttl(64);
+            ipv4_packet.set_flags(spec.ip_flags);
+            ipv4_packet.set_fragment_offset(spec.fragment_offset);
+            ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+            ipv4_packet.set_source(SRC_IP);
+            ipv4_packet.set_destination(DST_IP);
+            let checksum = ipv4::checksum(&ipv4_packet.to_immutable());
+            ipv4_packet.set_checksum(checksum);
+        }
+        offset += ip_header_len;
+
+        {
+            let mut tcp_packet = MutableTcpPacket::new(&mut frame[offset..]).unwrap();
+            tcp_packet.set_source(40000);
+            tcp_packet.set_destination(22);
+            tcp_packet.set_sequence(41);
+            tcp_packet.set_data_offset((tcp_header_len / 4) as u8);
+            tcp_packet.set_flags(spec.tcp_flags);
+            tcp_packet.set_window(1024);
+            tcp_packet.packet_mut()[20..tcp_header_len].copy_from_slice(spec.tcp_options);
+            tcp_packet.set_payload(spec.payload);
+            let checksum = tcp::ipv4_checksum(&tcp_packet.to_immutable(), &SRC_IP, &DST_IP);
+            tcp_packet.set_checksum(checksum);
+        }
+
+        frame
+    }
+
+    #[test]
+    fn test_decode_tcp_segment_plain() {
+        let frame = build_frame(&FrameSpec::default());
+
+        let segment = decode_tcp_segment(&frame).unwrap();
+
+        assert_eq!(segment.src_mac, MacAddr::new(1, 2, 3, 4, 5, 6));
+        assert_eq!(segment.src_ip, IpAddr::V4(SRC_IP));
+        assert_eq!(segment.dst_ip, IpAddr::V4(DST_IP));
+        assert_eq!(segment.src_port, 40000);
+        assert_eq!(segment.dst_port, 22);
+        assert_eq!(segment.sequence, 41);
+        assert_eq!(segment.flags, TcpFlags::SYN);
+        assert_eq!(segment.ip_id, Some(0));
+        assert!(segment.vlan_tags.is_empty());
+        assert_eq!(segment.vlan_tags.to_string(), "untagged");
+    }
+
+    #[test]
+    fn test_decode_tcp_segment_syn_options() {
+        assert_eq!(
+            decode_tcp_segment(&build_frame(&FrameSpec::default()))
+                .unwrap()
+                .options,
+            SynOptions::default()
+        );
+
+        // The options nmap sends in its first OS detection probe
+        let frame = build_frame(&FrameSpec {
+            tcp_options: &[
+                3, 3, 10, 1, 2, 4, 5, 0xb4, 8, 10, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0, 4, 2,
+            ],
+            ..FrameSpec::default()
+        });
+        let segment = decode_tcp_segment(&frame).unwrap();
+        assert_eq!(
+            segment.options,
+            SynOptions {
+                mss: Some(1460),
+                sack_permitted: true,
+                window_scale: true,
+                timestamp: Some(0xffff_ffff),
+            }
+        );
+        assert_eq!(segment.option_kinds.as_slice(), [3, 1, 2, 8, 4]);
+
+        // Nothing after a malformed option is trusted
+        let frame = build_frame(&FrameSpec {
+            tcp_options: &[4, 2, 8, 1, 3, 3, 10, 1],
+            ..FrameSpec::default()
+        });
+        let segment = decode_tcp_segment(&frame).unwrap();
+        assert!(segment.options.sack_permitted);
+        assert!(!segment.options.window_scale);
+        assert_eq!(segment.option_kinds.as_slice(), [4]);
+    }
+
+    #[test]
+    fn test_decode_tcp_segment_with_ip_options() {
+        // Router alert option padded with end-of-options
+        let frame = build_frame(&FrameSpec {
+            ip_options: &[0x94, 0x04, 0x00, 0x00, 0x01, 0x01, 0x01, 0x00],
+            ..Default::default()
+        });
+
+        let segment = decode_tcp_segment(&frame).unwrap();
+
+        assert_eq!(segment.src_port, 40000);
+        assert_eq!(segment.dst_port, 22);
+        assert_eq!(segment.sequence, 41);
+    }
+
+    #[test]
+    fn test_decode_tcp_segment_with_vlan_tag() {
+        let frame = build_frame(&FrameSpec {
+            vlan_ids: &[100],
+            ..Default::default()
+        });
+
+        let segment = decode_tcp_segment(&frame).unwrap();
+
+        assert_eq!(
+            segment.vlan_tags.as_slice(),
+            &[VlanTag {
+                tpid: EtherTypes::Vlan.0,
+                vid: 100
+            }]
+        );
+        assert_eq!(segment.dst_port, 22);
+    }
+
+    #[test]
+    fn test_decode_tcp_segment_with_qinq_tags() {
+        let frame = build_frame(&FrameSpec {
+            vlan_ids: &[10, 200],
+            ..Default::default()
+        });
+
+        let segment = decode_tcp_segment(&frame).unwrap();
+
+        assert_eq!(
+            segment
+                .vlan_tags
+                .as_slice()
+                .iter()
+                .map(|tag| tag.vid)
+                .collect::<Vec<_>>(),
+            vec![10, 200]
+        );
+        assert_eq!(segment.vlan_tags.to_string(), "vlan 10.200");
+        assert_eq!(segment.dst_ip, IpAddr::V4(DST_IP));
+    }
+
+    #[test]
+    fn test_decode_ethernet_too_many_tags() {
+        let frame = build_frame(&FrameSpec {
+            vlan_ids: &[10, 20, 30],
+            ..Default::default()
+        });
+
+        assert!(decode_ethernet(&frame).is_none());
+    }
+
+    #[test]
+    fn test_tag_frame_round_trip() {
+        let untagged = build_frame(&FrameSpec::default());
+        let tagged = build_frame(&FrameSpec {
+            vlan_ids: &[10, 200],
+            ..Default::default()
+        });
+        let vlan_tags = decode_ethernet(&tagged).unwrap().vlan_tags;
+
+        assert_eq!(tag_frame(&untagged, vlan_tags), tagged);
+        assert_eq!(tag_frame(&untagged, VlanTags::default()), untagged);
+    }
+
+    #[test]
+    fn test_decode_tcp_segment_first_fragment() {
+        let frame = build_frame(&FrameSpec {
+            ip_flags: Ipv4Flags::MoreFragments,
+            ..Default::default()
+        });
+
+        assert!(decode_tcp_segment(&frame).is_some());
+    }
+
+    #[test]
+    fn test_decode_tcp_segment_ignores_non_first_fragment() {
+        let frame = build_frame(&FrameSpec {
+            fragment_offset: 185,
+            ..Default::default()
+        });
+
+        assert!(decode_tcp_segment(&frame).is_none());
+    }
+
+    #[test]
+    fn test_decode_tcp_segment_bad_ip_checksum() {
+        let mut frame = build_frame(&FrameSpec::default());
+        frame[14 + 10] ^= 0xff;
+
+        assert!(decode_tcp_segment(&frame).is_none());
+    }
+
+    #[test]
+    fn test_decode_tcp_segment_bad_tcp_checksum() {
+        let mut frame = build_frame(&FrameSpec::default());
+        frame[34 + 16] ^= 0xff;
+
+        assert!(decode_tcp_segment(&frame).is_none());
+    }
+
+    #[test]
+    fn test_decode_tcp_segment_ignores_ethernet_padding() {
+        let mut frame = build_frame(&FrameSpec::default());
+        frame.extend_from_slice(&[0u8; 6]);
+
+        let segment = decode_tcp_segment(&frame).unwrap();
+
+        assert_eq!(segment.payload_len, 0);
+    }
+
+    #[test]
+    fn test_decode_tcp_segment_payload_len() {
+        let frame = build_frame(&FrameSpec {
+            tcp_flags: TcpFlags::ACK,
+            payload: b"x",
+            ..Default::default()
+        });
+
+        let segment = decode_tcp_segment(&frame).unwrap();
+
+        assert_eq!(segment.payload_len, 1);
+    }
+
+    #[test]
+    fn test_decode_tcp_segment_truncated() {
+        let frame = build_frame(&FrameSpec::default());
+
+        assert!(decode_tcp_segment(&frame[..40]).is_none());
+    }
+
+    #[test]
+    fn test_decode_tcp_segment_bad_header_length() {
+        let mut frame = build_frame(&FrameSpec::default());
+        // IHL of 4 words is shorter than the minimum header
+        frame[14] = 0x44;
+
+        assert!(decode_tcp_segment(&frame).is_none());
+    }
+
+    /// Builds a TCP SYN over IPv6 behind the given extension headers
+    fn build_ipv6_frame(extension_headers: &[(IpNextHeaderProtocol, &[u8])]) -> Vec<u8> {
+        let extensions_len: usize = extension_headers.iter().map(|(_, h)| h.len()).sum();
+        let payload_len = extensions_len + 20;
+        let mut frame = vec![0u8; 14 + 40 + payload_len];
+        frame[12..14].copy_from_slice(&EtherTypes::Ipv6.0.to_be_bytes());
+
+        let first_header = extension_headers
+            .first()
+            .map(|(protocol, _)| *protocol)
+            .unwrap_or(IpNextHeaderProtocols::Tcp);
+        {
+            let mut ipv6_packet = MutableIpv6Packet::new(&mut frame[14..54]).unwrap();
+            ipv6_packet.set_version(6);
+            ipv6_packet.set_payload_length(payload_len as u16);
+            ipv6_packet.set_next_header(first_header);
+            ipv6_packet.set_hop_limit(64);
+            ipv6_packet.set_source(SRC_IP6);
+            ipv6_packet.set_destination(DST_IP6);
+        }
+
+        let mut offset = 54;
+        for (i, (_, header)) in extension_headers.iter().enumerate() {
+            let next = extension_headers
+                .get(i + 1)
+                .map(|(protocol, _)| *protocol)
+                .unwrap_or(IpNextHeaderProtocols::Tcp);
+            frame[offset..offset + header.len()].copy_from_slice(header);
+            frame[offset] = next.0;
+            offset += header.len();
+        }
+
+        let mut tcp_packet = MutableTcpPacket::new(&mut frame[offset..]).unwrap();
+        tcp_packet.set_source(40000);
+        tcp_packet.set_destination(22);
+        tcp_packet.set_sequence(41);
+        tcp_packet.set_data_offset(5);
+        tcp_packet.set_flags(TcpFlags::SYN);
+        let checksum = tcp::ipv6_checksum(&tcp_packet.to_immutable(), &SRC_IP6, &DST_IP6);
+        tcp_packet.set_checksum(checksum);
+
+        frame
+    }
+
+    #[test]
+    fn test_decode_tcp_segment_ipv6() {
+        let frame = build_ipv6_frame(&[]);
+
+        let segment = decode_tcp_segment(&frame).unwrap();
+
+        assert_eq!(segment.src_ip, IpAddr::V6(SRC_IP6));
+        assert_eq!(segment.dst_ip, IpAddr::V6(DST_IP6));
+        assert_eq!(segment.ttl, 64);
+        assert_eq!(segment.dst_port, 22);
+        assert_eq!(segment.ip_id, None);
+    }
+
+    #[test]
+    fn test_decode_tcp_segment_ipv6_extension_headers() {
+        let hop_by_hop = [0u8, 0, 1, 4, 0, 0, 0, 0];
+        let destination_options = [0u8, 1, 1, 12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let frame = build_ipv6_frame(&[
+            (IpNextHeaderProtocols::Hopopt, &hop_by_hop),
+            (IpNextHeaderProtocols::Ipv6Opts, &destination_options),
+        ]);
+
+        let segment = decode_tcp_segment(&frame).unwrap();
+
+        assert_eq!(segment.src_port, 40000);
+        assert_eq!(segment.sequence, 41);
+    }
+
+    #[test]
+    fn test_decode_tcp_segment_ipv6_first_fragment() {
+        // Offset 0 with the more fragments flag set
+        let fragment = [0u8, 0, 0, 1, 0, 0, 0, 1];
+        let frame = build_ipv6_frame(&[(IpNextHeaderProtocols::Ipv6Frag, &fragment)]);
+
+        assert!(decode_tcp_segment(&frame).is_some());
+    }
+
+    #[test]
+    fn test_decode_tcp_segment_ipv6_ignores_non_first_fragment() {
+        let fragment = [0u8, 0, 0x05, 0xc8, 0, 0, 0, 1];
+        let frame = build_ipv6_frame(&[(IpNextHeaderProtocols::Ipv6Frag, &fragment)]);
+
+        assert!(decode_tcp_segment(&frame).is_none());
+    }
+
+    #[test]
+    fn test_decode_tcp_segment_ipv6_bad_checksum() {
+        let mut frame = build_ipv6_frame(&[]);
+        frame[54 + 16] ^= 0xff;
+
+        assert!(decode_tcp_segment(&frame).is_none());
+    }
+
+    #[test]
+    fn test_decode_tcp_segment_ipv6_truncated_extension() {
+        // Claims 16 bytes of options but only 8 are present
+        let hop_by_hop = [0u8, 1, 1, 4, 0, 0, 0, 0];
+        let frame = build_ipv6_frame(&[(IpNextHeaderProtocols::Hopopt, &hop_by_hop)]);
+
+        assert!(decode_tcp_segment(&frame[..14 + 40 + 8]).is_none());
+    }
+}
@@ -0,0 +1,91 @@
+use super::{Frame, PacketIo, READ_TIMEOUT};
+use std::io;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+/// An in-memory Ethernet segment. Every injected frame reaches each attached
+/// port and frames sent from any port are collected in one receiver.
+#[derive(Clone)]
+pub struct MemoryNetwork {
+    ports: Arc<Mutex<Vec<mpsc::Sender<Vec<u8>>>>>,
+    sent: mpsc::Sender<Vec<u8>>,
+}
+
+/// One attachment to a MemoryNetwork, its capture ends when the network is dropped
+pub struct MemoryIo {
+    incoming: mpsc::Receiver<Vec<u8>>,
+    sent: mpsc::Sender<Vec<u8>>,
+    frame: Vec<u8>,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> (Self, mpsc::Receiver<Vec<u8>>) {
+        let (sent, sent_frames) = mpsc::channel();
+        let network = MemoryNetwork {
+            ports: Arc::new(Mutex::new(Vec::new())),
+            sent,
+        };
+        (network, sent_frames)
+    }
+
+    pub fn attach(&self) -> MemoryIo {
+        let (port, incoming) = mpsc::channel();
+        self.ports.lock().unwrap().push(port);
+        MemoryIo {
+            incoming,
+            sent: self.sent.clone(),
+            frame: Vec::new(),
+        }
+    }
+
+    pub fn inject(&self, frame: &[u8]) {
+        self.ports
+            .lock()
+            .unwrap()
+            .retain(|port| port.send(frame.to_vec()).is_ok());
+    }
+}
+
+impl PacketIo for MemoryIo {
+    fn recv(&mut self) -> io::Result<Option<Frame<'_>>> {
+        match self.incoming.recv_timeout(READ_TIMEOUT) {
+            Ok(frame) => {
+                self.frame = frame;
+                Ok(Some(Frame::now(&self.frame)))
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(io::ErrorKind::TimedOut.into()),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Ok(None),
+        }
+    }
+
+    fn send(&mut self, frame: Frame<'_>) -> io::Result<()> {
+        self.sent
+            .send(frame.data.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_injected_frames_reach_every_port() {
+        let (network, sent_frames) = MemoryNetwork::new();
+        let mut first = network.attach();
+        let mut second = network.attach();
+
+        network.inject(&[1, 2, 3]);
+        first.send(Frame::now(&[4, 5, 6])).unwrap();
+
+        assert_eq!(first.recv().unwrap().unwrap().data, &[1, 2, 3]);
+        assert_eq!(second.recv().unwrap().unwrap().data, &[1, 2, 3]);
+        assert_eq!(
+            sent_frames.recv_timeout(Duration::from_secs(1)).unwrap(),
+            vec![4, 5, 6]
+        );
+
+        drop(network);
+        assert!(first.recv().unwrap().is_none());
+    }
+}
@@ -0,0 +1,140 @@
+#[cfg(test)]
+mod memory;
+mod pcap_file_io;
+mod pcap_io;
+mod pnet_io;
+#[cfg(feature = "tpacket-v3")]
+mod tpacket;
+
+#[cfg(test)]
+pub use memory::MemoryNetwork;
+pub use pcap_file_io::PcapFileIo;
+pub use pcap_io::PcapIo;
+pub use pnet_io::PnetIo;
+#[cfg(feature = "tpacket-v3")]
+pub use tpacket::TpacketIo;
+
+use crate::config::Backend;
+use pnet_base::MacAddr;
+use std::io;
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Live captures give up waiting after this long so listeners can act on
+/// claim changes even when their filter lets nothing through
+pub const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Capture filter that passes nothing, for handles that only transmit
+pub const MATCH_NOTHING: &str = "len = 0";
+
+/// A raw Ethernet frame and when it was captured or sent, since the Unix epoch
+#[derive(Clone, Copy, Debug)]
+pub struct Frame<'a> {
+    pub timestamp: Duration,
+    pub data: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    /// A frame stamped with the current wall clock time
+    pub fn now(data: &'a [u8]) -> Self {
+        Frame {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            data,
+        }
+    }
+}
+
+/// Receives and sends Ethernet frames. Implemented for live capture with
+/// pnet and libpcap, for capture files and for in-memory test networks.
+pub trait PacketIo: Send {
+    /// Blocks until the next frame arrives, None once the source is exhausted.
+    /// Live captures fail with ErrorKind::TimedOut after READ_TIMEOUT.
+    fn recv(&mut self) -> io::Result<Option<Frame<'_>>>;
+
+    fn send(&mut self, frame: Frame<'_>) -> io::Result<()>;
+
+    /// Sends frames in order, backends that batch system calls override this
+    fn send_batch(&mut self, frames: &[Frame<'_>]) -> io::Result<()> {
+        frames.iter().try_for_each(|frame| self.send(*frame))
+    }
+
+    /// Installs a kernel filter in pcap syntax. Backends without kernel
+    /// filtering keep delivering every frame, listeners check each one anyway.
+    fn set_filter(&mut self, _filter: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Frames the kernel dropped since the handle was opened because they
+    /// were not read in time, None for backends that do not count them
+    fn dropped(&mut self) -> Option<u64> {
+        None
+    }
+}
+
+/// Extends a capture filter to frames with up to vlan_depth 802.1Q tags.
+/// Every vlan keyword moves the offsets of the rest of the filter past one
+/// more tag, so each alternative matches one tag deeper than the previous.
+pub fn with_vlans(filter: &str, vlan_depth: usize) -> String {
+    let mut extended = format!("({})", filter);
+    for _ in 0..vlan_depth {
+        extended.push_str(&format!(" or (vlan and ({}))", filter));
+    }
+    extended
+}
+
+/// Opens live capture and transmit on an interface with the configured backend
+pub fn open(backend: Backend, interface_name: &str, promiscuous: bool) -> Box<dyn PacketIo> {
+    try_open(backend, interface_name, promiscuous).unwrap_or_else(|e| {
+        eprintln!("Could not open interface {}: {}", interface_name, e);
+        process::exit(1);
+    })
+}
+
+pub fn try_open(
+    backend: Backend,
+    interface_name: &str,
+    promiscuous: bool,
+) -> io::Result<Box<dyn PacketIo>> {
+    match backend {
+        Backend::Pcap => {
+            PcapIo::open(interface_name, promiscuous).map(|io| Box::new(io) as Box<dyn PacketIo>)
+        }
+        Backend::Pnet => PnetIo::open(&get_interface(interface_name), promiscuous)
+            .map(|io| Box::new(io) as Box<dyn PacketIo>),
+        #[cfg(feature = "tpacket-v3")]
+        Backend::Tpacket => {
+            TpacketIo::open(interface_name, promiscuous).map(|io| Box::new(io) as Box<dyn PacketIo>)
+        }
+    }
+}
+
+/// The hardware address replies are sent from
+pub fn interface_mac(interface_name: &str) -> MacAddr {
+    get_interface(interface_name).mac.unwrap_or_default()
+}
+
+fn get_interface(interface_name: &str) -> pnet_datalink::NetworkInterface {
+    pnet_datalink::interfaces()
+        .into_iter()
+        .find(|iface| iface.name == interface_name)
+        .unwrap_or_else(|| {
+            eprintln!("No such interface: {}", interface_name);
+            process::exit(1);
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_vlans() {
+        assert_eq!(with_vlans("arp", 0), "(arp)");
+        assert_eq!(
+            with_vlans("arp", 2),
+            "(arp) or (vlan and (arp)) or (vlan and (arp))"
+        );
+    }
+}
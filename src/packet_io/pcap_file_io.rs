@@ -0,0 +1,100 @@
+use super::{Frame, PacketIo};
+use pcap_file::pcap::{PcapPacket, PcapReader, PcapWriter};
+use pcap_file::DataLink;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+
+/// Reads frames from a capture file and writes sent frames to another,
+/// keeping the timestamps they were given. Needs neither libpcap nor root.
+pub struct PcapFileIo {
+    reader: PcapReader<BufReader<File>>,
+    writer: Option<PcapWriter<BufWriter<File>>>,
+    frame: Vec<u8>,
+}
+
+impl PcapFileIo {
+    /// Sent frames are discarded when there is no output file
+    pub fn open(input: &Path, output: Option<&Path>) -> io::Result<Self> {
+        let reader =
+            PcapReader::new(BufReader::new(File::open(input)?)).map_err(io::Error::other)?;
+        if reader.header().datalink != DataLink::ETHERNET {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not an Ethernet capture", input.display()),
+            ));
+        }
+        let writer = match output {
+            Some(path) => Some(
+                PcapWriter::new(BufWriter::new(File::create(path)?)).map_err(io::Error::other)?,
+            ),
+            None => None,
+        };
+
+        Ok(PcapFileIo {
+            reader,
+            writer,
+            frame: Vec::new(),
+        })
+    }
+}
+
+impl PacketIo for PcapFileIo {
+    fn recv(&mut self) -> io::Result<Option<Frame<'_>>> {
+        let packet = match self.reader.next_packet() {
+            Some(packet) => packet.map_err(io::Error::other)?,
+            None => return Ok(None),
+        };
+        let timestamp = packet.timestamp;
+        self.frame = packet.data.into_owned();
+
+        Ok(Some(Frame {
+            timestamp,
+            data: &self.frame,
+        }))
+    }
+
+    fn send(&mut self, frame: Frame<'_>) -> io::Result<()> {
+        if let Some(writer) = self.writer.as_mut() {
+            let packet = PcapPacket::new(frame.timestamp, frame.data.len() as u32, frame.data);
+            writer.write_packet(&packet).map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::time::Duration;
+
+    #[test]
+    fn test_sent_frames_read_back_with_timestamps() {
+        let dir = env::temp_dir();
+        let input = dir.join(format!("ants-test-{}-empty.pcap", process::id()));
+        let output = dir.join(format!("ants-test-{}-out.pcap", process::id()));
+        PcapWriter::new(File::create(&input).unwrap()).unwrap();
+
+        {
+            let mut packet_io = PcapFileIo::open(&input, Some(&output)).unwrap();
+            assert!(packet_io.recv().unwrap().is_none());
+            packet_io
+                .send(Frame {
+                    timestamp: Duration::from_millis(1500),
+                    data: &[0xAA; 60],
+                })
+                .unwrap();
+        }
+
+        let mut packet_io = PcapFileIo::open(&output, None).unwrap();
+        let frame = packet_io.recv().unwrap().unwrap();
+        assert_eq!(frame.timestamp, Duration::from_millis(1500));
+        assert_eq!(frame.data, &[0xAA; 60]);
+        assert!(packet_io.recv().unwrap().is_none());
+
+        std::fs::remove_file(input).unwrap();
+        std::fs::remove_file(output).unwrap();
+    }
+}
@@ -0,0 +1,55 @@
+use super::{Frame, PacketIo, READ_TIMEOUT};
+use std::io;
+use std::time::Duration;
+
+/// Live capture and transmit through libpcap
+pub struct PcapIo {
+    capture: pcap::Capture<pcap::Active>,
+}
+
+impl PcapIo {
+    pub fn open(interface_name: &str, promiscuous: bool) -> io::Result<Self> {
+        let capture = pcap::Capture::from_device(interface_name)
+            .and_then(|capture| {
+                capture
+                    .immediate_mode(true)
+                    .promisc(promiscuous)
+                    .timeout(READ_TIMEOUT.as_millis() as i32)
+                    .open()
+            })
+            .map_err(io::Error::other)?;
+        Ok(PcapIo { capture })
+    }
+}
+
+impl PacketIo for PcapIo {
+    fn recv(&mut self) -> io::Result<Option<Frame<'_>>> {
+        match self.capture.next_packet() {
+            Ok(packet) => Ok(Some(Frame {
+                timestamp: Duration::new(
+                    packet.header.ts.tv_sec as u64,
+                    packet.header.ts.tv_usec as u32 * 1000,
+                ),
+                data: packet.data,
+            })),
+            Err(pcap::Error::NoMorePackets) => Ok(None),
+            Err(pcap::Error::TimeoutExpired) => Err(io::ErrorKind::TimedOut.into()),
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+
+    fn send(&mut self, frame: Frame<'_>) -> io::Result<()> {
+        self.capture
+            .sendpacket(frame.data)
+            .map_err(io::Error::other)
+    }
+
+    fn set_filter(&mut self, filter: &str) -> io::Result<()> {
+        self.capture.filter(filter, true).map_err(io::Error::other)
+    }
+
+    fn dropped(&mut self) -> Option<u64> {
+        let stats = self.capture.stats().ok()?;
+        Some(u64::from(stats.dropped) + u64::from(stats.if_dropped))
+    }
+}
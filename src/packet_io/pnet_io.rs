@@ -0,0 +1,102 @@
+use super::{Frame, PacketIo, READ_TIMEOUT};
+use pnet_datalink::Channel::Ethernet;
+use pnet_datalink::{DataLinkReceiver, DataLinkSender, NetworkInterface};
+use std::io;
+
+/// Live capture and transmit through a pnet datalink channel
+pub struct PnetIo {
+    tx: Box<dyn DataLinkSender>,
+    rx: Box<dyn DataLinkReceiver>,
+}
+
+impl PnetIo {
+    pub fn open(interface: &NetworkInterface, promiscuous: bool) -> io::Result<Self> {
+        // pnet reads frames without the aux data a stripped tag is kept in,
+        // every VLAN would be taken for the untagged segment
+        #[cfg(target_os = "linux")]
+        if strips_vlan_tags(&interface.name)? {
+            return Err(io::Error::other(
+                "the NIC strips VLAN tags and pnet cannot restore them, \
+                 turn that off with ethtool -K <interface_name> rxvlan off \
+                 or use another backend",
+            ));
+        }
+        let config = pnet_datalink::Config {
+            read_timeout: Some(READ_TIMEOUT),
+            promiscuous,
+            ..Default::default()
+        };
+        match pnet_datalink::channel(interface, config)? {
+            Ethernet(tx, rx) => Ok(PnetIo { tx, rx }),
+            _ => Err(io::Error::other("Unhandled channel type")),
+        }
+    }
+}
+
+/// True if the interface has receive VLAN offload switched on
+#[cfg(target_os = "linux")]
+fn strips_vlan_tags(interface_name: &str) -> io::Result<bool> {
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    const ETHTOOL_GFLAGS: u32 = 0x25;
+    const ETH_FLAG_RXVLAN: u32 = 1 << 8;
+
+    #[repr(C)]
+    struct EthtoolValue {
+        cmd: u32,
+        data: u32,
+    }
+
+    let name = interface_name.as_bytes();
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    if name.len() >= request.ifr_name.len() {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+    for (to, from) in request.ifr_name.iter_mut().zip(name) {
+        *to = *from as libc::c_char;
+    }
+    let mut flags = EthtoolValue {
+        cmd: ETHTOOL_GFLAGS,
+        data: 0,
+    };
+    request.ifr_ifru.ifru_data = &mut flags as *mut EthtoolValue as *mut libc::c_char;
+
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCETHTOOL, &mut request) } < 0 {
+        let e = io::Error::last_os_error();
+        // Interfaces without ethtool support, such as loopback and veth, keep their tags
+        return match e.raw_os_error() {
+            Some(libc::EOPNOTSUPP) => Ok(false),
+            _ => Err(e),
+        };
+    }
+    Ok(flags.data & ETH_FLAG_RXVLAN != 0)
+}
+
+impl PacketIo for PnetIo {
+    fn recv(&mut self) -> io::Result<Option<Frame<'_>>> {
+        self.rx.next().map(|data| Some(Frame::now(data)))
+    }
+
+    fn send(&mut self, frame: Frame<'_>) -> io::Result<()> {
+        self.tx
+            .send_to(frame.data, None)
+            .unwrap_or_else(|| Err(io::Error::other("Channel closed")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_strips_vlan_tags() {
+        assert!(!strips_vlan_tags("lo").unwrap());
+        assert!(strips_vlan_tags("nosuchif0").is_err());
+    }
+}
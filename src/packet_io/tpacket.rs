@@ -0,0 +1,341 @@
+use super::{Frame, PacketIo, READ_TIMEOUT};
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{fence, Ordering};
+use std::time::Duration;
+
+/// Size of one ring block, the kernel hands frames to userspace a block at a time
+const BLOCK_SIZE: usize = 1 << 20;
+const BLOCK_COUNT: usize = 32;
+/// Only a hint for TPACKET_V3, frames are packed back to back within a block
+const FRAME_SIZE: usize = 2048;
+/// Milliseconds before a partly filled block is handed over anyway, bounds reply latency
+const BLOCK_TIMEOUT_MS: u32 = 10;
+/// Largest batch passed to one sendmmsg call
+const MAX_SEND_BATCH: usize = 1024;
+
+/// Live capture from an mmap'd AF_PACKET TPACKET_V3 receive ring,
+/// replies are sent in batches with sendmmsg
+pub struct TpacketIo {
+    socket: OwnedFd,
+    ring: *mut u8,
+    current_block: usize,
+    /// Frames not yet returned from the block being read, None when
+    /// the block belongs to the kernel
+    block_frames: Option<u32>,
+    next_frame_offset: usize,
+    /// Frame with its VLAN tag put back, the kernel strips it into the frame header
+    tagged_frame: Vec<u8>,
+    /// Drops counted so far, the kernel resets its counter on every read
+    dropped: u64,
+}
+
+// The ring is only accessed through &mut self
+unsafe impl Send for TpacketIo {}
+
+impl TpacketIo {
+    pub fn open(interface_name: &str, promiscuous: bool) -> io::Result<Self> {
+        let name = CString::new(interface_name)?;
+        let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let protocol = (libc::ETH_P_ALL as u16).to_be();
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, protocol as libc::c_int) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let version = libc::tpacket_versions::TPACKET_V3 as libc::c_int;
+        set_option(&socket, libc::SOL_PACKET, libc::PACKET_VERSION, &version)?;
+        let request = libc::tpacket_req3 {
+            tp_block_size: BLOCK_SIZE as libc::c_uint,
+            tp_block_nr: BLOCK_COUNT as libc::c_uint,
+            tp_frame_size: FRAME_SIZE as libc::c_uint,
+            tp_frame_nr: (BLOCK_SIZE * BLOCK_COUNT / FRAME_SIZE) as libc::c_uint,
+            tp_retire_blk_tov: BLOCK_TIMEOUT_MS,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+        set_option(&socket, libc::SOL_PACKET, libc::PACKET_RX_RING, &request)?;
+
+        let ring = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                BLOCK_SIZE * BLOCK_COUNT,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                socket.as_raw_fd(),
+                0,
+            )
+        };
+        if ring == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // Unmapped by Drop from here on
+        let tpacket_io = TpacketIo {
+            socket,
+            ring: ring as *mut u8,
+            current_block: 0,
+            block_frames: None,
+            next_frame_offset: 0,
+            tagged_frame: Vec::new(),
+            dropped: 0,
+        };
+
+        let address = libc::sockaddr_ll {
+            sll_family: libc::AF_PACKET as libc::c_ushort,
+            sll_protocol: protocol,
+            sll_ifindex: ifindex as libc::c_int,
+            sll_hatype: 0,
+            sll_pkttype: 0,
+            sll_halen: 0,
+            sll_addr: [0; 8],
+        };
+        let bound = unsafe {
+            libc::bind(
+                tpacket_io.socket.as_raw_fd(),
+                &address as *const libc::sockaddr_ll as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if bound < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if promiscuous {
+            let membership = libc::packet_mreq {
+                mr_ifindex: ifindex as libc::c_int,
+                mr_type: libc::PACKET_MR_PROMISC as libc::c_ushort,
+                mr_alen: 0,
+                mr_address: [0; 8],
+            };
+            set_option(
+                &tpacket_io.socket,
+                libc::SOL_PACKET,
+                libc::PACKET_ADD_MEMBERSHIP,
+                &membership,
+            )?;
+        }
+
+        Ok(tpacket_io)
+    }
+
+    fn block(&self, index: usize) -> *mut libc::tpacket_block_desc {
+        unsafe { self.ring.add(index * BLOCK_SIZE) as *mut libc::tpacket_block_desc }
+    }
+
+    /// Waits until the kernel hands over the current block, returns its frame count.
+    /// Fails with TimedOut when nothing arrives within READ_TIMEOUT.
+    fn wait_for_block(&mut self) -> io::Result<u32> {
+        let block = self.block(self.current_block);
+        loop {
+            let status = unsafe { ptr::read_volatile(&(*block).hdr.bh1.block_status) };
+            if status & libc::TP_STATUS_USER != 0 {
+                fence(Ordering::Acquire);
+                let header = unsafe { &(*block).hdr.bh1 };
+                self.next_frame_offset = header.offset_to_first_pkt as usize;
+                return Ok(header.num_pkts);
+            }
+
+            let mut poll_fd = libc::pollfd {
+                fd: self.socket.as_raw_fd(),
+                events: libc::POLLIN | libc::POLLERR,
+                revents: 0,
+            };
+            match unsafe { libc::poll(&mut poll_fd, 1, READ_TIMEOUT.as_millis() as libc::c_int) } {
+                0 => return Err(io::ErrorKind::TimedOut.into()),
+                result if result < 0 => {
+                    let error = io::Error::last_os_error();
+                    if error.kind() != io::ErrorKind::Interrupted {
+                        return Err(error);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Gives the current block back to the kernel and moves on to the next one
+    fn release_block(&mut self) {
+        let block = self.block(self.current_block);
+        fence(Ordering::Release);
+        unsafe { ptr::write_volatile(&mut (*block).hdr.bh1.block_status, libc::TP_STATUS_KERNEL) };
+        self.current_block = (self.current_block + 1) % BLOCK_COUNT;
+        self.block_frames = None;
+    }
+}
+
+impl PacketIo for TpacketIo {
+    fn recv(&mut self) -> io::Result<Option<Frame<'_>>> {
+        loop {
+            match self.block_frames {
+                Some(0) => self.release_block(),
+                Some(remaining) => {
+                    self.block_frames = Some(remaining - 1);
+                    break;
+                }
+                None => self.block_frames = Some(self.wait_for_block()?),
+            }
+        }
+
+        // The frame stays valid until the block is released by a later
+        // call, which needs the borrow of self returned here to have ended
+        let block = self.block(self.current_block) as *const u8;
+        let header = unsafe { &*(block.add(self.next_frame_offset) as *const libc::tpacket3_hdr) };
+        let data = unsafe {
+            slice::from_raw_parts(
+                (header as *const libc::tpacket3_hdr as *const u8).add(header.tp_mac as usize),
+                header.tp_snaplen as usize,
+            )
+        };
+        self.next_frame_offset += header.tp_next_offset as usize;
+        let timestamp = Duration::new(header.tp_sec as u64, header.tp_nsec);
+
+        if header.tp_status & libc::TP_STATUS_VLAN_VALID == 0 || data.len() < 12 {
+            return Ok(Some(Frame { timestamp, data }));
+        }
+        let tpid = if header.tp_status & libc::TP_STATUS_VLAN_TPID_VALID != 0 {
+            header.hv1.tp_vlan_tpid
+        } else {
+            0x8100
+        };
+        self.tagged_frame.clear();
+        self.tagged_frame.extend_from_slice(&data[..12]);
+        self.tagged_frame.extend_from_slice(&tpid.to_be_bytes());
+        self.tagged_frame
+            .extend_from_slice(&(header.hv1.tp_vlan_tci as u16).to_be_bytes());
+        self.tagged_frame.extend_from_slice(&data[12..]);
+        Ok(Some(Frame {
+            timestamp,
+            data: &self.tagged_frame,
+        }))
+    }
+
+    fn send(&mut self, frame: Frame<'_>) -> io::Result<()> {
+        let sent = unsafe {
+            libc::send(
+                self.socket.as_raw_fd(),
+                frame.data.as_ptr() as *const libc::c_void,
+                frame.data.len(),
+                0,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn send_batch(&mut self, frames: &[Frame<'_>]) -> io::Result<()> {
+        for batch in frames.chunks(MAX_SEND_BATCH) {
+            let mut iovecs: Vec<libc::iovec> = batch
+                .iter()
+                .map(|frame| libc::iovec {
+                    iov_base: frame.data.as_ptr() as *mut libc::c_void,
+                    iov_len: frame.data.len(),
+                })
+                .collect();
+            let mut messages: Vec<libc::mmsghdr> = iovecs
+                .iter_mut()
+                .map(|iovec| {
+                    let mut message: libc::mmsghdr = unsafe { mem::zeroed() };
+                    message.msg_hdr.msg_iov = iovec;
+                    message.msg_hdr.msg_iovlen = 1;
+                    message
+                })
+                .collect();
+
+            let mut sent = 0;
+            while sent < messages.len() {
+                let result = unsafe {
+                    libc::sendmmsg(
+                        self.socket.as_raw_fd(),
+                        messages[sent..].as_mut_ptr(),
+                        (messages.len() - sent) as libc::c_uint,
+                        0,
+                    )
+                };
+                if result < 0 {
+                    let error = io::Error::last_os_error();
+                    if error.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(error);
+                }
+                sent += result as usize;
+            }
+        }
+        Ok(())
+    }
+
+    fn set_filter(&mut self, filter: &str) -> io::Result<()> {
+        // libpcap compiles the filter, the kernel runs it on the socket
+        let program = pcap::Capture::dead(pcap::Linktype::ETHERNET)
+            .and_then(|capture| capture.compile(filter, true))
+            .map_err(io::Error::other)?;
+        let instructions = program.get_instructions();
+        let socket_program = libc::sock_fprog {
+            len: instructions.len() as libc::c_ushort,
+            filter: instructions.as_ptr() as *mut libc::sock_filter,
+        };
+        set_option(
+            &self.socket,
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_FILTER,
+            &socket_program,
+        )
+    }
+
+    fn dropped(&mut self) -> Option<u64> {
+        let mut stats: libc::tpacket_stats_v3 = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::tpacket_stats_v3>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                self.socket.as_raw_fd(),
+                libc::SOL_PACKET,
+                libc::PACKET_STATISTICS,
+                &mut stats as *mut libc::tpacket_stats_v3 as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if result < 0 {
+            return None;
+        }
+        self.dropped += u64::from(stats.tp_drops);
+        Some(self.dropped)
+    }
+}
+
+impl Drop for TpacketIo {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ring as *mut libc::c_void, BLOCK_SIZE * BLOCK_COUNT) };
+    }
+}
+
+fn set_option<T>(
+    socket: &OwnedFd,
+    level: libc::c_int,
+    option: libc::c_int,
+    value: &T,
+) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            option,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,165 @@
+use crate::claims;
+use crate::config::{PortAction, PortRange, PortRule, PortsConfig};
+use std::net::IpAddr;
+use tracing::info;
+
+/// Decides per SYN what a claimed IP does with the port: the first matching
+/// rule wins, then the IP's open port profile, then the default action
+#[derive(Clone, Debug, Default)]
+pub struct PortPolicy {
+    ports_config: PortsConfig,
+    persist: bool,
+}
+
+impl PortPolicy {
+    /// In persist mode every tarpitted port holds its flows open
+    pub fn new(ports_config: &PortsConfig, persist: bool) -> Self {
+        PortPolicy {
+            ports_config: ports_config.clone(),
+            persist,
+        }
+    }
+
+    pub fn action(&self, decoy_ip: IpAddr, scanner_ip: IpAddr, port: u16) -> PortAction {
+        let action = self
+            .ports_config
+            .rules
+            .iter()
+            .find(|rule| rule_matches(rule, decoy_ip, scanner_ip, port))
+            .map(|rule| rule.action)
+            .or_else(|| {
+                let open_ports = self.profile(decoy_ip)?;
+                Some(if open_ports.iter().any(|range| range.contains(port)) {
+                    PortAction::Tarpit
+                } else {
+                    self.ports_config.default_action
+                })
+            })
+            .unwrap_or(self.ports_config.default_action);
+
+        if self.persist && action == PortAction::Tarpit {
+            PortAction::Persist
+        } else {
+            action
+        }
+    }
+
+    /// True if some SYN can be answered with a flow held open
+    pub fn holds_flows(&self) -> bool {
+        self.persist || self.ports_config.uses_persist()
+    }
+
+    /// The open ports of the decoy, None without any profiles
+    fn profile(&self, decoy_ip: IpAddr) -> Option<&[PortRange]> {
+        let profiles = &self.ports_config.profiles;
+        if profiles.is_empty() {
+            return None;
+        }
+        let index = claims::ip_hash(self.ports_config.seed, decoy_ip) % profiles.len() as u64;
+        Some(&profiles[index as usize])
+    }
+
+    pub fn log_rules(&self) {
+        info!(
+            "Ports: {} rules, {} profiles, default {:?}",
+            self.ports_config.rules.len(),
+            self.ports_config.profiles.len(),
+            self.ports_config.default_action
+        );
+    }
+}
+
+fn rule_matches(rule: &PortRule, decoy_ip: IpAddr, scanner_ip: IpAddr, port: u16) -> bool {
+    (rule.ports.is_empty() || rule.ports.iter().any(|range| range.contains(port)))
+        && (rule.decoys.is_empty() || rule.decoys.iter().any(|net| net.contains(&decoy_ip)))
+        && (rule.sources.is_empty() || rule.sources.iter().any(|net| net.contains(&scanner_ip)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    const DECOY_IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 0, 2));
+    const SCANNER_IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+
+    fn policy(ports: &str, persist: bool) -> PortPolicy {
+        let config = Config::parse(&format!("[ports]\n{}", ports)).unwrap();
+        PortPolicy::new(&config.ports, persist)
+    }
+
+    #[test]
+    fn test_default_tarpits_every_port() {
+        let policy = PortPolicy::default();
+
+        assert_eq!(policy.action(DECOY_IP, SCANNER_IP, 1), PortAction::Tarpit);
+        assert_eq!(
+            policy.action(DECOY_IP, SCANNER_IP, 65535),
+            PortAction::Tarpit
+        );
+        assert!(!policy.holds_flows());
+    }
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let policy = policy(
+            r#"
+            default_action = "reset"
+            [[ports.rules]]
+            ports = [22]
+            sources = ["10.0.0.0/8"]
+            action = "drop"
+            [[ports.rules]]
+            ports = ["20-30"]
+            decoys = ["192.168.0.0/24"]
+            action = "persist"
+            "#,
+            false,
+        );
+
+        assert_eq!(policy.action(DECOY_IP, SCANNER_IP, 22), PortAction::Drop);
+        let other_scanner = "172.16.0.1".parse().unwrap();
+        assert_eq!(
+            policy.action(DECOY_IP, other_scanner, 22),
+            PortAction::Persist
+        );
+        let other_decoy = "192.168.1.2".parse().unwrap();
+        assert_eq!(
+            policy.action(other_decoy, other_scanner, 22),
+            PortAction::Reset
+        );
+        assert!(policy.holds_flows());
+    }
+
+    #[test]
+    fn test_profiles_are_stable_per_ip() {
+        let policy = policy(
+            r#"
+            default_action = "reset"
+            profiles = [[22], [80]]
+            "#,
+            true,
+        );
+
+        let open_ports = |decoy_ip| {
+            [22, 80]
+                .into_iter()
+                .filter(|&port| policy.action(decoy_ip, SCANNER_IP, port) != PortAction::Reset)
+                .collect::<Vec<_>>()
+        };
+        let mut profiles_seen = Vec::new();
+        for host in 1..=20 {
+            let decoy_ip = IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 0, host));
+            let ports = open_ports(decoy_ip);
+            assert_eq!(ports.len(), 1);
+            assert_eq!(ports, open_ports(decoy_ip));
+            // Tarpitted ports hold their flows in persist mode
+            assert_eq!(
+                policy.action(decoy_ip, SCANNER_IP, ports[0]),
+                PortAction::Persist
+            );
+            profiles_seen.push(ports[0]);
+        }
+        assert!(profiles_seen.contains(&22) && profiles_seen.contains(&80));
+    }
+}
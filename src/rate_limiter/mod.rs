@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// Number of sources tracked at once, bounds the memory used by the limiter
+pub const DEFAULT_MAX_SOURCES: usize = 65536;
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token bucket per source address, one second of burst
+pub struct RateLimiter {
+    buckets: HashMap<IpAddr, Bucket>,
+    rate: f64,
+    max_sources: usize,
+}
+
+impl RateLimiter {
+    pub fn new(rate_per_source: u32, max_sources: usize) -> Self {
+        RateLimiter {
+            buckets: HashMap::new(),
+            rate: f64::from(rate_per_source),
+            max_sources,
+        }
+    }
+
+    /// Buckets of known sources keep their tokens, up to the new rate
+    pub fn set_rate(&mut self, rate_per_source: u32) {
+        self.rate = f64::from(rate_per_source);
+    }
+
+    /// Takes one token from the source's bucket, returns false if it is empty
+    pub fn allow(&mut self, source: IpAddr, now: Instant) -> bool {
+        if !self.buckets.contains_key(&source) && self.buckets.len() >= self.max_sources {
+            self.evict_idle(now);
+            if self.buckets.len() >= self.max_sources {
+                return false;
+            }
+        }
+
+        let rate = self.rate;
+        let bucket = self.buckets.entry(source).or_insert(Bucket {
+            tokens: rate,
+            last_refill: now,
+        });
+
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Forgets sources whose bucket would be full again, they behave like new ones
+    fn evict_idle(&mut self, now: Instant) {
+        let rate = self.rate;
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.tokens + elapsed * rate < rate
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    const SOURCE: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 100));
+
+    #[test]
+    fn test_allow_until_bucket_empty() {
+        let mut limiter = RateLimiter::new(2, 10);
+        let now = Instant::now();
+
+        assert!(limiter.allow(SOURCE, now));
+        assert!(limiter.allow(SOURCE, now));
+        assert!(!limiter.allow(SOURCE, now));
+    }
+
+    #[test]
+    fn test_bucket_refills_over_time() {
+        let mut limiter = RateLimiter::new(2, 10);
+        let now = Instant::now();
+
+        assert!(limiter.allow(SOURCE, now));
+        assert!(limiter.allow(SOURCE, now));
+        assert!(limiter.allow(SOURCE, now + Duration::from_millis(500)));
+        assert!(!limiter.allow(SOURCE, now + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_max_sources_evicts_idle_sources() {
+        let mut limiter = RateLimiter::new(1, 1);
+        let other = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 101));
+        let now = Instant::now();
+
+        assert!(limiter.allow(SOURCE, now));
+        assert!(!limiter.allow(other, now));
+        assert!(limiter.allow(other, now + Duration::from_secs(1)));
+    }
+}
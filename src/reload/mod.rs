@@ -0,0 +1,198 @@
+use crate::claims::ClaimPolicy;
+use crate::config::{
+    Config, DiscoveryConfig, IcmpConfig, PersonalityConfig, ScannersConfig, TcpConfig,
+};
+use crate::control::{PassiveMode, SetLogFilter};
+use crate::port_policy::PortPolicy;
+use signal_hook::consts::SIGHUP;
+use signal_hook::flag;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use tracing::{error, info, warn};
+
+/// Set by SIGHUP or the control socket, the tarpitter takes it and reads
+/// the configuration again
+#[derive(Clone, Debug, Default)]
+pub struct ReloadRequest(Arc<AtomicBool>);
+
+impl ReloadRequest {
+    pub fn on_sighup() -> io::Result<Self> {
+        let reload_request = ReloadRequest::default();
+        flag::register(SIGHUP, reload_request.0.clone())?;
+        Ok(reload_request)
+    }
+
+    pub fn request(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// True once per request
+    pub fn take(&self) -> bool {
+        self.0.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// Reads the configuration file again with the command line overrides
+/// applied, Err says why it is invalid
+pub type LoadConfig = Box<dyn Fn() -> Result<Config, String> + Send>;
+
+/// The settings that change without a restart. Each listener swaps in a
+/// new set whole between two frames, claims and flows are kept.
+#[derive(Clone, Debug)]
+pub struct Policies {
+    pub discovery: DiscoveryConfig,
+    pub claim_policy: ClaimPolicy,
+    pub tcp: TcpConfig,
+    pub icmp: IcmpConfig,
+    pub scanners: ScannersConfig,
+    pub personality: PersonalityConfig,
+    pub port_policy: PortPolicy,
+}
+
+impl Policies {
+    pub fn new(config: &Config) -> Self {
+        Policies {
+            discovery: config.discovery,
+            claim_policy: ClaimPolicy::new(&config.claims),
+            tcp: config.tcp,
+            icmp: config.icmp,
+            scanners: config.scanners,
+            personality: config.personality.clone(),
+            port_policy: PortPolicy::new(&config.ports, config.tcp.persist),
+        }
+    }
+}
+
+/// Keys that keep the value ANTS started with until it is restarted
+fn restart_only_changes(running: &Config, loaded: &Config) -> Vec<&'static str> {
+    [
+        ("interface", running.interface != loaded.interface),
+        ("backend", running.backend != loaded.backend),
+        ("mac", running.mac != loaded.mac),
+        ("events", running.events != loaded.events),
+        ("metrics", running.metrics != loaded.metrics),
+        ("shutdown", running.shutdown != loaded.shutdown),
+        ("state", running.state != loaded.state),
+        ("control", running.control != loaded.control),
+    ]
+    .into_iter()
+    .filter_map(|(key, changed)| changed.then_some(key))
+    .collect()
+}
+
+/// Applies a reloaded configuration to the running listeners
+pub struct Reloader {
+    load_config: LoadConfig,
+    running: Config,
+    /// One per thread that holds policies
+    listeners: Vec<mpsc::Sender<Arc<Policies>>>,
+    passive_mode: PassiveMode,
+    set_log_filter: SetLogFilter,
+}
+
+impl Reloader {
+    pub fn new(
+        load_config: LoadConfig,
+        running: Config,
+        listeners: Vec<mpsc::Sender<Arc<Policies>>>,
+        passive_mode: PassiveMode,
+        set_log_filter: SetLogFilter,
+    ) -> Self {
+        Reloader {
+            load_config,
+            running,
+            listeners,
+            passive_mode,
+            set_log_filter,
+        }
+    }
+
+    /// Reads the configuration again, an invalid one is logged and the
+    /// running one kept
+    pub fn reload(&mut self) {
+        match (self.load_config)() {
+            Ok(loaded) => self.apply(loaded),
+            Err(e) => error!("Not reloading, keeping the running configuration: {}", e),
+        }
+    }
+
+    fn apply(&mut self, loaded: Config) {
+        for key in restart_only_changes(&self.running, &loaded) {
+            warn!("Changes to {} take effect on restart", key);
+        }
+        // Left alone unless changed in the file, they may have been switched on the control socket
+        if loaded.passive != self.running.passive {
+            self.passive_mode.set(loaded.passive);
+        }
+        if loaded.log_level != self.running.log_level {
+            if let Err(e) = (self.set_log_filter)(&loaded.log_level) {
+                error!("Invalid log level '{}': {}", loaded.log_level, e);
+            }
+        }
+
+        let policies = Arc::new(Policies::new(&loaded));
+        policies.claim_policy.log_rules();
+        policies.port_policy.log_rules();
+        for listener in &self.listeners {
+            let _ = listener.send(policies.clone());
+        }
+
+        self.running.passive = loaded.passive;
+        self.running.log_level = loaded.log_level;
+        self.running.discovery = loaded.discovery;
+        self.running.claims = loaded.claims;
+        self.running.tcp = loaded.tcp;
+        self.running.personality = loaded.personality;
+        self.running.ports = loaded.ports;
+        self.running.icmp = loaded.icmp;
+        self.running.scanners = loaded.scanners;
+        info!("Configuration reloaded");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload() {
+        let running = Config::default();
+        let (policy_sender, policy_updates) = mpsc::channel();
+        let passive_mode = PassiveMode::default();
+        let loaded = Arc::new(std::sync::Mutex::new(Ok(Config::default())));
+        let load = loaded.clone();
+        let mut reloader = Reloader::new(
+            Box::new(move || load.lock().unwrap().clone()),
+            running,
+            vec![policy_sender],
+            passive_mode.clone(),
+            Arc::new(|_| Ok(())),
+        );
+
+        let mut changed = Config::default();
+        changed.discovery.request_threshold = 5;
+        changed.interface = "eth1".to_string();
+        *loaded.lock().unwrap() = Ok(changed.clone());
+        reloader.reload();
+        let policies = policy_updates.try_recv().unwrap();
+        assert_eq!(policies.discovery.request_threshold, 5);
+        assert_eq!(reloader.running.discovery.request_threshold, 5);
+        assert_eq!(reloader.running.interface, "");
+        assert_eq!(
+            restart_only_changes(&reloader.running, &changed),
+            ["interface"]
+        );
+
+        // Switched on the control socket, an unrelated reload keeps it on
+        passive_mode.set(true);
+        reloader.reload();
+        assert!(passive_mode.is_on());
+        policy_updates.try_recv().unwrap();
+
+        *loaded.lock().unwrap() = Err("Invalid configuration".to_string());
+        reloader.reload();
+        assert!(policy_updates.try_recv().is_err());
+        assert_eq!(reloader.running.discovery.request_threshold, 5);
+    }
+}
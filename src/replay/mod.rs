@@ -0,0 +1,358 @@
+use crate::arp_listener::ArpState;
+use crate::claims::{ClaimMacs, ClaimPolicy};
+use crate::config::Config;
+use crate::control::PassiveMode;
+use crate::event_log::{self, EventLog};
+use crate::metrics::Metrics;
+use crate::ndp_listener::NdpState;
+use crate::packet_io::{Frame, PacketIo, PcapFileIo};
+use crate::port_policy::PortPolicy;
+use crate::state_file;
+use crate::tarpitter;
+use crate::tcp_listener::TcpState;
+use pnet_base::MacAddr;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Locally administered MAC address replies are sent from when replaying
+pub const REPLAY_MAC_ADDRESS: MacAddr = MacAddr(0x02, 0x00, 0x00, 0x00, 0x00, 0x01);
+
+/// Runs recorded frames through the ARP, NDP and TCP logic on a virtual
+/// clock, collecting every frame ANTS would have sent
+pub struct Replay {
+    arp_state: ArpState,
+    ndp_state: NdpState,
+    tcp_state: TcpState,
+    /// Frames to send ordered by send time and the order they were queued in
+    outgoing: BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>>,
+    next_sequence: u64,
+}
+
+impl Replay {
+    pub fn new(config: &Config, mac_address: MacAddr, start: Instant, events: EventLog) -> Self {
+        let claim_policy = ClaimPolicy::new(&config.claims);
+        let claim_macs = ClaimMacs::new(config.mac, mac_address);
+        // Nothing serves them, the listeners just need somewhere to count
+        let metrics = Arc::new(Metrics::default());
+        let passive_mode = PassiveMode::new(config.passive);
+        Replay {
+            arp_state: ArpState::new(
+                config.discovery,
+                claim_policy.clone(),
+                claim_macs,
+                passive_mode.clone(),
+                metrics.clone(),
+            ),
+            ndp_state: NdpState::new(
+                config.discovery,
+                claim_policy,
+                claim_macs,
+                passive_mode.clone(),
+                metrics.clone(),
+            ),
+            tcp_state: TcpState::new(
+                config.tcp,
+                config.icmp,
+                config.scanners,
+                &config.personality,
+                PortPolicy::new(&config.ports, config.tcp.persist),
+                claim_macs,
+                passive_mode,
+                events,
+                metrics,
+                start,
+            ),
+            outgoing: BinaryHeap::new(),
+            next_sequence: 0,
+        }
+    }
+
+    /// Feeds one received frame, replies are queued at the time they would be sent
+    pub fn handle_frame(&mut self, frame: &[u8], now: Instant) {
+        self.tcp_state.sweep_if_due(now);
+        for event in self.tcp_state.take_expired_claims() {
+            tarpitter::log_claim_event(&event);
+            self.arp_state.apply_claim_event(event);
+            self.ndp_state.apply_claim_event(event);
+        }
+
+        let claim_results = [
+            self.arp_state.handle_frame(frame, now),
+            self.ndp_state.handle_frame(frame, now),
+        ];
+        for (event, reply) in claim_results.into_iter().flatten() {
+            tarpitter::log_claim_event(&event);
+            self.tcp_state.apply_claim_event(event, now);
+            if let Some(reply) = reply {
+                self.queue(reply, now);
+            }
+        }
+
+        if let Some(reply) = self.tcp_state.handle_frame(frame, now) {
+            let mut queued = None;
+            self.tcp_state.queue_reply(reply, now, |reply, delay| {
+                queued = Some((reply, delay));
+                true
+            });
+            if let Some((reply, delay)) = queued {
+                self.queue(reply, now + delay);
+            }
+        }
+    }
+
+    fn queue(&mut self, frame: Vec<u8>, send_at: Instant) {
+        self.outgoing
+            .push(Reverse((send_at, self.next_sequence, frame)));
+        self.next_sequence += 1;
+    }
+
+    /// Takes the frames due by now, in the order they would have been sent
+    pub fn pop_due(&mut self, now: Instant) -> Vec<(Instant, Vec<u8>)> {
+        let mut due = Vec::new();
+        while self
+            .outgoing
+            .peek()
+            .is_some_and(|Reverse((send_at, _, _))| *send_at <= now)
+        {
+            let Reverse((send_at, _, frame)) = self.outgoing.pop().unwrap();
+            due.push((send_at, frame));
+        }
+        due
+    }
+
+    /// Takes every frame still queued when the capture ends
+    pub fn drain(&mut self) -> Vec<(Instant, Vec<u8>)> {
+        let mut remaining = Vec::new();
+        while let Some(Reverse((send_at, _, frame))) = self.outgoing.pop() {
+            remaining.push((send_at, frame));
+        }
+        remaining
+    }
+}
+
+/// Maps capture timestamps to Instants, the first packet is at start
+struct VirtualClock {
+    start: Instant,
+    first_timestamp: Option<Duration>,
+}
+
+impl VirtualClock {
+    fn now(&mut self, timestamp: Duration) -> Instant {
+        let first_timestamp = *self.first_timestamp.get_or_insert(timestamp);
+        // Timestamps going backwards are treated as no time passing
+        self.start + timestamp.saturating_sub(first_timestamp)
+    }
+
+    fn timestamp(&self, at: Instant) -> Duration {
+        self.first_timestamp.unwrap_or_default() + at.duration_since(self.start)
+    }
+}
+
+/// Replays a capture through ANTS, writing the frames it would have
+/// sent to output with timestamps on the same clock as the input
+pub fn replay_capture(config: &Config, input: &Path, output: Option<&Path>) -> io::Result<()> {
+    let mut config = config.clone();
+    // Decoys look as they did live when the seeds in use were kept
+    if !config.state.file.is_empty() {
+        if let Some(seeds) = state_file::load(Path::new(&config.state.file))?.seeds {
+            seeds.fill(&mut config);
+        }
+    }
+    let mut packet_io = PcapFileIo::open(input, output)?;
+    let (frames_read, frames_written) = replay_frames(&config, &mut packet_io)?;
+
+    info!(
+        "Replayed {} frames, ANTS would have sent {} frames",
+        frames_read, frames_written
+    );
+    Ok(())
+}
+
+/// Feeds every frame from packet_io through a Replay and sends what
+/// ANTS would have sent back to it, returns the frames read and sent
+fn replay_frames(config: &Config, packet_io: &mut dyn PacketIo) -> io::Result<(u64, u64)> {
+    let start = Instant::now();
+    let mut clock = VirtualClock {
+        start,
+        first_timestamp: None,
+    };
+    let mut event_output = event_log::open_output(&config.events)?;
+    let mut replay = None;
+    let mut frames_read: u64 = 0;
+    let mut frames_written: u64 = 0;
+
+    let mut write = |packet_io: &mut dyn PacketIo,
+                     clock: &VirtualClock,
+                     frames: Vec<(Instant, Vec<u8>)>|
+     -> io::Result<()> {
+        for (send_at, frame) in frames {
+            packet_io.send(Frame {
+                timestamp: clock.timestamp(send_at),
+                data: &frame,
+            })?;
+            frames_written += 1;
+        }
+        Ok(())
+    };
+
+    while let Some(frame) = packet_io.recv()? {
+        let now = clock.now(frame.timestamp);
+        // Events are timed on the capture's clock, which starts at the first frame
+        let replay = replay.get_or_insert_with(|| {
+            let events = EventLog::new(event_output.take(), start, frame.timestamp);
+            Replay::new(config, REPLAY_MAC_ADDRESS, start, events)
+        });
+        replay.handle_frame(frame.data, now);
+        frames_read += 1;
+        // Replies go out once the virtual clock reaches them, before later frames
+        write(packet_io, &clock, replay.pop_due(now))?;
+    }
+    if let Some(replay) = &mut replay {
+        write(packet_io, &clock, replay.drain())?;
+        replay.tcp_state.flush_events();
+    }
+
+    Ok((frames_read, frames_written))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MacMode;
+    use crate::packet_decoder;
+    use crate::test_frames::{arp_request, DECOY_IP};
+    use pnet_packet::ip::IpNextHeaderProtocols;
+    use pnet_packet::tcp::TcpFlags;
+    use std::net::IpAddr;
+
+    fn no_events(start: Instant) -> EventLog {
+        EventLog::new(None, start, Duration::ZERO)
+    }
+
+    fn tcp_syn() -> Vec<u8> {
+        crate::test_frames::tcp_syn(REPLAY_MAC_ADDRESS)
+    }
+
+    #[test]
+    fn test_replay_claims_and_answers_on_virtual_clock() {
+        let config = Config::default();
+        let start = Instant::now();
+        let mut replay = Replay::new(&config, REPLAY_MAC_ADDRESS, start, no_events(start));
+
+        replay.handle_frame(&arp_request(), start);
+        replay.handle_frame(&arp_request(), start + Duration::from_secs(1));
+        let arp_replies = replay.pop_due(start + Duration::from_secs(1));
+        assert_eq!(arp_replies.len(), 1);
+        assert_eq!(arp_replies[0].0, start + Duration::from_secs(1));
+
+        let syn_time = start + Duration::from_secs(2);
+        replay.handle_frame(&tcp_syn(), syn_time);
+        // The SYN/ACK waits for the configured delay
+        assert!(replay.pop_due(syn_time).is_empty());
+        let syn_acks = replay.drain();
+        assert_eq!(syn_acks.len(), 1);
+        assert_eq!(syn_acks[0].0, syn_time + config.tcp.syn_ack_delay());
+
+        let syn_ack = packet_decoder::decode_tcp_segment(&syn_acks[0].1).unwrap();
+        assert_eq!(syn_ack.src_ip, IpAddr::V4(DECOY_IP));
+        assert_eq!(syn_ack.src_mac, REPLAY_MAC_ADDRESS);
+        assert_eq!(syn_ack.flags, TcpFlags::SYN | TcpFlags::ACK);
+    }
+
+    #[test]
+    fn test_replay_answers_from_virtual_mac() {
+        let mut config = Config::default();
+        config.mac.mode = MacMode::Virtual;
+        let start = Instant::now();
+        let mut replay = Replay::new(&config, REPLAY_MAC_ADDRESS, start, no_events(start));
+        let virtual_mac =
+            ClaimMacs::new(config.mac, REPLAY_MAC_ADDRESS).mac_for(IpAddr::V4(DECOY_IP));
+
+        replay.handle_frame(&arp_request(), start);
+        replay.handle_frame(&arp_request(), start);
+        replay.handle_frame(&crate::test_frames::tcp_syn(virtual_mac), start);
+
+        let replies = replay.drain();
+        assert_eq!(replies.len(), 2);
+        for (_, reply) in replies {
+            let frame = packet_decoder::decode_ethernet(&reply).unwrap();
+            assert_eq!(frame.source, virtual_mac);
+        }
+    }
+
+    #[test]
+    fn test_replay_answers_ping_to_claimed_ip_at_once() {
+        let start = Instant::now();
+        let mut config = Config::default();
+        config.icmp.enabled = true;
+        let mut replay = Replay::new(&config, REPLAY_MAC_ADDRESS, start, no_events(start));
+        let ping = crate::test_frames::icmp_echo_request(REPLAY_MAC_ADDRESS, b"ping");
+
+        replay.handle_frame(&ping, start);
+        assert!(replay.drain().is_empty());
+
+        replay.handle_frame(&arp_request(), start);
+        replay.handle_frame(&arp_request(), start);
+        replay.drain();
+        replay.handle_frame(&ping, start);
+        let replies = replay.pop_due(start);
+        assert_eq!(replies.len(), 1);
+        let frame = packet_decoder::decode_ethernet(&replies[0].1).unwrap();
+        let datagram = packet_decoder::decode_ip_datagram(&frame).unwrap();
+        assert_eq!(datagram.src_ip, IpAddr::V4(DECOY_IP));
+        assert_eq!(datagram.protocol, IpNextHeaderProtocols::Icmp);
+    }
+
+    #[test]
+    fn test_replay_expired_claim_waits_for_threshold_again() {
+        let config = Config::default();
+        let start = Instant::now();
+        let mut replay = Replay::new(&config, REPLAY_MAC_ADDRESS, start, no_events(start));
+        replay.handle_frame(&arp_request(), start);
+        replay.handle_frame(&arp_request(), start);
+        assert_eq!(replay.drain().len(), 1);
+
+        let later = start + config.tcp.claim_expiry() + Duration::from_secs(1);
+        replay.handle_frame(&arp_request(), later);
+
+        assert!(replay.drain().is_empty());
+    }
+
+    #[test]
+    fn test_replay_ignores_syn_to_unclaimed_ip() {
+        let start = Instant::now();
+        let mut replay = Replay::new(
+            &Config::default(),
+            REPLAY_MAC_ADDRESS,
+            start,
+            no_events(start),
+        );
+
+        replay.handle_frame(&tcp_syn(), start);
+
+        assert!(replay.drain().is_empty());
+    }
+
+    #[test]
+    fn test_virtual_clock() {
+        let start = Instant::now();
+        let mut clock = VirtualClock {
+            start,
+            first_timestamp: None,
+        };
+
+        assert_eq!(clock.now(Duration::from_secs(1000)), start);
+        let later = clock.now(Duration::from_millis(1_500_250));
+        assert_eq!(later, start + Duration::from_millis(500_250));
+        assert_eq!(clock.now(Duration::from_secs(999)), start);
+        assert_eq!(
+            clock.timestamp(later + Duration::from_millis(500)),
+            Duration::from_millis(1_500_750)
+        );
+    }
+}
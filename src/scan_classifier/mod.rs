@@ -0,0 +1,366 @@
+use crate::config::ScannersConfig;
+use crate::packet_decoder::{
+    TcpSegment, TCP_OPTION_MSS, TCP_OPTION_NOP, TCP_OPTION_SACK_PERMITTED, TCP_OPTION_TIMESTAMP,
+    TCP_OPTION_WINDOW_SCALE,
+};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Instant;
+
+/// The IP ID ZMap sends every probe with
+const ZMAP_IP_ID: u16 = 54321;
+
+/// Windows of nmap's SYN scan, which sends an MSS option and nothing else
+const NMAP_SYN_WINDOWS: [u16; 4] = [1024, 2048, 3072, 4096];
+
+/// Option layouts of the SYNs nmap's OS detection sends, without the
+/// end-of-options option some of them close with
+const NMAP_PROBE_OPTIONS: [&[u8]; 6] = [
+    &[
+        TCP_OPTION_WINDOW_SCALE,
+        TCP_OPTION_NOP,
+        TCP_OPTION_MSS,
+        TCP_OPTION_TIMESTAMP,
+        TCP_OPTION_SACK_PERMITTED,
+    ],
+    &[
+        TCP_OPTION_MSS,
+        TCP_OPTION_WINDOW_SCALE,
+        TCP_OPTION_SACK_PERMITTED,
+        TCP_OPTION_TIMESTAMP,
+    ],
+    &[
+        TCP_OPTION_TIMESTAMP,
+        TCP_OPTION_NOP,
+        TCP_OPTION_NOP,
+        TCP_OPTION_WINDOW_SCALE,
+        TCP_OPTION_NOP,
+        TCP_OPTION_MSS,
+    ],
+    &[
+        TCP_OPTION_SACK_PERMITTED,
+        TCP_OPTION_TIMESTAMP,
+        TCP_OPTION_WINDOW_SCALE,
+    ],
+    &[
+        TCP_OPTION_MSS,
+        TCP_OPTION_SACK_PERMITTED,
+        TCP_OPTION_TIMESTAMP,
+        TCP_OPTION_WINDOW_SCALE,
+    ],
+    &[
+        TCP_OPTION_MSS,
+        TCP_OPTION_SACK_PERMITTED,
+        TCP_OPTION_TIMESTAMP,
+    ],
+];
+
+/// What a source's SYNs to claimed IPs add up to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanKind {
+    /// SYNs to one port of one claimed IP, then nothing for scanners.window_secs
+    SingleProbe,
+    /// Many claimed IPs
+    HorizontalSweep,
+    /// Many ports of a few claimed IPs
+    VerticalScan,
+    /// A sweep of a single port that other sources are sweeping too
+    WormLike,
+}
+
+/// Scanners recognised from the traits of their SYNs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tool {
+    Masscan,
+    Zmap,
+    Nmap,
+}
+
+/// A source's classification, given whenever it changes
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Classification {
+    pub scanner_ip: IpAddr,
+    pub kind: ScanKind,
+    /// Set when every SYN of the source looked like the tool's
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool: Option<Tool>,
+    pub syns: u64,
+    /// Since the source's first SYN
+    pub duration_secs: u64,
+}
+
+/// SYNs from one source since it last went quiet
+struct Activity {
+    first_seen: Instant,
+    last_seen: Instant,
+    syns: u64,
+    /// Claimed IPs reached, counted up to scanners.sweep_ips
+    decoys: HashSet<IpAddr>,
+    /// Ports reached, counted up to scanners.scan_ports
+    ports: HashSet<u16>,
+    tool: Option<Tool>,
+    kind: Option<ScanKind>,
+}
+
+impl Activity {
+    fn classification(&self, scanner_ip: IpAddr, kind: ScanKind) -> Classification {
+        Classification {
+            scanner_ip,
+            kind,
+            tool: self.tool,
+            syns: self.syns,
+            duration_secs: self.last_seen.duration_since(self.first_seen).as_secs(),
+        }
+    }
+}
+
+/// Follows every source of SYNs to claimed IPs and tells what kind of
+/// scanning it does, without any I/O
+pub struct ScanClassifier {
+    config: ScannersConfig,
+    sources: HashMap<IpAddr, Activity>,
+    /// Sources sweeping a single port, by port, and when they last did
+    port_sweepers: HashMap<u16, HashMap<IpAddr, Instant>>,
+}
+
+impl ScanClassifier {
+    pub fn new(config: ScannersConfig) -> Self {
+        ScanClassifier {
+            config,
+            sources: HashMap::new(),
+            port_sweepers: HashMap::new(),
+        }
+    }
+
+    /// Sources keep what they have been seen doing, the new thresholds
+    /// apply from their next SYN
+    pub fn set_config(&mut self, config: ScannersConfig) {
+        self.config = config;
+    }
+
+    /// Notes a SYN to a claimed IP, returns the source's classification
+    /// when this SYN changes it
+    pub fn observe(&mut self, syn: &TcpSegment, now: Instant) -> Option<Classification> {
+        let config = self.config;
+        if !self.sources.contains_key(&syn.src_ip) && self.sources.len() >= config.max_sources {
+            return None;
+        }
+        let tool = recognise_tool(syn);
+        let activity = self.sources.entry(syn.src_ip).or_insert(Activity {
+            first_seen: now,
+            last_seen: now,
+            syns: 0,
+            decoys: HashSet::new(),
+            ports: HashSet::new(),
+            tool,
+            kind: None,
+        });
+        activity.last_seen = now;
+        activity.syns += 1;
+        if activity.tool != tool {
+            activity.tool = None;
+        }
+        if activity.decoys.len() < config.sweep_ips {
+            activity.decoys.insert(syn.dst_ip);
+        }
+        if activity.ports.len() < config.scan_ports {
+            activity.ports.insert(syn.dst_port);
+        }
+        // Nothing is more telling than propagation
+        if activity.kind == Some(ScanKind::WormLike) {
+            return None;
+        }
+
+        let kind = if activity.decoys.len() >= config.sweep_ips {
+            match single_port(&activity.ports) {
+                Some(port) => {
+                    let sweepers = self.port_sweepers.entry(port).or_default();
+                    sweepers.insert(syn.src_ip, now);
+                    let window = config.window();
+                    let sweeping = sweepers
+                        .values()
+                        .filter(|&&last_seen| now.duration_since(last_seen) < window)
+                        .count();
+                    if sweeping >= config.worm_sources {
+                        ScanKind::WormLike
+                    } else {
+                        ScanKind::HorizontalSweep
+                    }
+                }
+                None => ScanKind::HorizontalSweep,
+            }
+        } else if activity.ports.len() >= config.scan_ports {
+            ScanKind::VerticalScan
+        } else {
+            return None;
+        };
+        if activity.kind == Some(kind) {
+            return None;
+        }
+        activity.kind = Some(kind);
+        Some(activity.classification(syn.src_ip, kind))
+    }
+
+    /// Forgets sources that sent nothing for scanners.window_secs, returns
+    /// the single probes among them
+    pub fn expire(&mut self, now: Instant) -> Vec<Classification> {
+        let window = self.config.window();
+        let mut single_probes = Vec::new();
+        self.sources.retain(|&scanner_ip, activity| {
+            if now.duration_since(activity.last_seen) < window {
+                return true;
+            }
+            if activity.kind.is_none() && activity.decoys.len() == 1 && activity.ports.len() == 1 {
+                single_probes.push(activity.classification(scanner_ip, ScanKind::SingleProbe));
+            }
+            false
+        });
+        self.port_sweepers.retain(|_, sweepers| {
+            sweepers.retain(|_, last_seen| now.duration_since(*last_seen) < window);
+            !sweepers.is_empty()
+        });
+        single_probes
+    }
+}
+
+fn single_port(ports: &HashSet<u16>) -> Option<u16> {
+    match ports.len() {
+        1 => ports.iter().next().copied(),
+        _ => None,
+    }
+}
+
+/// The tool a SYN looks like it was sent by: ZMap by its fixed IP ID,
+/// masscan by the IP ID it derives from the target and sequence number,
+/// nmap by the window and option order of its SYN scan and OS probes
+pub fn recognise_tool(syn: &TcpSegment) -> Option<Tool> {
+    match (syn.ip_id, syn.dst_ip) {
+        (Some(ZMAP_IP_ID), _) => return Some(Tool::Zmap),
+        (Some(ip_id), IpAddr::V4(dst_ip))
+            if ip_id == masscan_ip_id(dst_ip, syn.dst_port, syn.sequence) =>
+        {
+            return Some(Tool::Masscan)
+        }
+        _ => {}
+    }
+    let option_kinds = syn.option_kinds.as_slice();
+    let nmap_syn_scan = option_kinds == [TCP_OPTION_MSS] && NMAP_SYN_WINDOWS.contains(&syn.window);
+    (nmap_syn_scan || NMAP_PROBE_OPTIONS.contains(&option_kinds)).then_some(Tool::Nmap)
+}
+
+fn masscan_ip_id(dst_ip: Ipv4Addr, dst_port: u16, sequence: u32) -> u16 {
+    (u32::from(dst_ip) ^ u32::from(dst_port) ^ sequence) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_decoder;
+    use crate::test_frames;
+    use pnet_base::MacAddr;
+    use std::time::Duration;
+
+    fn syn(scanner: u8, decoy: u8, port: u16) -> TcpSegment {
+        let mut syn =
+            packet_decoder::decode_tcp_segment(&test_frames::tcp_syn(MacAddr::zero())).unwrap();
+        syn.src_ip = Ipv4Addr::new(10, 0, 0, scanner).into();
+        syn.dst_ip = Ipv4Addr::new(192, 168, 0, decoy).into();
+        syn.dst_port = port;
+        syn.ip_id = Some(1);
+        syn
+    }
+
+    #[test]
+    fn test_recognise_tool() {
+        let mut zmap = syn(1, 2, 80);
+        zmap.ip_id = Some(54321);
+        assert_eq!(recognise_tool(&zmap), Some(Tool::Zmap));
+
+        let mut masscan = syn(1, 2, 80);
+        masscan.sequence = 0x1234_5678;
+        masscan.ip_id = Some(masscan_ip_id(
+            Ipv4Addr::new(192, 168, 0, 2),
+            80,
+            0x1234_5678,
+        ));
+        assert_eq!(recognise_tool(&masscan), Some(Tool::Masscan));
+
+        let mut nmap = syn(1, 2, 80);
+        nmap.option_kinds.push(TCP_OPTION_MSS);
+        assert_eq!(recognise_tool(&nmap), Some(Tool::Nmap));
+        nmap.window = 65535;
+        assert_eq!(recognise_tool(&nmap), None);
+
+        // The first OS detection probe
+        let mut nmap = syn(1, 2, 80);
+        for kind in NMAP_PROBE_OPTIONS[0] {
+            nmap.option_kinds.push(*kind);
+        }
+        assert_eq!(recognise_tool(&nmap), Some(Tool::Nmap));
+
+        assert_eq!(recognise_tool(&syn(1, 2, 80)), None);
+    }
+
+    #[test]
+    fn test_classify_sweeps_and_scans() {
+        let now = Instant::now();
+        let mut classifier = ScanClassifier::new(ScannersConfig {
+            sweep_ips: 3,
+            scan_ports: 3,
+            worm_sources: 2,
+            ..ScannersConfig::default()
+        });
+
+        assert_eq!(classifier.observe(&syn(1, 2, 445), now), None);
+        assert_eq!(classifier.observe(&syn(1, 3, 445), now), None);
+        let sweep = classifier.observe(&syn(1, 4, 445), now).unwrap();
+        assert_eq!(sweep.kind, ScanKind::HorizontalSweep);
+        assert_eq!(sweep.syns, 3);
+        // Unchanged, nothing new to tell
+        assert_eq!(classifier.observe(&syn(1, 5, 445), now), None);
+
+        for port in [22, 80] {
+            assert_eq!(classifier.observe(&syn(2, 2, port), now), None);
+        }
+        let scan = classifier.observe(&syn(2, 2, 443), now).unwrap();
+        assert_eq!(scan.kind, ScanKind::VerticalScan);
+
+        // A second source sweeping 445 makes both look like a worm spreading
+        for decoy in [2, 3] {
+            classifier.observe(&syn(3, decoy, 445), now);
+        }
+        let worm = classifier.observe(&syn(3, 4, 445), now).unwrap();
+        assert_eq!(worm.kind, ScanKind::WormLike);
+        let worm = classifier.observe(&syn(1, 6, 445), now).unwrap();
+        assert_eq!(worm.kind, ScanKind::WormLike);
+    }
+
+    #[test]
+    fn test_single_probe_on_expiry() {
+        let now = Instant::now();
+        let mut classifier = ScanClassifier::new(ScannersConfig::default());
+        let window = ScannersConfig::default().window();
+
+        classifier.observe(&syn(1, 2, 22), now);
+        classifier.observe(&syn(1, 2, 22), now + Duration::from_secs(1));
+        classifier.observe(&syn(2, 2, 22), now);
+        classifier.observe(&syn(2, 3, 22), now);
+
+        assert!(classifier.expire(now + window).is_empty());
+        let single_probes = classifier.expire(now + Duration::from_secs(1) + window);
+        assert_eq!(
+            single_probes,
+            [Classification {
+                scanner_ip: Ipv4Addr::new(10, 0, 0, 1).into(),
+                kind: ScanKind::SingleProbe,
+                tool: None,
+                syns: 2,
+                duration_secs: 1,
+            }]
+        );
+        assert!(classifier.sources.is_empty());
+    }
+}
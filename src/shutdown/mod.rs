@@ -0,0 +1,31 @@
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Set once ANTS is asked to stop, listener threads check it between reads
+/// and wind down on their own
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown(Arc<AtomicBool>);
+
+impl Shutdown {
+    /// Requested by SIGINT or SIGTERM. A second signal while shutting down
+    /// exits at once.
+    pub fn on_signals() -> io::Result<Self> {
+        let shutdown = Shutdown::default();
+        for signal in [SIGINT, SIGTERM] {
+            flag::register_conditional_shutdown(signal, 1, shutdown.0.clone())?;
+            flag::register(signal, shutdown.0.clone())?;
+        }
+        Ok(shutdown)
+    }
+
+    pub fn request(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,268 @@
+use crate::claims::{Claim, ClaimMacs, ClaimPolicy};
+use crate::config::{Config, StateConfig};
+use crate::packet_decoder::{VlanTag, VlanTags};
+use pnet_base::MacAddr;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::fs;
+use std::hash::BuildHasher;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+
+/// Written as "version", files of another version are not read
+pub const FORMAT_VERSION: u32 = 1;
+
+/// A claimed IP as kept across restarts, times are seconds since the Unix epoch
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredClaim {
+    pub ip: IpAddr,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub vlan: Vec<VlanTag>,
+    /// The MAC address ANTS answered for the IP from
+    pub mac: MacAddr,
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+/// What the state file holds
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ClaimTable {
+    version: u32,
+    /// Missing from files written before seeds were kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seeds: Option<Seeds>,
+    pub claims: Vec<StoredClaim>,
+}
+
+/// Random seeds standing in for the [mac], [personality] and [ports] seeds
+/// left at 0, kept so decoys look the same after a restart
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Seeds {
+    pub mac: u64,
+    pub personality: u64,
+    pub ports: u64,
+}
+
+impl Seeds {
+    pub fn random() -> Self {
+        let random = || RandomState::new().hash_one(0u8).max(1);
+        Seeds {
+            mac: random(),
+            personality: random(),
+            ports: random(),
+        }
+    }
+
+    /// Sets the seeds config leaves at 0
+    pub fn fill(&self, config: &mut Config) {
+        for (seed, random) in [
+            (&mut config.mac.seed, self.mac),
+            (&mut config.personality.seed, self.personality),
+            (&mut config.ports.seed, self.ports),
+        ] {
+            if *seed == 0 {
+                *seed = random;
+            }
+        }
+    }
+}
+
+impl StoredClaim {
+    /// None when it has more VLAN tags than ANTS decodes
+    pub fn claim(&self) -> Option<Claim> {
+        let mut vlan_tags = VlanTags::default();
+        for tag in &self.vlan {
+            if !vlan_tags.push(*tag) {
+                return None;
+            }
+        }
+        Some(Claim {
+            vlan_tags,
+            ip: self.ip,
+        })
+    }
+}
+
+/// Writes the claim table to the state file every state.snapshot_secs
+pub struct StateWriter {
+    path: PathBuf,
+    seeds: Seeds,
+    interval: Duration,
+    last_write: Instant,
+}
+
+impl StateWriter {
+    /// None when no state file is configured
+    pub fn new(state_config: &StateConfig, seeds: Seeds, now: Instant) -> Option<Self> {
+        (!state_config.file.is_empty()).then(|| StateWriter {
+            path: PathBuf::from(&state_config.file),
+            seeds,
+            interval: state_config.snapshot_interval(),
+            last_write: now,
+        })
+    }
+
+    pub fn write_if_due(&mut self, now: Instant, claims: impl FnOnce() -> Vec<StoredClaim>) {
+        if now.duration_since(self.last_write) >= self.interval {
+            self.write(claims());
+            self.last_write = now;
+        }
+    }
+
+    pub fn write(&mut self, claims: Vec<StoredClaim>) {
+        if let Err(e) = save(&self.path, self.seeds, claims) {
+            error!("Writing state file {} failed: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Replaces the state file with claims. The table is written next to it
+/// first, a crash mid-write leaves the previous snapshot in place.
+pub fn save(path: &Path, seeds: Seeds, claims: Vec<StoredClaim>) -> io::Result<()> {
+    let table = ClaimTable {
+        version: FORMAT_VERSION,
+        seeds: Some(seeds),
+        claims,
+    };
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, serde_json::to_vec_pretty(&table)?)?;
+    fs::rename(&temporary, path)
+}
+
+/// Seeds and claims in the state file, neither if it does not exist yet
+pub fn load(path: &Path) -> io::Result<ClaimTable> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(ClaimTable::default()),
+        Err(e) => return Err(e),
+    };
+    let table: ClaimTable = serde_json::from_slice(&contents)?;
+    if table.version != FORMAT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "version {} is not supported, expected {}",
+                table.version, FORMAT_VERSION
+            ),
+        ));
+    }
+    Ok(table)
+}
+
+/// Keeps the claims that would still be held had ANTS kept running: seen
+/// within claim_expiry of now, answered from the MAC the current
+/// configuration gives the IP and allowed by the claim policy
+pub fn restorable(
+    claims: Vec<StoredClaim>,
+    now: Duration,
+    claim_expiry: Duration,
+    claim_macs: &ClaimMacs,
+    claim_policy: &ClaimPolicy,
+) -> Vec<StoredClaim> {
+    claims
+        .into_iter()
+        .filter(|stored| {
+            let reason =
+                if now.saturating_sub(Duration::from_secs(stored.last_seen)) >= claim_expiry {
+                    "expired"
+                } else if stored.mac != claim_macs.mac_for(stored.ip) {
+                    "claimed from another MAC"
+                } else if claim_policy.check(stored.ip).is_err() {
+                    "refused by claim policy"
+                } else if stored.claim().is_none() {
+                    "too many VLAN tags"
+                } else {
+                    return true;
+                };
+            info!("Not restoring claim on {}: {}", stored.ip, reason);
+            false
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ClaimsConfig, MacConfig};
+    use std::net::Ipv4Addr;
+
+    const ANTS_MAC: MacAddr = MacAddr(0x02, 0x00, 0x00, 0x00, 0x00, 0x02);
+
+    fn stored(last_octet: u8, last_seen: u64) -> StoredClaim {
+        StoredClaim {
+            ip: Ipv4Addr::new(192, 168, 0, last_octet).into(),
+            vlan: vec![VlanTag {
+                tpid: 0x8100,
+                vid: 100,
+            }],
+            mac: ANTS_MAC,
+            first_seen: 1_700_000_000,
+            last_seen,
+        }
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("ants-state-{}.json", std::process::id()));
+        let claims = vec![stored(2, 1_700_000_100), stored(3, 1_700_000_200)];
+
+        let seeds = Seeds::random();
+
+        save(&path, seeds, claims.clone()).unwrap();
+        let loaded = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.seeds, Some(seeds));
+        assert_eq!(loaded.claims, claims);
+        assert_eq!(
+            loaded.claims[0].claim().unwrap().vlan_tags.as_slice()[0].vid,
+            100
+        );
+        let missing = load(&path).unwrap();
+        assert_eq!(missing.seeds, None);
+        assert!(missing.claims.is_empty());
+    }
+
+    #[test]
+    fn test_seeds_fill_only_unset_seeds() {
+        let seeds = Seeds::random();
+        let mut config = Config::default();
+        config.ports.seed = 7;
+
+        seeds.fill(&mut config);
+
+        assert_eq!(config.mac.seed, seeds.mac);
+        assert_eq!(config.personality.seed, seeds.personality);
+        assert_eq!(config.ports.seed, 7);
+        assert_ne!(seeds.mac, 0);
+    }
+
+    #[test]
+    fn test_restorable() {
+        let claim_macs = ClaimMacs::new(MacConfig::default(), ANTS_MAC);
+        let claim_policy = ClaimPolicy::new(&ClaimsConfig::default());
+        let now = Duration::from_secs(1_700_000_200);
+        let other_mac = StoredClaim {
+            mac: MacAddr(0x02, 0, 0, 0, 0, 0x03),
+            ..stored(4, 1_700_000_200)
+        };
+
+        let restored = restorable(
+            vec![
+                stored(2, 1_700_000_100),
+                stored(3, 1_700_000_000),
+                other_mac,
+            ],
+            now,
+            Duration::from_secs(120),
+            &claim_macs,
+            &claim_policy,
+        );
+
+        assert_eq!(restored, [stored(2, 1_700_000_100)]);
+    }
+}
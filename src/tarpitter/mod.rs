@@ -1,17 +1,402 @@
-use tracing::info;
+use tracing::{error, info, warn};
 
-use crate::{arp_listener, tcp_listener};
+use crate::arp_listener::ArpState;
+use crate::claims::{ClaimEvent, ClaimMacs, ClaimPolicy};
+use crate::config::{Config, MacMode};
+use crate::control::{self, Control, PassiveMode, SetLogFilter};
+use crate::event_log::{self, EventLog};
+use crate::metrics::{self, Metrics};
+use crate::ndp_listener::NdpState;
+use crate::packet_io::{self, PacketIo};
+use crate::port_policy::PortPolicy;
+use crate::reload::{LoadConfig, ReloadRequest, Reloader};
+use crate::shutdown::Shutdown;
+use crate::state_file::{self, ClaimTable, Seeds, StateWriter, StoredClaim};
+use crate::tcp_listener::TcpState;
+use crate::{arp_listener, ndp_listener, tcp_listener};
 
-use std::sync::mpsc;
+use pnet_base::MacAddr;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::Instant;
 
-pub fn start_tarpitting(passive_mode: bool, interface_name: &str) {
-    let rx = arp_listener::start_arp_handling(interface_name, passive_mode);
+pub fn start_tarpitting(config: &Config, set_log_filter: SetLogFilter, load_config: LoadConfig) {
+    let mac_address = packet_io::interface_mac(&config.interface);
+    let signals = Shutdown::on_signals().and_then(|shutdown| {
+        ReloadRequest::on_sighup().map(|reload_request| (shutdown, reload_request))
+    });
+    let (shutdown, reload_request) = signals.unwrap_or_else(|e| {
+        eprintln!("Cannot handle signals: {}", e);
+        process::exit(1);
+    });
+    run_tarpit(
+        config,
+        mac_address,
+        shutdown,
+        reload_request,
+        set_log_filter,
+        load_config,
+        |promiscuous| packet_io::open(config.backend, &config.interface, promiscuous),
+    );
+}
+
+/// Runs every listener on its own handle from open_io, returns once
+/// shutdown is requested or the captures have ended and every queued
+/// reply has been sent. open_io is told whether the handle must capture
+/// in promiscuous mode. The configuration is read again with load_config
+/// on every reload request.
+pub fn run_tarpit(
+    config: &Config,
+    mac_address: MacAddr,
+    shutdown: Shutdown,
+    reload_request: ReloadRequest,
+    set_log_filter: SetLogFilter,
+    load_config: LoadConfig,
+    open_io: impl Fn(bool) -> Box<dyn PacketIo>,
+) {
+    let stored = if config.state.file.is_empty() {
+        for key in random_seeds(config) {
+            warn!(
+                "{} is 0 and no state.file keeps the random one in its place, decoys change on restart",
+                key
+            );
+        }
+        ClaimTable::default()
+    } else {
+        let path = Path::new(&config.state.file);
+        state_file::load(path).unwrap_or_else(|e| {
+            eprintln!("Cannot read state file {}: {}", path.display(), e);
+            process::exit(1);
+        })
+    };
+    // Seeds left at 0 would give every installation the same decoys
+    let seeds = stored.seeds.unwrap_or_else(Seeds::random);
+    let mut seeded = config.clone();
+    seeds.fill(&mut seeded);
+    let config = &seeded;
+    let load_config: LoadConfig = Box::new(move || {
+        let mut loaded = load_config()?;
+        seeds.fill(&mut loaded);
+        Ok(loaded)
+    });
+
+    let claim_policy = ClaimPolicy::new(&config.claims);
+    claim_policy.log_rules();
+    let claim_macs = ClaimMacs::new(config.mac, mac_address);
+
+    let metrics = Arc::new(Metrics::default());
+    if let Some(listen) = config.metrics.listen_addr() {
+        if let Err(e) = metrics::start_metrics_server(listen, metrics.clone()) {
+            eprintln!("Cannot serve metrics on {}: {}", listen, e);
+            process::exit(1);
+        }
+    }
+
+    let event_output = event_log::open_output(&config.events).unwrap_or_else(|e| {
+        eprintln!("Cannot open event log {}: {}", config.events.output, e);
+        process::exit(1);
+    });
+    let now = Instant::now();
+    let wall_now = event_log::wall_clock();
+    let events = EventLog::new(event_output, now, wall_now);
+
+    let port_policy = PortPolicy::new(&config.ports, config.tcp.persist);
+    port_policy.log_rules();
+
+    let passive_mode = PassiveMode::new(config.passive);
+    let mut arp_state = ArpState::new(
+        config.discovery,
+        claim_policy.clone(),
+        claim_macs,
+        passive_mode.clone(),
+        metrics.clone(),
+    );
+    let mut ndp_state = NdpState::new(
+        config.discovery,
+        claim_policy.clone(),
+        claim_macs,
+        passive_mode.clone(),
+        metrics.clone(),
+    );
+    let mut tcp_state = TcpState::new(
+        config.tcp,
+        config.icmp,
+        config.scanners,
+        &config.personality,
+        port_policy,
+        claim_macs,
+        passive_mode.clone(),
+        events,
+        metrics.clone(),
+        now,
+    );
+    let mut state_writer = StateWriter::new(&config.state, seeds, now);
+    if let Some(state_writer) = &mut state_writer {
+        let restored = state_file::restorable(
+            stored.claims,
+            wall_now,
+            config.tcp.claim_expiry(),
+            &claim_macs,
+            &claim_policy,
+        );
+        for claim in restored.iter().filter_map(StoredClaim::claim) {
+            info!("Tarpitting IP: {}, claimed before restart", claim);
+            arp_state.apply_claim_event(ClaimEvent::Claimed(claim));
+            ndp_state.apply_claim_event(ClaimEvent::Claimed(claim));
+        }
+        tcp_state.restore(restored, now, wall_now);
+        // Keeps random seeds from the start
+        state_writer.write(tcp_state.snapshot(now, wall_now));
+    }
+
+    // ARP, NDP and control socket claims are merged into one stream
+    let (claim_sender, rx) = mpsc::channel();
+    let (query_sender, queries) = mpsc::channel();
+    // Every thread holding policies gets those of a reloaded configuration
+    let (arp_policies, arp_policy_updates) = mpsc::channel();
+    let (ndp_policies, ndp_policy_updates) = mpsc::channel();
+    let (tcp_policies, tcp_policy_updates) = mpsc::channel();
+    let mut policy_senders = vec![arp_policies, ndp_policies, tcp_policies];
+    let control_socket = Path::new(&config.control.socket);
+    if !config.control.socket.is_empty() {
+        let (control_policies, policy_updates) = mpsc::channel();
+        policy_senders.push(control_policies);
+        let control = Control {
+            claim_sender: claim_sender.clone(),
+            claim_policy,
+            policy_updates,
+            queries: query_sender,
+            passive_mode: passive_mode.clone(),
+            set_log_filter: set_log_filter.clone(),
+            reload_request: reload_request.clone(),
+        };
+        if let Err(e) = control::start_control_server(control_socket, control) {
+            eprintln!(
+                "Cannot serve control socket {}: {}",
+                control_socket.display(),
+                e
+            );
+            process::exit(1);
+        }
+    }
+
+    // Each listener learns the claims the others made
+    let (arp_updates, arp_update_receiver) = mpsc::channel();
+    let (ndp_updates, ndp_update_receiver) = mpsc::channel();
+    let arp_thread = arp_listener::start_arp_handling(
+        open_io(claim_macs.needs_promiscuous()),
+        arp_state,
+        claim_sender.clone(),
+        arp_update_receiver,
+        arp_policy_updates,
+        metrics.clone(),
+        shutdown.clone(),
+    );
+    // Solicitations go to solicited-node multicast groups the interface has not joined
+    let ndp_thread = ndp_listener::start_ndp_handling(
+        open_io(true),
+        ndp_state,
+        claim_sender,
+        ndp_update_receiver,
+        ndp_policy_updates,
+        metrics.clone(),
+        shutdown.clone(),
+    );
 
     let (ip_sender, ip_receiver) = mpsc::channel();
-    tcp_listener::start_tcp_tarpitting(interface_name, ip_receiver, passive_mode);
+    let tcp_thread = tcp_listener::start_tcp_tarpitting(
+        open_io(claim_macs.needs_promiscuous()),
+        open_io(false),
+        tcp_state,
+        ip_receiver,
+        // Expired claims go straight to ARP and NDP, TCP has already dropped them
+        vec![arp_updates.clone(), ndp_updates.clone()],
+        queries,
+        tcp_policy_updates,
+        metrics.clone(),
+        shutdown.clone(),
+        config.shutdown.gratuitous_arp,
+        state_writer,
+    );
+
+    let mut reloader = Reloader::new(
+        load_config,
+        config.clone(),
+        policy_senders,
+        passive_mode,
+        set_log_filter,
+    );
+    // Ends on shutdown or once the ARP and NDP threads have exited, which
+    // they also do when their captures end
+    while !shutdown.is_requested() {
+        if reload_request.take() {
+            reloader.reload();
+        }
+        match rx.recv_timeout(packet_io::READ_TIMEOUT) {
+            Ok(event) => {
+                log_claim_event(&event);
+                let _ = arp_updates.send(event);
+                let _ = ndp_updates.send(event);
+                let _ = ip_sender.send(event);
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if arp_thread.is_finished() && ndp_thread.is_finished() {
+                    break;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    // The TCP thread follows them
+    shutdown.request();
+    for thread in [arp_thread, ndp_thread, tcp_thread] {
+        if thread.join().is_err() {
+            error!("Listener thread panicked");
+        }
+    }
+    if !config.control.socket.is_empty() {
+        let _ = fs::remove_file(control_socket);
+    }
+    info!("Exiting: {}", metrics.summary());
+}
+
+/// The seeds a random one replaces that change what decoys look like
+fn random_seeds(config: &Config) -> Vec<&'static str> {
+    let mut keys = Vec::new();
+    if config.mac.mode == MacMode::Virtual && config.mac.seed == 0 {
+        keys.push("mac.seed");
+    }
+    if !config.personality.os.is_empty() && config.personality.seed == 0 {
+        keys.push("personality.seed");
+    }
+    if !config.ports.profiles.is_empty() && config.ports.seed == 0 {
+        keys.push("ports.seed");
+    }
+    keys
+}
+
+pub fn log_claim_event(event: &ClaimEvent) {
+    match event {
+        ClaimEvent::Claimed(claim) => info!("Tarpitting IP: {}", claim),
+        ClaimEvent::Released(claim, _) => info!("Released IP: {}", claim),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_decoder;
+    use crate::packet_io::MemoryNetwork;
+    use crate::test_frames::{self, DECOY_IP, SCANNER_MAC};
+    use pnet_packet::arp::ArpPacket;
+    use pnet_packet::ethernet::EtherTypes;
+    use pnet_packet::tcp::TcpFlags;
+    use std::net::IpAddr;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    const ANTS_MAC: MacAddr = MacAddr(0x02, 0x00, 0x00, 0x00, 0x00, 0x02);
+
+    fn no_log_filter() -> SetLogFilter {
+        Arc::new(|_| Ok(()))
+    }
+
+    fn no_reload() -> LoadConfig {
+        Box::new(|| Err("no configuration file".to_string()))
+    }
+
+    #[test]
+    fn test_tarpit_end_to_end() {
+        let mut config = Config::default();
+        config.tcp.syn_ack_delay_ms = 0;
+        let (network, sent_frames) = MemoryNetwork::new();
+
+        // Attached up front so no injected frame is missed, and so that
+        // dropping the network ends every capture
+        let ports = Mutex::new((0..4).map(|_| network.attach()).collect::<Vec<_>>());
+        let tarpit = thread::spawn(move || {
+            run_tarpit(
+                &config,
+                ANTS_MAC,
+                Shutdown::default(),
+                ReloadRequest::default(),
+                no_log_filter(),
+                no_reload(),
+                |_| Box::new(ports.lock().unwrap().pop().unwrap()),
+            )
+        });
+
+        network.inject(&test_frames::arp_request());
+        network.inject(&test_frames::arp_request());
+        let arp_reply = sent_frames.recv_timeout(Duration::from_secs(5)).unwrap();
+        let arp_reply = packet_decoder::decode_ethernet(&arp_reply).unwrap();
+        assert_eq!(arp_reply.ethertype, EtherTypes::Arp);
+        assert_eq!(arp_reply.destination, SCANNER_MAC);
+
+        // The claim reaches the TCP thread asynchronously, retry until it has
+        let syn_ack = loop {
+            network.inject(&test_frames::tcp_syn(ANTS_MAC));
+            if let Ok(frame) = sent_frames.recv_timeout(Duration::from_millis(50)) {
+                break frame;
+            }
+        };
+        let syn_ack = packet_decoder::decode_tcp_segment(&syn_ack).unwrap();
+        assert_eq!(syn_ack.src_ip, IpAddr::V4(DECOY_IP));
+        assert_eq!(syn_ack.flags, TcpFlags::SYN | TcpFlags::ACK);
+
+        drop(network);
+        tarpit.join().unwrap();
+    }
+
+    #[test]
+    fn test_shutdown_withdraws_claims() {
+        let mut config = Config::default();
+        config.tcp.syn_ack_delay_ms = 0;
+        config.shutdown.gratuitous_arp = true;
+        let (network, sent_frames) = MemoryNetwork::new();
+        let shutdown = Shutdown::default();
+
+        let ports = Mutex::new((0..4).map(|_| network.attach()).collect::<Vec<_>>());
+        let tarpit_shutdown = shutdown.clone();
+        let tarpit = thread::spawn(move || {
+            run_tarpit(
+                &config,
+                ANTS_MAC,
+                tarpit_shutdown,
+                ReloadRequest::default(),
+                no_log_filter(),
+                no_reload(),
+                |_| Box::new(ports.lock().unwrap().pop().unwrap()),
+            )
+        });
+
+        network.inject(&test_frames::arp_request());
+        network.inject(&test_frames::arp_request());
+        sent_frames.recv_timeout(Duration::from_secs(5)).unwrap();
+        loop {
+            network.inject(&test_frames::tcp_syn(ANTS_MAC));
+            if sent_frames.recv_timeout(Duration::from_millis(50)).is_ok() {
+                break;
+            }
+        }
+
+        // Captures keep running, the listeners stop on their own
+        shutdown.request();
+        tarpit.join().unwrap();
 
-    for target_ip in rx {
-        info!("Tarpitting IP: {}", target_ip);
-        let _ = ip_sender.send(target_ip);
+        let withdrawal = sent_frames
+            .try_iter()
+            .find_map(|frame| {
+                let frame = packet_decoder::decode_ethernet(&frame)?;
+                let arp = ArpPacket::new(frame.payload)?;
+                (frame.ethertype == EtherTypes::Arp && arp.get_sender_hw_addr() == MacAddr::zero())
+                    .then(|| (frame.destination, arp.get_sender_proto_addr()))
+            })
+            .unwrap();
+        assert_eq!(withdrawal, (MacAddr::broadcast(), DECOY_IP));
     }
 }
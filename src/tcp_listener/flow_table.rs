@@ -0,0 +1,229 @@
+use crate::claims::Claim;
+use crate::event_log::Connection;
+use crate::packet_decoder::VlanTags;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Identifies a tarpitted connection from the scanner's point of view
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub vlan_tags: VlanTags,
+    pub scanner_ip: IpAddr,
+    pub scanner_port: u16,
+    pub decoy_ip: IpAddr,
+    pub decoy_port: u16,
+}
+
+impl FlowKey {
+    /// The claimed address this flow was sent to
+    pub fn decoy(&self) -> Claim {
+        Claim {
+            vlan_tags: self.vlan_tags,
+            ip: self.decoy_ip,
+        }
+    }
+
+    pub fn connection(&self) -> Connection {
+        Connection {
+            scanner_ip: self.scanner_ip,
+            scanner_port: self.scanner_port,
+            decoy_ip: self.decoy_ip,
+            decoy_port: self.decoy_port,
+            vlan: self.vlan_tags,
+        }
+    }
+}
+
+/// State of a single connection held open in persist mode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Flow {
+    /// Next sequence number ANTS sends (our ISN + 1)
+    pub local_seq: u32,
+    /// Next sequence number expected from the scanner, i.e. what we ACK
+    pub remote_seq: u32,
+    pub created: Instant,
+    pub last_seen: Instant,
+}
+
+/// Connections held open with zero-window ACKs, expired after a fixed lifetime
+pub struct FlowTable {
+    flows: HashMap<FlowKey, Flow>,
+    lifetime: Duration,
+    max_flows: usize,
+}
+
+impl FlowTable {
+    pub fn new(lifetime: Duration, max_flows: usize) -> Self {
+        FlowTable {
+            flows: HashMap::new(),
+            lifetime,
+            max_flows,
+        }
+    }
+
+    /// Flows already held keep their creation time, a smaller max_flows
+    /// refuses new flows until enough have ended
+    pub fn set_limits(&mut self, lifetime: Duration, max_flows: usize) {
+        self.lifetime = lifetime;
+        self.max_flows = max_flows;
+    }
+
+    /// Starts tracking a flow after a SYN/ACK has been sent for it,
+    /// returns false if the table is full
+    pub fn insert(&mut self, key: FlowKey, local_isn: u32, remote_isn: u32, now: Instant) -> bool {
+        if self.flows.len() >= self.max_flows && !self.flows.contains_key(&key) {
+            return false;
+        }
+        self.flows.insert(
+            key,
+            Flow {
+                local_seq: local_isn.wrapping_add(1),
+                remote_seq: remote_isn.wrapping_add(1),
+                created: now,
+                last_seen: now,
+            },
+        );
+        true
+    }
+
+    /// Looks up a flow and marks it as seen, returns None for unknown or expired flows
+    pub fn touch(&mut self, key: &FlowKey, now: Instant) -> Option<Flow> {
+        let lifetime = self.lifetime;
+        let flow = self.flows.get_mut(key)?;
+        if now.duration_since(flow.created) >= lifetime {
+            self.flows.remove(key);
+            return None;
+        }
+        flow.last_seen = now;
+        Some(*flow)
+    }
+
+    pub fn get(&self, key: &FlowKey) -> Option<&Flow> {
+        self.flows.get(key)
+    }
+
+    pub fn remove(&mut self, key: &FlowKey) -> Option<Flow> {
+        self.flows.remove(key)
+    }
+
+    /// Drops every flow to a decoy, returns the flows that were held
+    pub fn remove_decoy(&mut self, decoy: &Claim) -> Vec<(FlowKey, Flow)> {
+        self.remove_where(|key, _| key.decoy() == *decoy)
+    }
+
+    /// Drops flows that have been held longer than the configured lifetime
+    pub fn expire(&mut self, now: Instant) -> Vec<(FlowKey, Flow)> {
+        let lifetime = self.lifetime;
+        self.remove_where(|_, flow| now.duration_since(flow.created) >= lifetime)
+    }
+
+    fn remove_where(&mut self, remove: impl Fn(&FlowKey, &Flow) -> bool) -> Vec<(FlowKey, Flow)> {
+        let mut removed = Vec::new();
+        self.flows.retain(|key, flow| {
+            if remove(key, flow) {
+                removed.push((*key, *flow));
+                return false;
+            }
+            true
+        });
+        removed
+    }
+
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&FlowKey, &Flow)> {
+        self.flows.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn flow_key() -> FlowKey {
+        FlowKey {
+            vlan_tags: VlanTags::default(),
+            scanner_ip: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 100)),
+            scanner_port: 40000,
+            decoy_ip: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)),
+            decoy_port: 22,
+        }
+    }
+
+    #[test]
+    fn test_insert_sets_next_sequence_numbers() {
+        let mut table = FlowTable::new(Duration::from_secs(60), 10);
+        let now = Instant::now();
+
+        table.insert(flow_key(), 1, u32::MAX, now);
+
+        let flow = table.touch(&flow_key(), now).unwrap();
+        assert_eq!(flow.local_seq, 2);
+        assert_eq!(flow.remote_seq, 0);
+    }
+
+    #[test]
+    fn test_insert_refused_when_full() {
+        let mut table = FlowTable::new(Duration::from_secs(60), 1);
+        let now = Instant::now();
+        let other = FlowKey {
+            scanner_port: 40001,
+            ..flow_key()
+        };
+
+        assert!(table.insert(flow_key(), 1, 42, now));
+        assert!(!table.insert(other, 1, 42, now));
+        assert!(table.insert(flow_key(), 1, 43, now));
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn test_touch_unknown_flow() {
+        let mut table = FlowTable::new(Duration::from_secs(60), 10);
+
+        assert!(table.touch(&flow_key(), Instant::now()).is_none());
+    }
+
+    #[test]
+    fn test_touch_expired_flow_removes_it() {
+        let mut table = FlowTable::new(Duration::from_secs(60), 10);
+        let created = Instant::now();
+        table.insert(flow_key(), 1, 42, created);
+
+        let later = created + Duration::from_secs(61);
+
+        assert!(table.touch(&flow_key(), later).is_none());
+        assert_eq!(table.len(), 0);
+    }
+
+    #[test]
+    fn test_expire_keeps_young_flows() {
+        let mut table = FlowTable::new(Duration::from_secs(60), 10);
+        let created = Instant::now();
+        table.insert(flow_key(), 1, 42, created);
+
+        assert!(table.expire(created + Duration::from_secs(30)).is_empty());
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.expire(created + Duration::from_secs(60)).len(), 1);
+        assert_eq!(table.len(), 0);
+    }
+
+    #[test]
+    fn test_remove_decoy() {
+        let mut table = FlowTable::new(Duration::from_secs(60), 10);
+        let now = Instant::now();
+        let other_decoy = FlowKey {
+            decoy_ip: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 3)),
+            ..flow_key()
+        };
+        table.insert(flow_key(), 1, 42, now);
+        table.insert(other_decoy, 1, 42, now);
+
+        assert_eq!(table.remove_decoy(&flow_key().decoy()).len(), 1);
+        assert!(table.touch(&other_decoy, now).is_some());
+    }
+}
@@ -1,253 +1,1219 @@
-use pnet::datalink::{self, Channel::Ethernet};
+mod flow_table;
+mod personality;
+mod syn_cookie;
+mod transmitter;
+
+use crate::arp_listener;
+use crate::claims::{Claim, ClaimEvent, ClaimMacs};
+use crate::config::{IcmpConfig, PersonalityConfig, PortAction, ScannersConfig, TcpConfig};
+use crate::control::{HeldFlow, PassiveMode, Query};
+use crate::event_log::{self, CloseReason, Event, EventLog, ReleaseReason, ReplyKind};
+use crate::icmp_responder::{self, IcmpResponder};
+use crate::metrics::{self, Capture, Metrics};
+use crate::packet_decoder::{self, TcpSegment};
+use crate::packet_io::{self, PacketIo};
+use crate::port_policy::PortPolicy;
+use crate::rate_limiter::{self, RateLimiter};
+use crate::reload::Policies;
+use crate::scan_classifier::{Classification, ScanClassifier};
+use crate::shutdown::Shutdown;
+use crate::state_file::{StateWriter, StoredClaim};
+use crate::tarpitter;
+use flow_table::{Flow, FlowKey, FlowTable};
+pub use personality::IpFields;
+use personality::{Personalities, SynAckFields};
+use pnet::packet::ip::IpNextHeaderProtocols;
 use pnet::packet::ipv4::MutableIpv4Packet;
-use pnet::packet::tcp::{MutableTcpPacket, TcpFlags};
+use pnet::packet::ipv6::MutableIpv6Packet;
+use pnet::packet::tcp::{self, MutableTcpPacket, TcpFlags};
 use pnet::packet::Packet;
-use pnet::util::{checksum, ipv4_checksum};
+use pnet::util::checksum;
 use pnet_base::MacAddr;
 use pnet_packet::ethernet::{EtherTypes, MutableEthernetPacket};
 use pnet_packet::ipv4::Ipv4Flags;
-use std::collections::HashMap;
-use std::net::Ipv4Addr;
-use std::sync::{mpsc, Arc, Mutex};
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::net::IpAddr;
+use std::sync::{mpsc, Arc};
 use std::thread;
 use std::time::{Duration, Instant};
-use tracing::{debug, info};
+use syn_cookie::SynCookies;
+use tracing::{debug, error, info};
+
+/// How often claimed IPs and held flows are checked for expiry
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Segments with SYN set and ACK clear, the kernel can only check this on IPv4
+const SYN_ONLY_FILTER: &str = "tcp[tcpflags] & (tcp-syn|tcp-ack) == tcp-syn";
+
+/// Above this many claimed IPs the capture filter no longer lists them,
+/// classic BPF programs are limited to 4096 instructions
+const MAX_FILTER_HOSTS: usize = 100;
+
+/// When an IP was claimed and when it was last claimed again or answered for
+#[derive(Clone, Copy, Debug)]
+struct ClaimTimes {
+    first_seen: Instant,
+    last_seen: Instant,
+}
+
+type Claims = HashMap<Claim, ClaimTimes>;
+
+/// A reply to send after delay, counted and logged by TcpState::queue_reply
+/// once the transmitter has taken it
+#[derive(Debug, PartialEq)]
+pub struct Reply {
+    pub frame: Vec<u8>,
+    pub delay: Duration,
+    kind: ReplyKind,
+    /// None when no events are written
+    event: Option<Event>,
+}
+
+/// TCP side of ANTS without any I/O: answers SYNs to claimed IPs as the
+/// port policy says and holds persisted flows open. Pings to claimed IPs
+/// are answered here too since this is where the tarpit set lives.
+pub struct TcpState {
+    ips_to_tarpit: Claims,
+    held_flows: FlowTable,
+    rate_limiter: RateLimiter,
+    port_policy: PortPolicy,
+    personalities: Personalities,
+    syn_cookies: SynCookies,
+    /// None when ICMP is disabled
+    icmp_responder: Option<IcmpResponder>,
+    /// None when scanners are not classified
+    scan_classifier: Option<ScanClassifier>,
+    last_sweep: Instant,
+    tcp_config: TcpConfig,
+    claim_macs: ClaimMacs,
+    passive_mode: PassiveMode,
+    events: EventLog,
+    metrics: Arc<Metrics>,
+    /// Claimed IPs changed since the capture filter was last built
+    filter_stale: bool,
+    /// Claims expired since take_expired_claims was last called
+    expired_claims: Vec<Claim>,
+    /// Number of claims when a capture filter last failed to install, the
+    /// filter matches any destination until there are fewer
+    filter_failed_at: Option<usize>,
+}
+
+impl TcpState {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        tcp_config: TcpConfig,
+        icmp_config: IcmpConfig,
+        scanners_config: ScannersConfig,
+        personality_config: &PersonalityConfig,
+        port_policy: PortPolicy,
+        claim_macs: ClaimMacs,
+        passive_mode: PassiveMode,
+        events: EventLog,
+        metrics: Arc<Metrics>,
+        now: Instant,
+    ) -> Self {
+        TcpState {
+            ips_to_tarpit: HashMap::new(),
+            held_flows: FlowTable::new(tcp_config.flow_lifetime(), tcp_config.max_flows),
+            rate_limiter: RateLimiter::new(
+                tcp_config.rate_per_source,
+                rate_limiter::DEFAULT_MAX_SOURCES,
+            ),
+            port_policy,
+            personalities: Personalities::new(personality_config, tcp_config.window, now),
+            syn_cookies: SynCookies::new(now),
+            icmp_responder: icmp_config.enabled.then(|| {
+                IcmpResponder::new(
+                    icmp_config,
+                    claim_macs,
+                    passive_mode.clone(),
+                    metrics.clone(),
+                )
+            }),
+            scan_classifier: scanners_config
+                .enabled
+                .then(|| ScanClassifier::new(scanners_config)),
+            last_sweep: now,
+            tcp_config,
+            claim_macs,
+            passive_mode,
+            events,
+            metrics,
+            filter_stale: true,
+            expired_claims: Vec::new(),
+            filter_failed_at: None,
+        }
+    }
+
+    pub fn apply_claim_event(&mut self, event: ClaimEvent, now: Instant) {
+        let changes_claims = match &event {
+            ClaimEvent::Claimed(claim) => !self.ips_to_tarpit.contains_key(claim),
+            ClaimEvent::Released(claim, _) => self.ips_to_tarpit.contains_key(claim),
+        };
+        self.filter_stale |= changes_claims;
+        let released_flows =
+            apply_claim_event(event, &mut self.ips_to_tarpit, &mut self.held_flows, now);
+
+        if changes_claims {
+            self.events.record(
+                now,
+                match event {
+                    ClaimEvent::Claimed(claim) => Event::IpClaimed {
+                        ip: claim.ip,
+                        vlan: claim.vlan_tags,
+                    },
+                    ClaimEvent::Released(claim, reason) => Event::IpReleased {
+                        ip: claim.ip,
+                        vlan: claim.vlan_tags,
+                        reason,
+                    },
+                },
+            );
+        }
+        self.record_closed_flows(released_flows, CloseReason::Released, now);
+        self.update_gauges();
+    }
+
+    /// Claims kept from an earlier run, wall_now is the time since the
+    /// Unix epoch at now
+    pub fn restore(&mut self, claims: Vec<StoredClaim>, now: Instant, wall_now: Duration) {
+        let instant_at = |secs| now.checked_sub(wall_now.saturating_sub(Duration::from_secs(secs)));
+        for stored in claims {
+            let Some(claim) = stored.claim() else {
+                continue;
+            };
+            let claim_times = ClaimTimes {
+                first_seen: instant_at(stored.first_seen).unwrap_or(now),
+                last_seen: instant_at(stored.last_seen).unwrap_or(now),
+            };
+            self.ips_to_tarpit.insert(claim, claim_times);
+            self.events.record(
+                now,
+                Event::IpClaimed {
+                    ip: claim.ip,
+                    vlan: claim.vlan_tags,
+                },
+            );
+        }
+        self.filter_stale = true;
+        self.update_gauges();
+    }
+
+    /// The claims to keep across a restart, wall_now is the time since the
+    /// Unix epoch at now
+    pub fn snapshot(&self, now: Instant, wall_now: Duration) -> Vec<StoredClaim> {
+        let secs_at = |instant| {
+            wall_now
+                .saturating_sub(now.duration_since(instant))
+                .as_secs()
+        };
+        self.ips_to_tarpit
+            .iter()
+            .map(|(claim, claim_times)| StoredClaim {
+                ip: claim.ip,
+                vlan: claim.vlan_tags.as_slice().to_vec(),
+                mac: self.claim_macs.mac_for(claim.ip),
+                first_seen: secs_at(claim_times.first_seen),
+                last_seen: secs_at(claim_times.last_seen),
+            })
+            .collect()
+    }
+
+    /// Swaps in the policies of a reloaded configuration. Claims, held
+    /// flows and rate limits already spent are kept, the new limits apply
+    /// from the next frame.
+    pub fn apply_policies(&mut self, policies: &Policies) {
+        let tcp_config = policies.tcp;
+        self.held_flows
+            .set_limits(tcp_config.flow_lifetime(), tcp_config.max_flows);
+        self.rate_limiter.set_rate(tcp_config.rate_per_source);
+        self.personalities
+            .set_config(&policies.personality, tcp_config.window);
+        self.port_policy = policies.port_policy.clone();
+        match (&mut self.icmp_responder, policies.icmp.enabled) {
+            (Some(icmp_responder), true) => icmp_responder.set_config(policies.icmp),
+            (icmp_responder, enabled) => {
+                *icmp_responder = enabled.then(|| {
+                    IcmpResponder::new(
+                        policies.icmp,
+                        self.claim_macs,
+                        self.passive_mode.clone(),
+                        self.metrics.clone(),
+                    )
+                })
+            }
+        }
+        match (&mut self.scan_classifier, policies.scanners.enabled) {
+            (Some(scan_classifier), true) => scan_classifier.set_config(policies.scanners),
+            (scan_classifier, enabled) => {
+                *scan_classifier = enabled.then(|| ScanClassifier::new(policies.scanners))
+            }
+        }
+        self.tcp_config = tcp_config;
+        // Persist mode and ICMP decide what the filter passes
+        self.filter_stale = true;
+    }
+
+    /// The flows held open in persist mode
+    pub fn held_flows(&self, now: Instant) -> Vec<HeldFlow> {
+        self.held_flows
+            .iter()
+            .map(|(key, flow)| HeldFlow {
+                connection: key.connection(),
+                held_secs: now.duration_since(flow.created).as_secs(),
+                idle_secs: now.duration_since(flow.last_seen).as_secs(),
+            })
+            .collect()
+    }
+
+    /// Answers a query from the control socket, a client that gave up
+    /// waiting is ignored
+    pub fn answer(&self, query: Query, now: Instant, wall_now: Duration) {
+        match query {
+            Query::Claims(answer) => {
+                let _ = answer.send(self.snapshot(now, wall_now));
+            }
+            Query::Flows(answer) => {
+                let _ = answer.send(self.held_flows(now));
+            }
+        }
+    }
+
+    /// Longest a reply is held back before it is sent
+    pub fn reply_delay(&self) -> Duration {
+        self.tcp_config.syn_ack_delay()
+    }
+
+    /// Sweeps expired claims and flows once SWEEP_INTERVAL has passed,
+    /// also called while no frames arrive
+    pub fn sweep_if_due(&mut self, now: Instant) {
+        if now.duration_since(self.last_sweep) >= SWEEP_INTERVAL {
+            self.sweep(now);
+        }
+    }
+
+    /// Capture filter for the current claims, None if it has not changed
+    /// since the last call
+    pub fn take_capture_filter(&mut self) -> Option<String> {
+        if !self.filter_stale {
+            return None;
+        }
+        self.filter_stale = false;
+        let persist = self.port_policy.holds_flows();
+        let icmp = self.icmp_responder.is_some();
+        if self.filter_failed() {
+            return Some(any_destination_filter(persist, icmp));
+        }
+        Some(capture_filter(self.ips_to_tarpit.keys(), persist, icmp))
+    }
+
+    /// Called when the filter from take_capture_filter could not be
+    /// installed, the next one matches any destination and claims are
+    /// only checked here
+    pub fn capture_filter_failed(&mut self) {
+        // Tried already, retrying would fail the same way
+        if self.filter_failed() {
+            return;
+        }
+        self.filter_failed_at = Some(self.ips_to_tarpit.len());
+        self.filter_stale = true;
+    }
+
+    fn filter_failed(&self) -> bool {
+        self.filter_failed_at
+            .is_some_and(|failed_at| self.ips_to_tarpit.len() >= failed_at)
+    }
+
+    /// Runs one received frame through the TCP logic, returns the reply
+    /// to pass to queue_reply
+    pub fn handle_frame(&mut self, frame: &[u8], now: Instant) -> Option<Reply> {
+        self.sweep_if_due(now);
+
+        if let Some(icmp_responder) = &mut self.icmp_responder {
+            let echo_reply = icmp_responder.handle_frame(
+                frame,
+                |claim| self.ips_to_tarpit.contains_key(claim),
+                |decoy_ip| self.personalities.ip_fields(decoy_ip),
+                now,
+            );
+            if let Some(echo_reply) = echo_reply {
+                let event = self.echo_reply_event(&echo_reply);
+                return Some(Reply {
+                    frame: echo_reply,
+                    delay: Duration::ZERO,
+                    kind: ReplyKind::EchoReply,
+                    event,
+                });
+            }
+        }
+
+        let segment = packet_decoder::decode_tcp_segment(frame)?;
+        if validate_tcp_syn_packet(&segment) {
+            let key = flow_key(&segment);
+            let claim_times = self.ips_to_tarpit.get_mut(&key.decoy())?;
+            self.metrics.count_syn(key.decoy_port);
+            if let Some(scan_classifier) = &mut self.scan_classifier {
+                if let Some(classification) = scan_classifier.observe(&segment, now) {
+                    record_classification(&mut self.events, &self.metrics, classification, now);
+                }
+            }
+            let action = self
+                .port_policy
+                .action(key.decoy_ip, key.scanner_ip, key.decoy_port);
+            self.events.record(
+                now,
+                Event::SynReceived {
+                    connection: key.connection(),
+                    ttl: segment.ttl,
+                    window: segment.window,
+                    options: segment.options,
+                    action,
+                },
+            );
+            if action == PortAction::Drop {
+                claim_times.last_seen = now;
+                debug!("Dropping SYN to {}:{}", key.decoy_ip, key.decoy_port);
+                return None;
+            }
+            if !self.rate_limiter.allow(key.scanner_ip, now) {
+                debug!("Rate limit exceeded for {}", key.scanner_ip);
+                return None;
+            }
+            if self.passive_mode.is_on() {
+                Metrics::count(&self.metrics.passive_suppressed);
+            }
+            let src_mac = self.claim_macs.mac_for(key.decoy_ip);
+            let (reply, kind) = if action == PortAction::Reset {
+                let ip = self.personalities.ip_fields(key.decoy_ip);
+                let rst_ack = handle_closed_port(&segment, src_mac, ip, self.passive_mode.is_on())?;
+                (rst_ack, ReplyKind::RstAck)
+            } else {
+                // Persisted flows are only held once the ACK returns the cookie
+                let tick_ns = self.personalities.isn_tick_ns(key.decoy_ip);
+                let isn = self.syn_cookies.isn(&key, tick_ns, now);
+                let fields = self.personalities.syn_ack(&segment, isn, now);
+                let syn_ack = handle_packet(&segment, src_mac, self.passive_mode.is_on(), &fields)?;
+                (syn_ack, ReplyKind::SynAck)
+            };
+
+            claim_times.last_seen = now;
+            debug!("Response queued for IP: {}", key.decoy_ip);
+            // Closed ports answer as slowly as open ones, a faster RST would give the decoy away
+            let delay = self.tcp_config.syn_ack_delay();
+            Some(Reply {
+                frame: reply,
+                delay,
+                kind,
+                event: Some(Event::reply_sent(kind, key.connection(), delay)),
+            })
+        } else if self.port_policy.holds_flows() && validate_tcp_flow_packet(&segment) {
+            // Checked before any state changes, as for SYNs
+            if !self.rate_limiter.allow(segment.src_ip, now) {
+                return None;
+            }
+            if segment.flags & TcpFlags::RST != 0 {
+                self.reset_flow(&segment, now);
+                return None;
+            }
+            self.complete_handshake(&segment, now);
+            let ack = handle_held_flow(
+                &segment,
+                &mut self.held_flows,
+                &mut self.ips_to_tarpit,
+                self.claim_macs.mac_for(segment.dst_ip),
+                self.personalities.ip_fields(segment.dst_ip),
+                self.passive_mode.is_on(),
+                now,
+            )?;
+            let connection = flow_key(&segment).connection();
+            Some(Reply {
+                frame: ack,
+                delay: Duration::ZERO,
+                kind: ReplyKind::ZeroWindowAck,
+                event: Some(Event::reply_sent(
+                    ReplyKind::ZeroWindowAck,
+                    connection,
+                    Duration::ZERO,
+                )),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Starts holding a flow once the scanner's ACK returns the SYN cookie
+    /// sent from a persisted port
+    fn complete_handshake(&mut self, segment: &TcpSegment, now: Instant) {
+        let key = flow_key(segment);
+        let local_isn = segment.acknowledgement.wrapping_sub(1);
+        if self.held_flows.get(&key).is_some()
+            || !self.ips_to_tarpit.contains_key(&key.decoy())
+            || self
+                .port_policy
+                .action(key.decoy_ip, key.scanner_ip, key.decoy_port)
+                != PortAction::Persist
+            || !self.syn_cookies.validate(
+                &key,
+                local_isn,
+                self.personalities.isn_tick_ns(key.decoy_ip),
+                now,
+            )
+        {
+            return;
+        }
+        let remote_isn = segment.sequence.wrapping_sub(1);
+        if !self.held_flows.insert(key, local_isn, remote_isn, now) {
+            debug!("Flow table full, not holding flow to {}", key.decoy_ip);
+            return;
+        }
+        self.events.record(
+            now,
+            Event::FlowHeld {
+                connection: key.connection(),
+            },
+        );
+        self.update_gauges();
+    }
+
+    fn reset_flow(&mut self, segment: &TcpSegment, now: Instant) {
+        let key = flow_key(segment);
+        if let Some(flow) = self.held_flows.remove(&key) {
+            debug!(
+                "Held flow {}:{} -> {}:{} reset by scanner",
+                key.scanner_ip, key.scanner_port, key.decoy_ip, key.decoy_port
+            );
+            self.record_closed_flows(vec![(key, flow)], CloseReason::Reset, now);
+            self.update_gauges();
+        }
+    }
+
+    fn record_closed_flows(
+        &mut self,
+        flows: Vec<(FlowKey, Flow)>,
+        reason: CloseReason,
+        now: Instant,
+    ) {
+        for (key, flow) in flows {
+            self.events.record(
+                now,
+                Event::FlowClosed {
+                    connection: key.connection(),
+                    reason,
+                    held_secs: now.duration_since(flow.created).as_secs(),
+                },
+            );
+        }
+    }
+
+    fn echo_reply_event(&self, echo_reply: &[u8]) -> Option<Event> {
+        if !self.events.is_enabled() {
+            return None;
+        }
+        let frame = packet_decoder::decode_ethernet(echo_reply)?;
+        let datagram = packet_decoder::decode_ip_datagram(&frame)?;
+        Some(Event::ReplySent {
+            kind: ReplyKind::EchoReply,
+            scanner_ip: datagram.dst_ip,
+            scanner_port: None,
+            decoy_ip: datagram.src_ip,
+            decoy_port: None,
+            vlan: frame.vlan_tags,
+            delay_ms: 0,
+        })
+    }
+
+    /// Passes reply to schedule, it is counted and logged as sent only if
+    /// schedule takes it
+    pub fn queue_reply(
+        &mut self,
+        reply: Reply,
+        now: Instant,
+        schedule: impl FnOnce(Vec<u8>, Duration) -> bool,
+    ) {
+        if !schedule(reply.frame, reply.delay) {
+            Metrics::count(&self.metrics.transmit_dropped);
+            return;
+        }
+        if reply.kind == ReplyKind::SynAck {
+            Metrics::count(&self.metrics.syn_acks);
+        }
+        if let Some(event) = reply.event {
+            self.events.record(now, event);
+        }
+    }
+
+    /// Forgets claims nobody has connected to lately and expired flows
+    fn sweep(&mut self, now: Instant) {
+        let claim_expiry = self.tcp_config.claim_expiry();
+        let mut expired_claims = Vec::new();
+        self.ips_to_tarpit.retain(|claim, claim_times| {
+            let keep = now.duration_since(claim_times.last_seen) < claim_expiry;
+            if !keep {
+                expired_claims.push(*claim);
+            }
+            keep
+        });
+        self.filter_stale |= !expired_claims.is_empty();
+        for claim in expired_claims {
+            self.expired_claims.push(claim);
+            self.events.record(
+                now,
+                Event::IpReleased {
+                    ip: claim.ip,
+                    vlan: claim.vlan_tags,
+                    reason: ReleaseReason::Expired,
+                },
+            );
+        }
+
+        let expired = self.held_flows.expire(now);
+        if !expired.is_empty() {
+            debug!(
+                "Released {} expired flows, {} still held",
+                expired.len(),
+                self.held_flows.len()
+            );
+        }
+        self.record_closed_flows(expired, CloseReason::Expired, now);
+        if let Some(scan_classifier) = &mut self.scan_classifier {
+            for single_probe in scan_classifier.expire(now) {
+                record_classification(&mut self.events, &self.metrics, single_probe, now);
+            }
+        }
+        self.update_gauges();
+        self.events.flush();
+        self.last_sweep = now;
+    }
+
+    /// Releases every claim and the flows held on it, returns the
+    /// gratuitous ARPs withdrawing IPv4 claims if gratuitous_arp is set
+    pub fn shut_down(&mut self, gratuitous_arp: bool, now: Instant) -> Vec<Vec<u8>> {
+        let mut withdrawals = Vec::new();
+        for (claim, _) in std::mem::take(&mut self.ips_to_tarpit) {
+            let flows = self.held_flows.remove_decoy(&claim);
+            self.events.record(
+                now,
+                Event::IpReleased {
+                    ip: claim.ip,
+                    vlan: claim.vlan_tags,
+                    reason: ReleaseReason::Shutdown,
+                },
+            );
+            self.record_closed_flows(flows, CloseReason::Shutdown, now);
+            if let IpAddr::V4(ip) = claim.ip {
+                if gratuitous_arp && !self.passive_mode.is_on() {
+                    let mac_address = self.claim_macs.mac_for(claim.ip);
+                    withdrawals.push(arp_listener::create_claim_withdrawal(
+                        ip,
+                        claim.vlan_tags,
+                        mac_address,
+                    ));
+                }
+            }
+        }
+        self.update_gauges();
+        self.events.flush();
+        withdrawals
+    }
+
+    /// Releases of the claims expired since the last call, the ARP and NDP
+    /// listeners would otherwise keep answering them at once
+    pub fn take_expired_claims(&mut self) -> Vec<ClaimEvent> {
+        self.expired_claims
+            .drain(..)
+            .map(|claim| ClaimEvent::Released(claim, ReleaseReason::Expired))
+            .collect()
+    }
+
+    /// Writes out events still buffered, they are otherwise written on the next sweep
+    pub fn flush_events(&mut self) {
+        self.events.flush();
+    }
+
+    fn update_gauges(&self) {
+        Metrics::set(&self.metrics.claimed_ips, self.ips_to_tarpit.len());
+        Metrics::set(&self.metrics.held_flows, self.held_flows.len());
+    }
+}
 
 // start listening to tcp and respond to TCP handshakes in the given interface
+// in persist mode handshaked connections are held open with zero-window ACKs
+// until the configured flow lifetime has passed
+#[allow(clippy::too_many_arguments)]
 pub fn start_tcp_tarpitting(
-    interface_name: &str,
-    ip_receiver: mpsc::Receiver<Ipv4Addr>,
-    passive_mode: bool,
-) {
-    let interface_name = interface_name.to_string();
+    mut packet_io: Box<dyn PacketIo>,
+    transmit_io: Box<dyn PacketIo>,
+    mut tcp_state: TcpState,
+    ip_receiver: mpsc::Receiver<ClaimEvent>,
+    expiry_senders: Vec<mpsc::Sender<ClaimEvent>>,
+    queries: mpsc::Receiver<Query>,
+    policy_updates: mpsc::Receiver<Arc<Policies>>,
+    metrics: Arc<Metrics>,
+    shutdown: Shutdown,
+    gratuitous_arp: bool,
+    mut state_writer: Option<StateWriter>,
+) -> thread::JoinHandle<()> {
     thread::spawn(move || {
-        let device = pcap::Device::list()
-            .unwrap()
-            .into_iter()
-            .find(|dev| dev.name == interface_name)
-            .unwrap_or_else(|| {
-                eprintln!("Could not find device {}", interface_name);
-                std::process::exit(1);
-            });
-
-        let mut cap = pcap::Capture::from_device(device)
-            .unwrap()
-            .immediate_mode(true)
-            .open()
-            .unwrap();
+        let (transmitter, transmit_thread) =
+            transmitter::start_transmitter(transmit_io, transmitter::DEFAULT_QUEUE_CAPACITY);
 
-        info!(
-            "Listening for incoming TCP SYN packets on interface {}...",
-            interface_name
-        );
+        info!("Listening for incoming TCP SYN packets...");
 
-        let ips_to_tarpit: Arc<Mutex<HashMap<Ipv4Addr, Instant>>> =
-            Arc::new(Mutex::new(HashMap::new()));
+        let mut last_drops_poll = Instant::now();
+        loop {
+            if shutdown.is_requested() {
+                debug!("Shutting down, exiting TCP tarpitting thread.");
+                break;
+            }
+            metrics::poll_capture_drops(
+                &metrics,
+                Capture::Tcp,
+                packet_io.as_mut(),
+                &mut last_drops_poll,
+                Instant::now(),
+            );
+            let frame = match packet_io.recv() {
+                Ok(Some(frame)) => Some(frame),
+                Ok(None) => {
+                    debug!("Capture ended, exiting TCP tarpitting thread.");
+                    break;
+                }
+                // Claim changes and expiry are handled even when no frames pass the filter
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => None,
+                Err(e) => {
+                    error!("An error occurred while reading: {}", e);
+                    continue;
+                }
+            };
+            let now = Instant::now();
 
-        while let Ok(packet) = cap.next_packet() {
-            while let Ok(ip) = ip_receiver.try_recv() {
-                let mut ips = ips_to_tarpit.lock().unwrap();
-                ips.insert(ip, Instant::now());
+            while let Ok(event) = ip_receiver.try_recv() {
+                tcp_state.apply_claim_event(event, now);
+            }
+            while let Ok(policies) = policy_updates.try_recv() {
+                tcp_state.apply_policies(&policies);
+            }
+            while let Ok(query) = queries.try_recv() {
+                tcp_state.answer(query, now, event_log::wall_clock());
             }
 
-            if validate_tcp_syn_packet(packet.data) {
-                let dst_ip = Ipv4Addr::new(
-                    packet.data[30],
-                    packet.data[31],
-                    packet.data[32],
-                    packet.data[33],
-                );
-
-                {
-                    let ips = ips_to_tarpit.lock().unwrap();
-                    if !ips.contains_key(&dst_ip) {
-                        continue;
+            match frame {
+                Some(frame) => {
+                    if let Some(reply) = tcp_state.handle_frame(frame.data, now) {
+                        tcp_state.queue_reply(reply, now, |reply, delay| {
+                            transmitter.schedule(reply, delay)
+                        });
                     }
                 }
+                None => tcp_state.sweep_if_due(now),
+            }
+            for event in tcp_state.take_expired_claims() {
+                tarpitter::log_claim_event(&event);
+                for sender in &expiry_senders {
+                    let _ = sender.send(event);
+                }
+            }
+
+            if let Some(filter) = tcp_state.take_capture_filter() {
+                debug!("Capture filter: {}", filter);
+                if let Err(e) = packet_io.set_filter(&filter) {
+                    error!("Failed to install TCP capture filter: {}", e);
+                    tcp_state.capture_filter_failed();
+                }
+            }
 
-                let packet_data = packet.data.to_vec();
-                let interface_name = interface_name.to_string();
-                let passive_mode = passive_mode;
-                let ips_to_tarpit_clone = Arc::clone(&ips_to_tarpit);
-
-                thread::spawn(move || {
-                    if let Some(response_ip) =
-                        handle_packet(&packet_data, &interface_name, passive_mode)
-                    {
-                        if response_ip == dst_ip {
-                            let mut ips = ips_to_tarpit_clone.lock().unwrap();
-                            ips.insert(dst_ip, Instant::now());
-                            debug!("Response sent for IP: {}", dst_ip);
-                        }
-                    }
-                });
+            if let Some(state_writer) = &mut state_writer {
+                state_writer.write_if_due(now, || tcp_state.snapshot(now, event_log::wall_clock()));
             }
+        }
 
-            {
-                let mut ips = ips_to_tarpit.lock().unwrap();
-                ips.retain(|_, &mut last_time| last_time.elapsed() < Duration::from_secs(120));
+        let now = Instant::now();
+        if let Some(state_writer) = &mut state_writer {
+            state_writer.write(tcp_state.snapshot(now, event_log::wall_clock()));
+        }
+        // Withdrawals go out after every reply already queued, none is delayed longer
+        for withdrawal in tcp_state.shut_down(gratuitous_arp, now) {
+            if !transmitter.schedule(withdrawal, tcp_state.reply_delay()) {
+                Metrics::count(&metrics.transmit_dropped);
             }
         }
-    });
+        drop(transmitter);
+        if transmit_thread.join().is_err() {
+            error!("Transmit thread panicked");
+        }
+    })
 }
 
-fn validate_tcp_syn_packet(packet_data: &[u8]) -> bool {
-    // Minimum packet size: Ethernet (14 bytes) + IPv4 (20 bytes) + TCP (20 bytes)
-    if packet_data.len() < 54 {
-        return false;
+fn record_classification(
+    events: &mut EventLog,
+    metrics: &Metrics,
+    classification: Classification,
+    now: Instant,
+) {
+    debug!(
+        "Scanner {}: {:?}, tool {:?}",
+        classification.scanner_ip, classification.kind, classification.tool
+    );
+    metrics.count_classification(classification.kind, classification.tool);
+    events.record(now, Event::ScannerClassified { classification });
+}
+
+/// Kernel filter passing only SYNs to claimed IPs, or in persist mode every
+/// segment to them since held flows are answered too, and pings to them if
+/// icmp is set. IPv6 SYNs are told apart in userspace. Matches nothing while
+/// no IP is claimed.
+fn capture_filter<'a>(
+    claims: impl Iterator<Item = &'a Claim>,
+    persist: bool,
+    icmp: bool,
+) -> String {
+    let mut ipv4_hosts = BTreeSet::new();
+    let mut ipv6_hosts = BTreeSet::new();
+    let mut vlan_depth = 0;
+    for claim in claims {
+        match claim.ip {
+            IpAddr::V4(ip) => ipv4_hosts.insert(IpAddr::V4(ip)),
+            IpAddr::V6(ip) => ipv6_hosts.insert(IpAddr::V6(ip)),
+        };
+        vlan_depth = vlan_depth.max(claim.vlan_tags.as_slice().len());
+    }
+    if ipv4_hosts.len() + ipv6_hosts.len() > MAX_FILTER_HOSTS {
+        return any_destination_filter(persist, icmp);
     }
 
-    let ethertype = u16::from_be_bytes([packet_data[12], packet_data[13]]);
-    if ethertype != 0x0800 {
-        return false; // Not an IPv4 packet
+    let dst_hosts = |hosts: &BTreeSet<IpAddr>| {
+        hosts
+            .iter()
+            .map(|ip| format!("dst host {}", ip))
+            .collect::<Vec<_>>()
+            .join(" or ")
+    };
+    let mut alternatives = Vec::new();
+    if !ipv4_hosts.is_empty() {
+        if persist {
+            alternatives.push(format!("(tcp and ({}))", dst_hosts(&ipv4_hosts)));
+        } else {
+            alternatives.push(format!(
+                "(tcp and ({}) and {})",
+                dst_hosts(&ipv4_hosts),
+                SYN_ONLY_FILTER
+            ));
+        }
+    }
+    if !ipv4_hosts.is_empty() && icmp {
+        alternatives.push(format!(
+            "(icmp and ({}) and {})",
+            dst_hosts(&ipv4_hosts),
+            icmp_responder::ECHO_REQUEST_FILTER
+        ));
+    }
+    if !ipv6_hosts.is_empty() {
+        let protocols = if icmp { "(tcp or icmp6)" } else { "tcp" };
+        alternatives.push(format!("({} and ({}))", protocols, dst_hosts(&ipv6_hosts)));
     }
+    if alternatives.is_empty() {
+        return packet_io::MATCH_NOTHING.to_string();
+    }
+
+    packet_io::with_vlans(&alternatives.join(" or "), vlan_depth)
+}
 
-    let protocol = packet_data[23];
-    if protocol != 6 {
-        return false; // Not a TCP packet
+/// Capture filter for SYNs and pings to any IP, for when the claimed IPs
+/// do not fit in one
+fn any_destination_filter(persist: bool, icmp: bool) -> String {
+    let mut alternatives = vec![if persist {
+        "(ip and tcp)".to_string()
+    } else {
+        format!("(ip and tcp and {})", SYN_ONLY_FILTER)
+    }];
+    if icmp {
+        alternatives.push(format!(
+            "(icmp and {})",
+            icmp_responder::ECHO_REQUEST_FILTER
+        ));
+        alternatives.push("(ip6 and (tcp or icmp6))".to_string());
+    } else {
+        alternatives.push("(ip6 and tcp)".to_string());
     }
+    packet_io::with_vlans(&alternatives.join(" or "), packet_decoder::MAX_VLAN_TAGS)
+}
+
+/// Starts tarpitting a claimed IP, or stops at once and lets go of
+/// its held flows when the IP is released, returns the flows let go of
+fn apply_claim_event(
+    event: ClaimEvent,
+    ips_to_tarpit: &mut Claims,
+    held_flows: &mut FlowTable,
+    now: Instant,
+) -> Vec<(FlowKey, Flow)> {
+    match event {
+        ClaimEvent::Claimed(claim) => {
+            touch_claim(ips_to_tarpit, claim, now);
+            Vec::new()
+        }
+        ClaimEvent::Released(claim, _) => {
+            ips_to_tarpit.remove(&claim);
+            let released = held_flows.remove_decoy(&claim);
+            if !released.is_empty() {
+                debug!("Released {} held flows to {}", released.len(), claim);
+            }
+            released
+        }
+    }
+}
 
-    let tcp_offset = 34; // Ethernet (14 bytes) + IPv4 (20 bytes, no options)
-    let tcp_flags = packet_data[tcp_offset + 13];
+fn touch_claim(ips_to_tarpit: &mut Claims, claim: Claim, now: Instant) {
+    ips_to_tarpit
+        .entry(claim)
+        .and_modify(|claim_times| claim_times.last_seen = now)
+        .or_insert(ClaimTimes {
+            first_seen: now,
+            last_seen: now,
+        });
+}
 
-    let syn_flag = tcp_flags & 0b0000_0010 != 0;
-    let ack_flag = tcp_flags & 0b0001_0000 != 0;
+fn validate_tcp_syn_packet(segment: &TcpSegment) -> bool {
+    let syn_flag = segment.flags & TcpFlags::SYN != 0;
+    let ack_flag = segment.flags & TcpFlags::ACK != 0;
 
     syn_flag && !ack_flag
 }
 
+/// Accepts the segments a scanner sends on an established flow:
+/// ACKs, window probes and resets
+fn validate_tcp_flow_packet(segment: &TcpSegment) -> bool {
+    let syn_flag = segment.flags & TcpFlags::SYN != 0;
+    let ack_flag = segment.flags & TcpFlags::ACK != 0;
+    let rst_flag = segment.flags & TcpFlags::RST != 0;
+
+    !syn_flag && (ack_flag || rst_flag)
+}
+
+fn flow_key(segment: &TcpSegment) -> FlowKey {
+    FlowKey {
+        vlan_tags: segment.vlan_tags,
+        scanner_ip: segment.src_ip,
+        scanner_port: segment.src_port,
+        decoy_ip: segment.dst_ip,
+        decoy_port: segment.dst_port,
+    }
+}
+
+/// Header fields of a TCP segment sent by ANTS
+struct TcpFields {
+    src_ip: IpAddr,
+    dst_ip: IpAddr,
+    src_port: u16,
+    dst_port: u16,
+    sequence: u32,
+    acknowledgement: u32,
+    flags: u8,
+    window: u16,
+    ip: IpFields,
+    /// Encoded options, a multiple of 4 bytes long
+    options: Vec<u8>,
+}
+
+#[allow(clippy::too_many_arguments)]
 fn create_syn_ack_packet(
     src_mac: MacAddr,
     dst_mac: MacAddr,
-    src_ip: Ipv4Addr,
-    dst_ip: Ipv4Addr,
+    src_ip: IpAddr,
+    dst_ip: IpAddr,
     src_port: u16,
     dst_port: u16,
     received_seq_num: u32,
-) -> [u8; 60] {
-    let mut eth_buffer = [0u8; 60]; // Ethernet header + IP + TCP
+    syn_ack: &SynAckFields,
+) -> Vec<u8> {
+    create_tcp_packet(
+        src_mac,
+        dst_mac,
+        &TcpFields {
+            src_ip,
+            dst_ip,
+            src_port,
+            dst_port,
+            sequence: syn_ack.isn,
+            acknowledgement: received_seq_num.wrapping_add(1),
+            flags: TcpFlags::SYN | TcpFlags::ACK,
+            window: syn_ack.window,
+            ip: syn_ack.ip,
+            options: syn_ack.options.clone(),
+        },
+    )
+}
+
+/// Builds the RST/ACK a closed port answers a SYN with
+fn create_rst_ack_packet(
+    src_mac: MacAddr,
+    dst_mac: MacAddr,
+    segment: &TcpSegment,
+    ip: IpFields,
+) -> Vec<u8> {
+    create_tcp_packet(
+        src_mac,
+        dst_mac,
+        &TcpFields {
+            src_ip: segment.dst_ip,
+            dst_ip: segment.src_ip,
+            src_port: segment.dst_port,
+            dst_port: segment.src_port,
+            sequence: 0,
+            acknowledgement: segment.sequence.wrapping_add(1),
+            flags: TcpFlags::RST | TcpFlags::ACK,
+            window: 0,
+            ip,
+            options: Vec::new(),
+        },
+    )
+}
+
+/// Builds the ACK that keeps a held flow stuck: the scanner's data is never
+/// acknowledged and the advertised window stays closed
+fn create_zero_window_ack_packet(
+    src_mac: MacAddr,
+    dst_mac: MacAddr,
+    key: &FlowKey,
+    flow: &Flow,
+    ip: IpFields,
+) -> Vec<u8> {
+    create_tcp_packet(
+        src_mac,
+        dst_mac,
+        &TcpFields {
+            src_ip: key.decoy_ip,
+            dst_ip: key.scanner_ip,
+            src_port: key.decoy_port,
+            dst_port: key.scanner_port,
+            sequence: flow.local_seq,
+            acknowledgement: flow.remote_seq,
+            flags: TcpFlags::ACK,
+            window: 0,
+            ip,
+            options: Vec::new(),
+        },
+    )
+}
+
+fn create_tcp_packet(src_mac: MacAddr, dst_mac: MacAddr, fields: &TcpFields) -> Vec<u8> {
+    let (ethertype, ip_header_len) = match fields.src_ip {
+        IpAddr::V4(_) => (EtherTypes::Ipv4, 20),
+        IpAddr::V6(_) => (EtherTypes::Ipv6, 40),
+    };
+    let tcp_offset = 14 + ip_header_len;
+    let tcp_header_len = 20 + fields.options.len();
+    // Ethernet header + IP + TCP, padded to the minimum Ethernet frame size
+    let mut eth_buffer = vec![0u8; (tcp_offset + tcp_header_len).max(60)];
 
     let mut ethernet_packet = MutableEthernetPacket::new(&mut eth_buffer[..]).unwrap();
     ethernet_packet.set_source(src_mac);
     ethernet_packet.set_destination(dst_mac);
-    ethernet_packet.set_ethertype(EtherTypes::Ipv4);
+    ethernet_packet.set_ethertype(ethertype);
 
-    {
-        let ipv4_buffer = &mut eth_buffer[14..34];
-        let mut ipv4_packet = MutableIpv4Packet::new(ipv4_buffer).unwrap();
-
-        ipv4_packet.set_version(4);
-        ipv4_packet.set_header_length(5);
-        ipv4_packet.set_total_length((20 + 20) as u16);
-        ipv4_packet.set_ttl(64);
-        ipv4_packet.set_flags(Ipv4Flags::DontFragment);
-        ipv4_packet.set_next_level_protocol(pnet::packet::ip::IpNextHeaderProtocols::Tcp);
-        ipv4_packet.set_source(src_ip);
-        ipv4_packet.set_destination(dst_ip);
-        ipv4_packet.set_checksum(0);
-
-        let ipv4_checksum = checksum(ipv4_packet.packet(), 10);
-        ipv4_packet.set_checksum(ipv4_checksum);
+    match (fields.src_ip, fields.dst_ip) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let ipv4_buffer = &mut eth_buffer[14..tcp_offset];
+            let mut ipv4_packet = MutableIpv4Packet::new(ipv4_buffer).unwrap();
+
+            ipv4_packet.set_version(4);
+            ipv4_packet.set_header_length(5);
+            ipv4_packet.set_total_length((20 + tcp_header_len) as u16);
+            ipv4_packet.set_identification(fields.ip.id);
+            ipv4_packet.set_ttl(fields.ip.ttl);
+            if fields.ip.dont_fragment {
+                ipv4_packet.set_flags(Ipv4Flags::DontFragment);
+            }
+            ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+            ipv4_packet.set_source(src_ip);
+            ipv4_packet.set_destination(dst_ip);
+            ipv4_packet.set_checksum(0);
+
+            let ipv4_checksum = checksum(ipv4_packet.packet(), 10);
+            ipv4_packet.set_checksum(ipv4_checksum);
+        }
+        (IpAddr::V6(src_ip), IpAddr::V6(dst_ip)) => {
+            let ipv6_buffer = &mut eth_buffer[14..tcp_offset];
+            let mut ipv6_packet = MutableIpv6Packet::new(ipv6_buffer).unwrap();
+
+            ipv6_packet.set_version(6);
+            ipv6_packet.set_payload_length(tcp_header_len as u16);
+            ipv6_packet.set_next_header(IpNextHeaderProtocols::Tcp);
+            ipv6_packet.set_hop_limit(fields.ip.ttl);
+            ipv6_packet.set_source(src_ip);
+            ipv6_packet.set_destination(dst_ip);
+        }
+        _ => panic!("TCP reply with mixed address families"),
     }
 
     {
-        let tcp_buffer = &mut eth_buffer[34..54];
+        let tcp_buffer = &mut eth_buffer[tcp_offset..tcp_offset + tcp_header_len];
+        tcp_buffer[20..].copy_from_slice(&fields.options);
         let mut tcp_packet = MutableTcpPacket::new(tcp_buffer).unwrap();
 
-        tcp_packet.set_sequence(1);
-        tcp_packet.set_acknowledgement(received_seq_num + 1);
-        tcp_packet.set_source(src_port);
-        tcp_packet.set_destination(dst_port);
-        tcp_packet.set_data_offset(5);
-        tcp_packet.set_flags(TcpFlags::SYN | TcpFlags::ACK);
-        tcp_packet.set_window(1024);
+        tcp_packet.set_sequence(fields.sequence);
+        tcp_packet.set_acknowledgement(fields.acknowledgement);
+        tcp_packet.set_source(fields.src_port);
+        tcp_packet.set_destination(fields.dst_port);
+        tcp_packet.set_data_offset((tcp_header_len / 4) as u8);
+        tcp_packet.set_flags(fields.flags);
+        tcp_packet.set_window(fields.window);
         tcp_packet.set_checksum(0);
 
-        let tcp_checksum = ipv4_checksum(
-            tcp_packet.packet(),
-            28,
-            &[],
-            &src_ip,
-            &dst_ip,
-            pnet::packet::ip::IpNextHeaderProtocols::Tcp,
-        );
+        // The pseudo-header differs between IPv4 and IPv6
+        let tcp_checksum = match (fields.src_ip, fields.dst_ip) {
+            (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+                tcp::ipv4_checksum(&tcp_packet.to_immutable(), &src_ip, &dst_ip)
+            }
+            (IpAddr::V6(src_ip), IpAddr::V6(dst_ip)) => {
+                tcp::ipv6_checksum(&tcp_packet.to_immutable(), &src_ip, &dst_ip)
+            }
+            _ => unreachable!(),
+        };
         tcp_packet.set_checksum(tcp_checksum);
     }
 
     eth_buffer
 }
 
-fn send_syn_ack(
-    interface_name: &str,
-    dst_mac: MacAddr,
-    src_ip: Ipv4Addr,
-    dst_ip: Ipv4Addr,
-    src_port: u16,
-    dst_port: u16,
-    received_seq_num: u32,
-) {
-    let interface = datalink::interfaces()
-        .into_iter()
-        .find(|iface| iface.name == interface_name)
-        .expect("Could not find the specified interface");
-
-    let src_mac = interface.mac.unwrap();
-    let eth_buffer = create_syn_ack_packet(
-        src_mac,
-        dst_mac,
-        src_ip,
-        dst_ip,
-        src_port,
-        dst_port,
-        received_seq_num,
-    );
+/// Answers an ACK or window probe on a held flow, returns the
+/// zero-window ACK to send if the flow is still held
+fn handle_held_flow(
+    segment: &TcpSegment,
+    held_flows: &mut FlowTable,
+    ips_to_tarpit: &mut Claims,
+    src_mac: MacAddr,
+    ip: IpFields,
+    passive_mode: bool,
+    now: Instant,
+) -> Option<Vec<u8>> {
+    let key = flow_key(segment);
 
-    match datalink::channel(&interface, Default::default()) {
-        Ok(Ethernet(mut tx, _)) => {
-            let _ = tx.send_to(&eth_buffer, None).unwrap();
-            debug!("Sent SYN/ACK packet");
-        }
-        Ok(_) => panic!("Unhandled channel type"),
-        Err(e) => panic!("Failed to send packet: {}", e),
+    // ACKs that do not acknowledge the SYN/ACK were not sent by the scanner holding the flow
+    if segment.acknowledgement != held_flows.get(&key)?.local_seq {
+        return None;
     }
-}
+    let flow = held_flows.touch(&key, now)?;
 
-fn handle_packet(packet: &[u8], interface: &str, passive_mode: bool) -> Option<Ipv4Addr> {
-    let src_ip = Ipv4Addr::new(packet[26], packet[27], packet[28], packet[29]);
-    let dst_ip = Ipv4Addr::new(packet[30], packet[31], packet[32], packet[33]);
+    // Keep the decoy claimed for as long as it holds connections
+    touch_claim(ips_to_tarpit, key.decoy(), now);
 
-    let src_mac = MacAddr::new(
-        packet[6], packet[7], packet[8], packet[9], packet[10], packet[11],
+    if passive_mode {
+        return None;
+    }
+
+    debug!(
+        "Holding flow {}:{} -> {}:{} with zero window",
+        key.scanner_ip, key.scanner_port, key.decoy_ip, key.decoy_port
     );
+    let ack = create_zero_window_ack_packet(src_mac, segment.src_mac, &key, &flow, ip);
+    Some(packet_decoder::tag_frame(&ack, key.vlan_tags))
+}
 
-    let src_port = u16::from_be_bytes([packet[34], packet[35]]);
-    let dst_port = u16::from_be_bytes([packet[36], packet[37]]);
-    let received_seq_num = u32::from_be_bytes([packet[38], packet[39], packet[40], packet[41]]);
+/// Builds the RST/ACK answering a SYN to a closed port on the VLAN it
+/// arrived on, None in passive mode
+fn handle_closed_port(
+    segment: &TcpSegment,
+    src_mac: MacAddr,
+    ip: IpFields,
+    passive_mode: bool,
+) -> Option<Vec<u8>> {
+    if passive_mode {
+        return None;
+    }
 
-    if !passive_mode {
-        thread::sleep(Duration::from_millis(500));
-        send_syn_ack(
-            interface,
-            src_mac,
-            dst_ip,
-            src_ip,
-            dst_port,
-            src_port,
-            received_seq_num,
-        );
+    let rst_ack = create_rst_ack_packet(src_mac, segment.src_mac, segment, ip);
+    Some(packet_decoder::tag_frame(&rst_ack, segment.vlan_tags))
+}
 
-        return Some(dst_ip);
+/// Builds the SYN/ACK answering a SYN on the VLAN it arrived on, None in passive mode
+fn handle_packet(
+    segment: &TcpSegment,
+    src_mac: MacAddr,
+    passive_mode: bool,
+    syn_ack: &SynAckFields,
+) -> Option<Vec<u8>> {
+    if passive_mode {
+        return None;
     }
 
-    None
+    let syn_ack = create_syn_ack_packet(
+        src_mac,
+        segment.src_mac,
+        segment.dst_ip,
+        segment.src_ip,
+        segment.dst_port,
+        segment.src_port,
+        segment.sequence,
+        syn_ack,
+    );
+    Some(packet_decoder::tag_frame(&syn_ack, segment.vlan_tags))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::{Config, Os};
+    use crate::event_log::CapturedEvents;
+    use crate::packet_decoder::{VlanTag, VlanTags};
+    use crate::test_frames;
     use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
     use pnet::packet::ipv4::Ipv4Packet;
     use pnet::packet::tcp::{TcpFlags, TcpPacket};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    /// Sequence number of the SYN/ACKs built in tests
+    const SYN_ACK_SEQUENCE: u32 = 1;
+
+    fn tcp_state(config: &Config, now: Instant) -> TcpState {
+        tcp_state_with(
+            config,
+            EventLog::new(None, now, Duration::ZERO),
+            Arc::default(),
+            now,
+        )
+    }
+
+    fn tcp_state_with(
+        config: &Config,
+        events: EventLog,
+        metrics: Arc<Metrics>,
+        now: Instant,
+    ) -> TcpState {
+        TcpState::new(
+            config.tcp,
+            config.icmp,
+            config.scanners,
+            &config.personality,
+            PortPolicy::new(&config.ports, config.tcp.persist),
+            ClaimMacs::new(config.mac, MacAddr::zero()),
+            PassiveMode::default(),
+            events,
+            metrics,
+            now,
+        )
+    }
+
+    /// Claims test_frames::DECOY_IP, untagged
+    fn claim_decoy(tcp_state: &mut TcpState, now: Instant) -> Claim {
+        let claim = Claim {
+            vlan_tags: VlanTags::default(),
+            ip: test_frames::DECOY_IP.into(),
+        };
+        tcp_state.apply_claim_event(ClaimEvent::Claimed(claim), now);
+        claim
+    }
+
+    /// Handles frame and queues its reply as the TCP thread does
+    fn send_reply(tcp_state: &mut TcpState, frame: &[u8], now: Instant) -> Option<Vec<u8>> {
+        let reply = tcp_state.handle_frame(frame, now)?;
+        let mut sent = None;
+        tcp_state.queue_reply(reply, now, |frame, _| {
+            sent = Some(frame);
+            true
+        });
+        sent
+    }
 
     #[test]
     fn test_create_syn_ack_packet_ethernet_header() {
@@ -262,11 +1228,12 @@ mod tests {
         let packet = create_syn_ack_packet(
             src_mac,
             dst_mac,
-            src_ip,
-            dst_ip,
+            src_ip.into(),
+            dst_ip.into(),
             src_port,
             dst_port,
             received_seq_num,
+            &SynAckFields::plain(SYN_ACK_SEQUENCE, 1024),
         );
 
         let eth_packet = EthernetPacket::new(&packet).unwrap();
@@ -288,11 +1255,12 @@ mod tests {
         let packet = create_syn_ack_packet(
             src_mac,
             dst_mac,
-            src_ip,
-            dst_ip,
+            src_ip.into(),
+            dst_ip.into(),
             src_port,
             dst_port,
             received_seq_num,
+            &SynAckFields::plain(SYN_ACK_SEQUENCE, 1024),
         );
 
         let ipv4_packet = Ipv4Packet::new(&packet[14..34]).unwrap();
@@ -321,11 +1289,12 @@ mod tests {
         let packet = create_syn_ack_packet(
             src_mac,
             dst_mac,
-            src_ip,
-            dst_ip,
+            src_ip.into(),
+            dst_ip.into(),
             src_port,
             dst_port,
             received_seq_num,
+            &SynAckFields::plain(SYN_ACK_SEQUENCE, 1024),
         );
 
         let tcp_packet = TcpPacket::new(&packet[34..54]).unwrap();
@@ -338,29 +1307,45 @@ mod tests {
         assert_eq!(tcp_packet.get_window(), 1024);
     }
 
+    const SCANNER_MAC: MacAddr = MacAddr(1, 2, 3, 4, 5, 6);
+    const SCANNER_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 100);
+    const DECOY_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+
+    fn create_mock_tcp_packet(flags: u8) -> Vec<u8> {
+        create_tcp_packet(
+            SCANNER_MAC,
+            MacAddr::zero(),
+            &TcpFields {
+                src_ip: IpAddr::V4(SCANNER_IP),
+                dst_ip: IpAddr::V4(DECOY_IP),
+                src_port: 40000,
+                dst_port: 22,
+                sequence: 41,
+                acknowledgement: 0,
+                flags,
+                window: 1024,
+                ip: IpFields::PLAIN,
+                options: Vec::new(),
+            },
+        )
+        .to_vec()
+    }
+
     fn create_mock_tcp_syn_packet() -> Vec<u8> {
-        let mut packet = vec![0u8; 54];
-        // Set Ethernet type to IPv4 (0x0800)
-        packet[12] = 0x08;
-        packet[13] = 0x00;
-        // Set IPv4 protocol to TCP (6)
-        packet[23] = 0x06;
-        // Set TCP flags to SYN
-        packet[34 + 13] = 0b0000_0010;
-        packet
+        create_mock_tcp_packet(TcpFlags::SYN)
     }
 
     fn create_mock_tcp_syn_ack_packet() -> Vec<u8> {
-        let mut packet = create_mock_tcp_syn_packet();
-        // Set TCP flags to SYN-ACK
-        packet[34 + 13] = 0b0001_0010;
-        packet
+        create_mock_tcp_packet(TcpFlags::SYN | TcpFlags::ACK)
     }
 
     fn create_non_tcp_packet() -> Vec<u8> {
         let mut packet = create_mock_tcp_syn_packet();
-        // Set IPv4 protocol to something other than TCP
-        packet[23] = 0x11;
+        // Set IPv4 protocol to something other than TCP and fix the checksum
+        let mut ipv4_packet = MutableIpv4Packet::new(&mut packet[14..34]).unwrap();
+        ipv4_packet.set_next_level_protocol(pnet::packet::ip::IpNextHeaderProtocols::Udp);
+        let ipv4_checksum = pnet::packet::ipv4::checksum(&ipv4_packet.to_immutable());
+        ipv4_packet.set_checksum(ipv4_checksum);
         packet
     }
 
@@ -372,52 +1357,646 @@ mod tests {
         packet
     }
 
+    fn is_tcp_syn(packet: &[u8]) -> bool {
+        packet_decoder::decode_tcp_segment(packet)
+            .is_some_and(|segment| validate_tcp_syn_packet(&segment))
+    }
+
+    fn is_tcp_flow(packet: &[u8]) -> bool {
+        packet_decoder::decode_tcp_segment(packet)
+            .is_some_and(|segment| validate_tcp_flow_packet(&segment))
+    }
+
     #[test]
     fn test_validate_tcp_syn_packet_valid_syn() {
         let packet = create_mock_tcp_syn_packet();
-        assert!(validate_tcp_syn_packet(&packet));
+        assert!(is_tcp_syn(&packet));
     }
 
     #[test]
     fn test_validate_tcp_syn_packet_valid_syn_ack() {
         let packet = create_mock_tcp_syn_ack_packet();
-        assert!(!validate_tcp_syn_packet(&packet));
+        assert!(!is_tcp_syn(&packet));
     }
 
     #[test]
     fn test_validate_tcp_syn_packet_non_tcp() {
         let packet = create_non_tcp_packet();
-        assert!(!validate_tcp_syn_packet(&packet));
+        assert!(!is_tcp_syn(&packet));
     }
 
     #[test]
     fn test_validate_tcp_syn_packet_non_ipv4() {
         let packet = create_non_ipv4_packet();
-        assert!(!validate_tcp_syn_packet(&packet));
+        assert!(!is_tcp_syn(&packet));
     }
 
     #[test]
     fn test_validate_tcp_syn_packet_too_small() {
         let packet = vec![0u8; 40]; // Smaller than the minimum packet size
-        assert!(!validate_tcp_syn_packet(&packet));
+        assert!(!is_tcp_syn(&packet));
+    }
+
+    #[test]
+    fn test_validate_tcp_flow_packet_ack() {
+        let packet = create_mock_tcp_packet(TcpFlags::ACK);
+        assert!(is_tcp_flow(&packet));
+        assert!(!is_tcp_syn(&packet));
+    }
+
+    #[test]
+    fn test_validate_tcp_flow_packet_rst() {
+        let packet = create_mock_tcp_packet(TcpFlags::RST);
+        assert!(is_tcp_flow(&packet));
+    }
+
+    #[test]
+    fn test_validate_tcp_flow_packet_syn() {
+        assert!(!is_tcp_flow(&create_mock_tcp_syn_packet()));
+        assert!(!is_tcp_flow(&create_mock_tcp_syn_ack_packet()));
+    }
+
+    #[test]
+    fn test_create_zero_window_ack_packet() {
+        let src_mac = MacAddr::new(0x00, 0x11, 0x22, 0x33, 0x44, 0x55);
+        let dst_mac = MacAddr::new(0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB);
+        let key = FlowKey {
+            vlan_tags: VlanTags::default(),
+            scanner_ip: IpAddr::V4(SCANNER_IP),
+            scanner_port: 40000,
+            decoy_ip: IpAddr::V4(DECOY_IP),
+            decoy_port: 22,
+        };
+        let mut held_flows = FlowTable::new(Duration::from_secs(60), 10);
+        let now = Instant::now();
+        held_flows.insert(key, SYN_ACK_SEQUENCE, 41, now);
+        let flow = held_flows.touch(&key, now).unwrap();
+
+        let packet = create_zero_window_ack_packet(src_mac, dst_mac, &key, &flow, IpFields::PLAIN);
+
+        let ipv4_packet = Ipv4Packet::new(&packet[14..34]).unwrap();
+        assert_eq!(ipv4_packet.get_source(), DECOY_IP);
+        assert_eq!(ipv4_packet.get_destination(), SCANNER_IP);
+
+        let tcp_packet = TcpPacket::new(&packet[34..54]).unwrap();
+        assert_eq!(tcp_packet.get_source(), 22);
+        assert_eq!(tcp_packet.get_destination(), 40000);
+        assert_eq!(tcp_packet.get_sequence(), SYN_ACK_SEQUENCE + 1);
+        assert_eq!(tcp_packet.get_acknowledgement(), 42);
+        assert_eq!(tcp_packet.get_flags(), TcpFlags::ACK);
+        assert_eq!(tcp_packet.get_window(), 0);
+    }
+
+    #[test]
+    fn test_created_packets_pass_decoder() {
+        let packet = create_mock_tcp_syn_packet();
+
+        let segment = packet_decoder::decode_tcp_segment(&packet).unwrap();
+        let key = flow_key(&segment);
+
+        assert_eq!(key.scanner_ip, IpAddr::V4(SCANNER_IP));
+        assert_eq!(key.scanner_port, 40000);
+        assert_eq!(key.decoy_ip, IpAddr::V4(DECOY_IP));
+        assert_eq!(key.decoy_port, 22);
     }
 
     #[test]
     fn test_handle_packet_passive_mode() {
-        let packet = vec![
-            0, 0, 0, 0, 0, 0, // Padding (simulate irrelevant initial data)
-            0, 0, 0, 0, 0, 0, // Ethernet header (destination MAC)
-            1, 2, 3, 4, 5, 6, // Ethernet header (source MAC)
-            0, 0, 0, 0, 0, 0, 0, 0, // Padding to IPv4 header
-            192, 168, 0, 1, // Source IP
-            192, 168, 0, 2, // Destination IP
-            0, 80, // Source port (80)
-            0, 42, // Destination port (443)
-            0, 0, 0, 42, // Sequence number
-        ];
-
-        let result = handle_packet(&packet, "eth0", true);
+        let packet = create_mock_tcp_syn_packet();
+        let segment = packet_decoder::decode_tcp_segment(&packet).unwrap();
+
+        let result = handle_packet(
+            &segment,
+            MacAddr::zero(),
+            true,
+            &SynAckFields::plain(SYN_ACK_SEQUENCE, 1024),
+        );
 
         assert!(result.is_none());
     }
+
+    #[test]
+    fn test_handle_packet_active_mode() {
+        let packet = create_mock_tcp_syn_packet();
+        let segment = packet_decoder::decode_tcp_segment(&packet).unwrap();
+        let src_mac = MacAddr::new(0x00, 0x11, 0x22, 0x33, 0x44, 0x55);
+
+        let syn_ack = handle_packet(
+            &segment,
+            src_mac,
+            false,
+            &SynAckFields::plain(SYN_ACK_SEQUENCE, 1024),
+        )
+        .unwrap();
+
+        let eth_packet = EthernetPacket::new(&syn_ack).unwrap();
+        assert_eq!(eth_packet.get_source(), src_mac);
+        assert_eq!(eth_packet.get_destination(), SCANNER_MAC);
+        let ipv4_packet = Ipv4Packet::new(&syn_ack[14..34]).unwrap();
+        assert_eq!(ipv4_packet.get_source(), DECOY_IP);
+        assert_eq!(ipv4_packet.get_destination(), SCANNER_IP);
+        let tcp_packet = TcpPacket::new(&syn_ack[34..54]).unwrap();
+        assert_eq!(tcp_packet.get_source(), 22);
+        assert_eq!(tcp_packet.get_destination(), 40000);
+        assert_eq!(tcp_packet.get_acknowledgement(), 42);
+    }
+
+    #[test]
+    fn test_handle_packet_replies_on_same_vlan() {
+        let mut vlan_tags = VlanTags::default();
+        vlan_tags.push(VlanTag {
+            tpid: EtherTypes::Vlan.0,
+            vid: 100,
+        });
+        let packet = packet_decoder::tag_frame(&create_mock_tcp_syn_packet(), vlan_tags);
+        let segment = packet_decoder::decode_tcp_segment(&packet).unwrap();
+
+        let syn_ack = handle_packet(
+            &segment,
+            MacAddr::zero(),
+            false,
+            &SynAckFields::plain(SYN_ACK_SEQUENCE, 1024),
+        )
+        .unwrap();
+
+        let reply = packet_decoder::decode_tcp_segment(&syn_ack).unwrap();
+        assert_eq!(reply.vlan_tags, vlan_tags);
+        assert_eq!(reply.dst_ip, IpAddr::V4(SCANNER_IP));
+        assert_eq!(reply.flags, TcpFlags::SYN | TcpFlags::ACK);
+    }
+
+    #[test]
+    fn test_handle_packet_ipv6() {
+        let scanner_ip = IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0x100));
+        let decoy_ip = IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0x2));
+        let packet = create_tcp_packet(
+            SCANNER_MAC,
+            MacAddr::zero(),
+            &TcpFields {
+                src_ip: scanner_ip,
+                dst_ip: decoy_ip,
+                src_port: 40000,
+                dst_port: 22,
+                sequence: 41,
+                acknowledgement: 0,
+                flags: TcpFlags::SYN,
+                window: 1024,
+                ip: IpFields::PLAIN,
+                options: Vec::new(),
+            },
+        );
+        let segment = packet_decoder::decode_tcp_segment(&packet).unwrap();
+
+        let syn_ack = handle_packet(
+            &segment,
+            MacAddr::zero(),
+            false,
+            &SynAckFields::plain(SYN_ACK_SEQUENCE, 1024),
+        )
+        .unwrap();
+
+        let eth_packet = EthernetPacket::new(&syn_ack).unwrap();
+        assert_eq!(eth_packet.get_ethertype(), EtherTypes::Ipv6);
+        // Decoding verifies the checksum over the IPv6 pseudo-header
+        let reply = packet_decoder::decode_tcp_segment(&syn_ack).unwrap();
+        assert_eq!(reply.src_ip, decoy_ip);
+        assert_eq!(reply.dst_ip, scanner_ip);
+        assert_eq!(reply.flags, TcpFlags::SYN | TcpFlags::ACK);
+        assert_eq!(reply.acknowledgement, 42);
+    }
+
+    #[test]
+    fn test_apply_claim_event_release_drops_claim_and_flows() {
+        let mut ips_to_tarpit = HashMap::new();
+        let mut held_flows = FlowTable::new(Duration::from_secs(60), 10);
+        let now = Instant::now();
+        let segment = packet_decoder::decode_tcp_segment(&create_mock_tcp_syn_packet()).unwrap();
+        let key = flow_key(&segment);
+        let claim = key.decoy();
+
+        apply_claim_event(
+            ClaimEvent::Claimed(claim),
+            &mut ips_to_tarpit,
+            &mut held_flows,
+            now,
+        );
+        held_flows.insert(key, SYN_ACK_SEQUENCE, 41, now);
+        assert!(ips_to_tarpit.contains_key(&claim));
+
+        apply_claim_event(
+            ClaimEvent::Released(claim, ReleaseReason::OwnerReturned),
+            &mut ips_to_tarpit,
+            &mut held_flows,
+            now,
+        );
+
+        assert!(ips_to_tarpit.is_empty());
+        assert_eq!(held_flows.len(), 0);
+    }
+
+    #[test]
+    fn test_capture_filter() {
+        let untagged = Claim {
+            vlan_tags: VlanTags::default(),
+            ip: Ipv4Addr::new(192, 168, 0, 2).into(),
+        };
+        let mut vlan_tags = VlanTags::default();
+        vlan_tags.push(VlanTag {
+            tpid: EtherTypes::Vlan.0,
+            vid: 100,
+        });
+        let tagged = Claim {
+            vlan_tags,
+            ip: Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2).into(),
+        };
+
+        assert_eq!(
+            capture_filter([].iter(), false, true),
+            packet_io::MATCH_NOTHING
+        );
+        assert_eq!(
+            capture_filter([untagged].iter(), false, false),
+            "((tcp and (dst host 192.168.0.2) and tcp[tcpflags] & (tcp-syn|tcp-ack) == tcp-syn))"
+        );
+        assert_eq!(
+            capture_filter([untagged, tagged].iter(), true, false),
+            "((tcp and (dst host 192.168.0.2)) or (tcp and (dst host fd00::2))) or \
+             (vlan and ((tcp and (dst host 192.168.0.2)) or (tcp and (dst host fd00::2))))"
+        );
+        assert_eq!(
+            capture_filter([untagged, tagged].iter(), true, true),
+            "((tcp and (dst host 192.168.0.2)) or \
+             (icmp and (dst host 192.168.0.2) and icmp[icmptype] == icmp-echo) or \
+             ((tcp or icmp6) and (dst host fd00::2))) or \
+             (vlan and ((tcp and (dst host 192.168.0.2)) or \
+             (icmp and (dst host 192.168.0.2) and icmp[icmptype] == icmp-echo) or \
+             ((tcp or icmp6) and (dst host fd00::2))))"
+        );
+
+        let many = (0..=MAX_FILTER_HOSTS as u8)
+            .map(|host| Claim {
+                vlan_tags: VlanTags::default(),
+                ip: Ipv4Addr::new(192, 168, 0, host).into(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            capture_filter(many.iter(), false, false),
+            "((ip and tcp and tcp[tcpflags] & (tcp-syn|tcp-ack) == tcp-syn) or (ip6 and tcp)) or \
+             (vlan and ((ip and tcp and tcp[tcpflags] & (tcp-syn|tcp-ack) == tcp-syn) or (ip6 and tcp))) or \
+             (vlan and ((ip and tcp and tcp[tcpflags] & (tcp-syn|tcp-ack) == tcp-syn) or (ip6 and tcp)))"
+        );
+    }
+
+    #[test]
+    fn test_syn_answered_per_port_policy() {
+        let now = Instant::now();
+        let syn = test_frames::tcp_syn(MacAddr::zero());
+        let answer = |default_action| {
+            let mut config = Config::default();
+            config.ports.default_action = default_action;
+            let mut tcp_state = tcp_state(&config, now);
+            claim_decoy(&mut tcp_state, now);
+            let reply = tcp_state.handle_frame(&syn, now);
+            let reply =
+                reply.map(|reply| packet_decoder::decode_tcp_segment(&reply.frame).unwrap());
+            (reply, tcp_state.held_flows.len())
+        };
+
+        let (syn_ack, held_flows) = answer(PortAction::Tarpit);
+        assert_eq!(syn_ack.unwrap().flags, TcpFlags::SYN | TcpFlags::ACK);
+        assert_eq!(held_flows, 0);
+
+        // Persisted flows are held once the handshake completes, not per SYN
+        let (syn_ack, held_flows) = answer(PortAction::Persist);
+        assert_eq!(syn_ack.unwrap().flags, TcpFlags::SYN | TcpFlags::ACK);
+        assert_eq!(held_flows, 0);
+
+        let (rst_ack, _) = answer(PortAction::Reset);
+        let rst_ack = rst_ack.unwrap();
+        assert_eq!(rst_ack.flags, TcpFlags::RST | TcpFlags::ACK);
+        assert_eq!(rst_ack.src_port, 22);
+        assert_eq!(rst_ack.acknowledgement, 42);
+
+        assert_eq!(answer(PortAction::Drop), (None, 0));
+    }
+
+    #[test]
+    fn test_syn_ack_follows_personality() {
+        let now = Instant::now();
+        let mut config = Config::default();
+        config.tcp.persist = true;
+        config.personality.os = vec![Os::CiscoIos];
+        let mut tcp_state = tcp_state(&config, now);
+        claim_decoy(&mut tcp_state, now);
+
+        let frame = tcp_state
+            .handle_frame(&test_frames::tcp_syn(MacAddr::zero()), now)
+            .unwrap()
+            .frame;
+        let ipv4_packet = Ipv4Packet::new(&frame[14..]).unwrap();
+        assert_eq!(ipv4_packet.get_ttl(), 255);
+        assert_eq!(ipv4_packet.get_flags(), 0);
+        let tcp_packet = TcpPacket::new(ipv4_packet.payload()).unwrap();
+        assert_eq!(tcp_packet.get_window(), 4128);
+        assert_eq!(tcp_packet.get_data_offset(), 6);
+        assert_eq!(tcp_packet.get_options_raw(), [2, 4, 0x02, 0x18]);
+    }
+
+    #[test]
+    fn test_ping_and_syn_ack_follow_same_personality() {
+        let now = Instant::now();
+        let mut config = Config::default();
+        config.icmp.enabled = true;
+        config.personality.os = vec![Os::Windows];
+        let mut tcp_state = tcp_state(&config, now);
+        claim_decoy(&mut tcp_state, now);
+
+        let ping = test_frames::icmp_echo_request(MacAddr::zero(), b"ping");
+        let echo_reply = tcp_state.handle_frame(&ping, now).unwrap().frame;
+        let syn_ack = tcp_state
+            .handle_frame(&test_frames::tcp_syn(MacAddr::zero()), now)
+            .unwrap()
+            .frame;
+
+        let echo_reply = Ipv4Packet::new(&echo_reply[14..]).unwrap();
+        let syn_ack = Ipv4Packet::new(&syn_ack[14..]).unwrap();
+        assert_eq!(echo_reply.get_ttl(), 128);
+        assert_eq!(syn_ack.get_ttl(), 128);
+        assert_eq!(echo_reply.get_flags(), Ipv4Flags::DontFragment);
+        assert_eq!(syn_ack.get_flags(), Ipv4Flags::DontFragment);
+        // Both come from the one incrementing IP ID counter
+        assert_eq!(
+            syn_ack.get_identification(),
+            echo_reply.get_identification().wrapping_add(1)
+        );
+    }
+
+    #[test]
+    fn test_flow_held_once_ack_returns_syn_cookie() {
+        let now = Instant::now();
+        let mut config = Config::default();
+        config.tcp.persist = true;
+        let mut tcp_state = tcp_state(&config, now);
+        claim_decoy(&mut tcp_state, now);
+        let syn_ack = tcp_state
+            .handle_frame(&test_frames::tcp_syn(MacAddr::zero()), now)
+            .unwrap()
+            .frame;
+        let isn = packet_decoder::decode_tcp_segment(&syn_ack)
+            .unwrap()
+            .sequence;
+
+        // An ACK guessing at the ISN is ignored
+        let forged_ack = test_frames::tcp_ack(MacAddr::zero(), isn.wrapping_add(2));
+        assert_eq!(tcp_state.handle_frame(&forged_ack, now), None);
+        assert_eq!(tcp_state.held_flows.len(), 0);
+
+        let ack = test_frames::tcp_ack(MacAddr::zero(), isn.wrapping_add(1));
+        let zero_window_ack = tcp_state.handle_frame(&ack, now).unwrap();
+        assert_eq!(zero_window_ack.delay, Duration::ZERO);
+        let zero_window_ack = packet_decoder::decode_tcp_segment(&zero_window_ack.frame).unwrap();
+        assert_eq!(zero_window_ack.sequence, isn.wrapping_add(1));
+        assert_eq!(zero_window_ack.acknowledgement, 42);
+        assert_eq!(zero_window_ack.window, 0);
+        assert_eq!(tcp_state.held_flows.len(), 1);
+    }
+
+    #[test]
+    fn test_rate_limited_ack_changes_nothing() {
+        let now = Instant::now();
+        let mut config = Config::default();
+        config.tcp.persist = true;
+        config.tcp.rate_per_source = 1;
+        config.personality.os = vec![Os::Windows];
+        let mut tcp_state = tcp_state(&config, now);
+        claim_decoy(&mut tcp_state, now);
+        let syn_ack = tcp_state
+            .handle_frame(&test_frames::tcp_syn(MacAddr::zero()), now)
+            .unwrap()
+            .frame;
+        let syn_ack_id = Ipv4Packet::new(&syn_ack[14..])
+            .unwrap()
+            .get_identification();
+        let isn = packet_decoder::decode_tcp_segment(&syn_ack)
+            .unwrap()
+            .sequence;
+        let ack = test_frames::tcp_ack(MacAddr::zero(), isn.wrapping_add(1));
+
+        // The SYN took the only token
+        assert_eq!(tcp_state.handle_frame(&ack, now), None);
+        assert_eq!(tcp_state.held_flows.len(), 0);
+
+        let later = now + Duration::from_secs(1);
+        let zero_window_ack = tcp_state.handle_frame(&ack, later).unwrap().frame;
+        assert_eq!(tcp_state.held_flows.len(), 1);
+        let zero_window_ack_id = Ipv4Packet::new(&zero_window_ack[14..])
+            .unwrap()
+            .get_identification();
+        assert_eq!(zero_window_ack_id, syn_ack_id.wrapping_add(1));
+    }
+
+    #[test]
+    fn test_events_of_a_held_flow() {
+        let now = Instant::now();
+        let mut config = Config::default();
+        config.tcp.persist = true;
+        let captured = CapturedEvents::default();
+        let mut tcp_state = tcp_state_with(&config, captured.event_log(now), Arc::default(), now);
+        let claim = claim_decoy(&mut tcp_state, now);
+        let syn_ack =
+            send_reply(&mut tcp_state, &test_frames::tcp_syn(MacAddr::zero()), now).unwrap();
+        let isn = packet_decoder::decode_tcp_segment(&syn_ack)
+            .unwrap()
+            .sequence;
+        let ack = test_frames::tcp_ack(MacAddr::zero(), isn.wrapping_add(1));
+        send_reply(&mut tcp_state, &ack, now).unwrap();
+
+        let events = captured.take();
+        let names = events
+            .iter()
+            .map(|event| event["event"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "ip_claimed",
+                "syn_received",
+                "reply_sent",
+                "flow_held",
+                "reply_sent"
+            ]
+        );
+        assert_eq!(events[1]["scanner_ip"], test_frames::SCANNER_IP.to_string());
+        assert_eq!(events[1]["ttl"], 64);
+        assert_eq!(events[1]["action"], "persist");
+        assert_eq!(events[2]["kind"], "syn_ack");
+        assert_eq!(events[2]["delay_ms"], 500);
+        assert_eq!(events[4]["kind"], "zero_window_ack");
+
+        tcp_state.apply_claim_event(ClaimEvent::Released(claim, ReleaseReason::Manual), now);
+        let events = captured.take();
+        assert_eq!(events[0]["event"], "ip_released");
+        assert_eq!(events[0]["reason"], "manual");
+        assert_eq!(events[1]["event"], "flow_closed");
+    }
+
+    #[test]
+    fn test_scanner_classified_as_single_probe() {
+        let now = Instant::now();
+        let mut config = Config::default();
+        config.tcp.claim_expiry_secs = 3600;
+        let captured = CapturedEvents::default();
+        let metrics = Arc::new(Metrics::default());
+        let mut tcp_state = tcp_state_with(&config, captured.event_log(now), metrics.clone(), now);
+        claim_decoy(&mut tcp_state, now);
+        tcp_state.handle_frame(&test_frames::tcp_syn(MacAddr::zero()), now);
+        captured.take();
+
+        tcp_state.sweep_if_due(now + config.scanners.window());
+
+        let events = captured.take();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["event"], "scanner_classified");
+        assert_eq!(events[0]["scanner_ip"], test_frames::SCANNER_IP.to_string());
+        assert_eq!(events[0]["kind"], "single_probe");
+        assert_eq!(events[0]["syns"], 1);
+        assert!(metrics
+            .render()
+            .contains("\nants_scanners_total{kind=\"single_probe\"} 1\n"));
+    }
+
+    #[test]
+    fn test_metrics_of_a_held_flow() {
+        let now = Instant::now();
+        let mut config = Config::default();
+        config.tcp.persist = true;
+        let metrics = Arc::new(Metrics::default());
+        let mut tcp_state = tcp_state_with(
+            &config,
+            EventLog::new(None, now, Duration::ZERO),
+            metrics.clone(),
+            now,
+        );
+        let claim = claim_decoy(&mut tcp_state, now);
+        let syn_ack =
+            send_reply(&mut tcp_state, &test_frames::tcp_syn(MacAddr::zero()), now).unwrap();
+        let isn = packet_decoder::decode_tcp_segment(&syn_ack)
+            .unwrap()
+            .sequence;
+        let ack = test_frames::tcp_ack(MacAddr::zero(), isn.wrapping_add(1));
+        send_reply(&mut tcp_state, &ack, now).unwrap();
+
+        let text = metrics.render();
+        assert!(text.contains("\nants_claimed_ips 1\n"));
+        assert!(text.contains("\nants_syns_total 1\n"));
+        assert!(text.contains("\nants_syn_acks_total 1\n"));
+        assert!(text.contains("\nants_held_flows 1\n"));
+
+        // A reply the transmit queue refuses is counted as dropped, not sent
+        let syn = tcp_state
+            .handle_frame(&test_frames::tcp_syn(MacAddr::zero()), now)
+            .unwrap();
+        tcp_state.queue_reply(syn, now, |_, _| false);
+        let text = metrics.render();
+        assert!(text.contains("\nants_syn_acks_total 1\n"));
+        assert!(text.contains("\nants_transmit_dropped_total 1\n"));
+
+        tcp_state.apply_claim_event(
+            ClaimEvent::Released(claim, ReleaseReason::OwnerReturned),
+            now,
+        );
+        let text = metrics.render();
+        assert!(text.contains("\nants_claimed_ips 0\n"));
+        assert!(text.contains("\nants_held_flows 0\n"));
+    }
+
+    #[test]
+    fn test_snapshot_restores_claim_times() {
+        let now = Instant::now();
+        let wall_now = Duration::from_secs(1_700_000_000);
+        let config = Config::default();
+        let mut tcp_state = tcp_state(&config, now);
+        claim_decoy(&mut tcp_state, now);
+        let claim = claim_decoy(&mut tcp_state, now + Duration::from_secs(30));
+
+        let snapshot = tcp_state.snapshot(now + Duration::from_secs(40), wall_now);
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].first_seen, 1_699_999_960);
+        assert_eq!(snapshot[0].last_seen, 1_699_999_990);
+        assert_eq!(snapshot[0].mac, MacAddr::zero());
+
+        // Restored 60 seconds after the snapshot the claim was last seen 70
+        // seconds ago, it expires 50 seconds later with claim_expiry_secs of 120
+        let later = now + Duration::from_secs(100);
+        let mut tcp_state = self::tcp_state(&config, now);
+        tcp_state.restore(snapshot, later, wall_now + Duration::from_secs(60));
+        assert!(tcp_state.ips_to_tarpit.contains_key(&claim));
+        tcp_state.sweep(later + Duration::from_secs(49));
+        assert!(tcp_state.ips_to_tarpit.contains_key(&claim));
+        tcp_state.sweep(later + Duration::from_secs(50));
+        assert!(tcp_state.ips_to_tarpit.is_empty());
+    }
+
+    #[test]
+    fn test_reloaded_policies_keep_claims_and_flows() {
+        let now = Instant::now();
+        let mut config = Config::default();
+        config.tcp.persist = true;
+        let mut tcp_state = tcp_state(&config, now);
+        let claim = claim_decoy(&mut tcp_state, now);
+        let syn = test_frames::tcp_syn(MacAddr::zero());
+        let syn_ack = tcp_state.handle_frame(&syn, now).unwrap().frame;
+        let isn = packet_decoder::decode_tcp_segment(&syn_ack)
+            .unwrap()
+            .sequence;
+        let ack = test_frames::tcp_ack(MacAddr::zero(), isn.wrapping_add(1));
+        tcp_state.handle_frame(&ack, now).unwrap();
+        tcp_state.take_capture_filter();
+
+        config.ports.default_action = PortAction::Drop;
+        config.icmp.enabled = false;
+        tcp_state.apply_policies(&Policies::new(&config));
+
+        assert_eq!(tcp_state.handle_frame(&syn, now), None);
+        assert!(tcp_state.handle_frame(&ack, now).is_some());
+        assert!(tcp_state.ips_to_tarpit.contains_key(&claim));
+        assert!(tcp_state.icmp_responder.is_none());
+        assert!(tcp_state.take_capture_filter().is_some());
+    }
+
+    #[test]
+    fn test_capture_filter_rebuilt_on_claim_changes() {
+        let config = Config::default();
+        let now = Instant::now();
+        let mut tcp_state = tcp_state(&config, now);
+
+        assert_eq!(
+            tcp_state.take_capture_filter().as_deref(),
+            Some(packet_io::MATCH_NOTHING)
+        );
+        claim_decoy(&mut tcp_state, now);
+        assert!(tcp_state
+            .take_capture_filter()
+            .unwrap()
+            .contains("192.168.0.2"));
+
+        // Claiming an IP again only refreshes it
+        claim_decoy(&mut tcp_state, now);
+        assert_eq!(tcp_state.take_capture_filter(), None);
+
+        // A filter that fails to install is replaced by one for any destination
+        tcp_state.capture_filter_failed();
+        assert_eq!(
+            tcp_state.take_capture_filter(),
+            Some(any_destination_filter(false, false))
+        );
+        tcp_state.capture_filter_failed();
+        assert_eq!(tcp_state.take_capture_filter(), None);
+
+        tcp_state.sweep_if_due(now + config.tcp.claim_expiry());
+        assert_eq!(
+            tcp_state.take_capture_filter().as_deref(),
+            Some(packet_io::MATCH_NOTHING)
+        );
+    }
 }
@@ -0,0 +1,377 @@
+use crate::claims;
+use crate::config::{Os, PersonalityConfig};
+use crate::packet_decoder::{
+    SynOptions, TcpSegment, TCP_OPTION_MSS, TCP_OPTION_NOP, TCP_OPTION_SACK_PERMITTED,
+    TCP_OPTION_TIMESTAMP, TCP_OPTION_WINDOW_SCALE,
+};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// Rate of the TCP timestamp clock, 1 ms as on Linux and Windows
+const TIMESTAMP_HZ: u128 = 1000;
+
+/// Nanoseconds per tick of the ISN clock of decoys without a personality,
+/// the 4 µs of RFC 793
+pub const PLAIN_ISN_TICK_NS: u128 = 4000;
+
+/// TCP options a stack lays out together in its SYN/ACKs, each group is
+/// padded to a multiple of 4 bytes with NOPs in front
+#[derive(Clone, Copy, Debug)]
+enum OptionGroup {
+    Mss,
+    /// SACK permitted and timestamps share 4 byte words when both are offered
+    SackTimestamp,
+    WindowScale,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IpIdMode {
+    Zero,
+    Incremental,
+    Random,
+}
+
+/// How an OS fills in the fields nmap -O and p0f look at
+#[derive(Debug)]
+struct Personality {
+    ttl: u8,
+    dont_fragment: bool,
+    ip_id: IpIdMode,
+    window: u16,
+    mss: u16,
+    window_scale: u8,
+    options: &'static [OptionGroup],
+    /// Nanoseconds per tick of the clock added to the ISN
+    isn_tick_ns: u128,
+}
+
+const LINUX: Personality = Personality {
+    ttl: 64,
+    dont_fragment: true,
+    ip_id: IpIdMode::Zero,
+    window: 65160,
+    mss: 1460,
+    window_scale: 7,
+    options: &[
+        OptionGroup::Mss,
+        OptionGroup::SackTimestamp,
+        OptionGroup::WindowScale,
+    ],
+    isn_tick_ns: 64,
+};
+
+const WINDOWS: Personality = Personality {
+    ttl: 128,
+    dont_fragment: true,
+    ip_id: IpIdMode::Incremental,
+    window: 65535,
+    mss: 1460,
+    window_scale: 8,
+    options: &[
+        OptionGroup::Mss,
+        OptionGroup::WindowScale,
+        OptionGroup::SackTimestamp,
+    ],
+    isn_tick_ns: 100,
+};
+
+const CISCO_IOS: Personality = Personality {
+    ttl: 255,
+    dont_fragment: false,
+    ip_id: IpIdMode::Random,
+    window: 4128,
+    mss: 536,
+    window_scale: 0,
+    options: &[OptionGroup::Mss],
+    isn_tick_ns: 4000,
+};
+
+fn personality(os: Os) -> &'static Personality {
+    match os {
+        Os::Linux => &LINUX,
+        Os::Windows => &WINDOWS,
+        Os::CiscoIos => &CISCO_IOS,
+    }
+}
+
+/// IP header fields of a reply, the hop limit on IPv6
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpFields {
+    pub ttl: u8,
+    pub dont_fragment: bool,
+    pub id: u16,
+}
+
+impl IpFields {
+    /// What every decoy sends without personalities
+    pub const PLAIN: IpFields = IpFields {
+        ttl: 64,
+        dont_fragment: true,
+        id: 0,
+    };
+}
+
+/// The fields of a SYN/ACK that follow the decoy's personality
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SynAckFields {
+    pub ip: IpFields,
+    pub isn: u32,
+    pub window: u16,
+    /// Encoded TCP options, padded to a multiple of 4 bytes
+    pub options: Vec<u8>,
+}
+
+impl SynAckFields {
+    /// What every decoy sends without personalities
+    pub fn plain(isn: u32, window: u16) -> Self {
+        SynAckFields {
+            ip: IpFields::PLAIN,
+            isn,
+            window,
+            options: Vec::new(),
+        }
+    }
+}
+
+/// Gives every claimed IP one of the configured personalities and keeps
+/// the counters and clocks their replies are built from
+pub struct Personalities {
+    config: PersonalityConfig,
+    plain_window: u16,
+    /// Keys the hashes timestamps and random IP IDs are made from
+    secret: RandomState,
+    started: Instant,
+    ip_id: u16,
+}
+
+impl Personalities {
+    /// Without any OS configured, SYN/ACKs advertise plain_window
+    pub fn new(config: &PersonalityConfig, plain_window: u16, now: Instant) -> Self {
+        Personalities {
+            config: config.clone(),
+            plain_window,
+            secret: RandomState::new(),
+            started: now,
+            ip_id: 0,
+        }
+    }
+
+    /// Keeps the secret and clocks, decoys whose OS stays the same keep
+    /// answering consistently
+    pub fn set_config(&mut self, config: &PersonalityConfig, plain_window: u16) {
+        self.config = config.clone();
+        self.plain_window = plain_window;
+    }
+
+    fn personality(&self, decoy_ip: IpAddr) -> Option<&'static Personality> {
+        let os = &self.config.os;
+        if os.is_empty() {
+            return None;
+        }
+        let index = claims::ip_hash(self.config.seed, decoy_ip) % os.len() as u64;
+        Some(personality(os[index as usize]))
+    }
+
+    /// Nanoseconds per tick of the clock the ISNs of decoy_ip advance with
+    pub fn isn_tick_ns(&self, decoy_ip: IpAddr) -> u128 {
+        self.personality(decoy_ip)
+            .map_or(PLAIN_ISN_TICK_NS, |personality| personality.isn_tick_ns)
+    }
+
+    /// IP header fields of the next reply from decoy_ip
+    pub fn ip_fields(&mut self, decoy_ip: IpAddr) -> IpFields {
+        let Some(personality) = self.personality(decoy_ip) else {
+            return IpFields::PLAIN;
+        };
+        let id = match personality.ip_id {
+            IpIdMode::Zero => 0,
+            IpIdMode::Incremental => {
+                self.ip_id = self.ip_id.wrapping_add(1);
+                self.ip_id
+            }
+            IpIdMode::Random => {
+                self.ip_id = self.ip_id.wrapping_add(1);
+                self.secret.hash_one(self.ip_id) as u16
+            }
+        };
+        IpFields {
+            ttl: personality.ttl,
+            dont_fragment: personality.dont_fragment,
+            id,
+        }
+    }
+
+    /// The fields of the SYN/ACK answering syn with isn, only options
+    /// the SYN offered are answered
+    pub fn syn_ack(&mut self, syn: &TcpSegment, isn: u32, now: Instant) -> SynAckFields {
+        let ip = self.ip_fields(syn.dst_ip);
+        let Some(personality) = self.personality(syn.dst_ip) else {
+            return SynAckFields::plain(isn, self.plain_window);
+        };
+        let elapsed_ns = now.duration_since(self.started).as_nanos();
+
+        // Timestamps start at a secret offset per pair of hosts
+        let timestamp = (self.secret.hash_one((syn.dst_ip, syn.src_ip)) as u32)
+            .wrapping_add((elapsed_ns * TIMESTAMP_HZ / 1_000_000_000) as u32);
+
+        SynAckFields {
+            ip,
+            isn,
+            window: personality.window,
+            options: encode_options(personality, &syn.options, timestamp),
+        }
+    }
+}
+
+fn encode_options(personality: &Personality, offered: &SynOptions, timestamp: u32) -> Vec<u8> {
+    let mut options = Vec::new();
+    for group in personality.options {
+        let mut encoded = Vec::new();
+        match group {
+            OptionGroup::Mss => {
+                encoded.extend([TCP_OPTION_MSS, 4]);
+                encoded.extend(personality.mss.to_be_bytes());
+            }
+            OptionGroup::SackTimestamp => {
+                if offered.sack_permitted {
+                    encoded.extend([TCP_OPTION_SACK_PERMITTED, 2]);
+                }
+                if let Some(echoed) = offered.timestamp {
+                    encoded.extend([TCP_OPTION_TIMESTAMP, 10]);
+                    encoded.extend(timestamp.to_be_bytes());
+                    encoded.extend(echoed.to_be_bytes());
+                }
+            }
+            OptionGroup::WindowScale if offered.window_scale => {
+                encoded.extend([TCP_OPTION_WINDOW_SCALE, 3, personality.window_scale]);
+            }
+            OptionGroup::WindowScale => {}
+        }
+        let padding = (4 - encoded.len() % 4) % 4;
+        options.resize(options.len() + padding, TCP_OPTION_NOP);
+        options.extend(encoded);
+    }
+    options
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_decoder;
+    use crate::test_frames::{self, DECOY_IP};
+    use pnet_base::MacAddr;
+
+    fn personalities(os: &[Os]) -> Personalities {
+        let config = PersonalityConfig {
+            os: os.to_vec(),
+            seed: 0,
+        };
+        Personalities::new(&config, 1024, Instant::now())
+    }
+
+    fn nmap_syn() -> TcpSegment {
+        let mut syn =
+            packet_decoder::decode_tcp_segment(&test_frames::tcp_syn(MacAddr::zero())).unwrap();
+        syn.options = SynOptions {
+            mss: Some(1460),
+            sack_permitted: true,
+            window_scale: true,
+            timestamp: Some(0xffff_ffff),
+        };
+        syn
+    }
+
+    #[test]
+    fn test_plain_syn_ack_without_personalities() {
+        let mut personalities = personalities(&[]);
+
+        let syn_ack = personalities.syn_ack(&nmap_syn(), 1, Instant::now());
+
+        assert_eq!(syn_ack, SynAckFields::plain(1, 1024));
+        assert_eq!(
+            personalities.ip_fields(IpAddr::V4(DECOY_IP)),
+            IpFields::PLAIN
+        );
+    }
+
+    #[test]
+    fn test_linux_syn_ack() {
+        let mut personalities = personalities(&[Os::Linux]);
+        let now = Instant::now();
+
+        let syn_ack = personalities.syn_ack(&nmap_syn(), 1, now);
+
+        assert_eq!(syn_ack.ip, IpFields::PLAIN);
+        assert_eq!(syn_ack.window, 65160);
+        assert_eq!(personalities.isn_tick_ns(IpAddr::V4(DECOY_IP)), 64);
+        // MSS, SACK permitted, timestamps echoing the SYN's, NOP, window scale
+        assert_eq!(syn_ack.options.len(), 20);
+        assert_eq!(syn_ack.options[..6], [2, 4, 0x05, 0xb4, 4, 2]);
+        assert_eq!(syn_ack.options[6..8], [8, 10]);
+        assert_eq!(
+            syn_ack.options[12..20],
+            [0xff, 0xff, 0xff, 0xff, 1, 3, 3, 7]
+        );
+    }
+
+    #[test]
+    fn test_options_not_offered_are_left_out() {
+        let mut personalities = personalities(&[Os::Windows]);
+        let mut syn = nmap_syn();
+        syn.options = SynOptions::default();
+
+        let syn_ack = personalities.syn_ack(&syn, 1, Instant::now());
+
+        assert_eq!(syn_ack.options, [2, 4, 0x05, 0xb4]);
+        assert_eq!(syn_ack.ip.ttl, 128);
+        let next_ip_id = personalities.ip_fields(IpAddr::V4(DECOY_IP)).id;
+        assert_eq!(next_ip_id, syn_ack.ip.id.wrapping_add(1));
+    }
+
+    #[test]
+    fn test_options_laid_out_for_offered_subset() {
+        let mut linux = personalities(&[Os::Linux]);
+        let mut windows = personalities(&[Os::Windows]);
+        let mut syn = nmap_syn();
+
+        // Linux without timestamps: MSS, NOP, NOP, SACK permitted, NOP, window scale
+        syn.options.timestamp = None;
+        let syn_ack = linux.syn_ack(&syn, 1, Instant::now());
+        assert_eq!(syn_ack.options, [2, 4, 0x05, 0xb4, 1, 1, 4, 2, 1, 3, 3, 7]);
+
+        // Linux without SACK: MSS, NOP, NOP, timestamps, NOP, window scale
+        syn.options.timestamp = Some(0xffff_ffff);
+        syn.options.sack_permitted = false;
+        let syn_ack = linux.syn_ack(&syn, 1, Instant::now());
+        assert_eq!(syn_ack.options[..8], [2, 4, 0x05, 0xb4, 1, 1, 8, 10]);
+        assert_eq!(syn_ack.options[16..], [1, 3, 3, 7]);
+
+        // Windows without SACK: MSS, NOP, window scale, NOP, NOP, timestamps
+        let syn_ack = windows.syn_ack(&syn, 1, Instant::now());
+        assert_eq!(syn_ack.options.len(), 20);
+        assert_eq!(syn_ack.options[..10], [2, 4, 0x05, 0xb4, 1, 3, 3, 8, 1, 1]);
+        assert_eq!(syn_ack.options[10..12], [8, 10]);
+
+        // Windows without window scale: MSS, SACK permitted, timestamps
+        syn.options.sack_permitted = true;
+        syn.options.window_scale = false;
+        let syn_ack = windows.syn_ack(&syn, 1, Instant::now());
+        assert_eq!(syn_ack.options.len(), 16);
+        assert_eq!(syn_ack.options[..8], [2, 4, 0x05, 0xb4, 4, 2, 8, 10]);
+    }
+
+    #[test]
+    fn test_cisco_ios_syn_ack() {
+        let mut personalities = personalities(&[Os::CiscoIos]);
+
+        let syn_ack = personalities.syn_ack(&nmap_syn(), 1, Instant::now());
+
+        assert_eq!(syn_ack.ip.ttl, 255);
+        assert!(!syn_ack.ip.dont_fragment);
+        assert_eq!(personalities.isn_tick_ns(IpAddr::V4(DECOY_IP)), 4000);
+        assert_eq!(syn_ack.window, 4128);
+        assert_eq!(syn_ack.options, [2, 4, 0x02, 0x18]);
+    }
+}
@@ -0,0 +1,155 @@
+use super::flow_table::FlowKey;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::time::{Duration, Instant};
+
+/// Length of the time slot a cookie is made in
+const SLOT: Duration = Duration::from_secs(64);
+/// Slots a cookie stays valid for, the one it was made in included
+const SLOTS_VALID: u64 = 2;
+
+/// Initial sequence numbers made of a keyed hash of the connection plus
+/// a clock that moves on every time slot, as in RFC 6528. The clock runs
+/// at the rate of the decoy's personality. The ACK completing a handshake
+/// is checked without remembering the SYN.
+pub struct SynCookies {
+    secret: RandomState,
+    started: Instant,
+}
+
+impl SynCookies {
+    pub fn new(now: Instant) -> Self {
+        SynCookies {
+            secret: RandomState::new(),
+            started: now,
+        }
+    }
+
+    /// The ISN of the SYN/ACK answering a SYN on key, from a clock with
+    /// ticks of tick_ns nanoseconds
+    pub fn isn(&self, key: &FlowKey, tick_ns: u128, now: Instant) -> u32 {
+        self.cookie(key, self.slot(now), tick_ns)
+    }
+
+    /// True if isn was handed out for key within the last SLOTS_VALID slots
+    pub fn validate(&self, key: &FlowKey, isn: u32, tick_ns: u128, now: Instant) -> bool {
+        let current = self.slot(now);
+        (0..SLOTS_VALID)
+            .filter(|age| *age <= current)
+            .any(|age| self.cookie(key, current - age, tick_ns) == isn)
+    }
+
+    fn slot(&self, now: Instant) -> u64 {
+        now.duration_since(self.started).as_secs() / SLOT.as_secs()
+    }
+
+    /// A secret offset per connection plus the clock at the start of
+    /// slot, no bits are shared by all cookies of a slot
+    fn cookie(&self, key: &FlowKey, slot: u64, tick_ns: u128) -> u32 {
+        let offset = self.secret.hash_one(key) as u32;
+        let clock = u128::from(slot) * SLOT.as_nanos() / tick_ns;
+        offset.wrapping_add(clock as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_decoder::VlanTags;
+    use crate::tcp_listener::personality::PLAIN_ISN_TICK_NS;
+    use std::collections::HashSet;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn key() -> FlowKey {
+        FlowKey {
+            vlan_tags: VlanTags::default(),
+            scanner_ip: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            scanner_port: 40000,
+            decoy_ip: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)),
+            decoy_port: 22,
+        }
+    }
+
+    #[test]
+    fn test_cookie_valid_for_two_slots() {
+        let now = Instant::now();
+        let syn_cookies = SynCookies::new(now);
+        let isn = syn_cookies.isn(&key(), PLAIN_ISN_TICK_NS, now + Duration::from_secs(10));
+
+        assert!(syn_cookies.validate(
+            &key(),
+            isn,
+            PLAIN_ISN_TICK_NS,
+            now + Duration::from_secs(10)
+        ));
+        assert!(syn_cookies.validate(
+            &key(),
+            isn,
+            PLAIN_ISN_TICK_NS,
+            now + Duration::from_secs(100)
+        ));
+        assert!(!syn_cookies.validate(
+            &key(),
+            isn,
+            PLAIN_ISN_TICK_NS,
+            now + Duration::from_secs(130)
+        ));
+        assert!(!syn_cookies.validate(
+            &key(),
+            isn,
+            PLAIN_ISN_TICK_NS,
+            now + SLOT * 32 + Duration::from_secs(10)
+        ));
+    }
+
+    #[test]
+    fn test_cookie_bound_to_connection() {
+        let now = Instant::now();
+        let syn_cookies = SynCookies::new(now);
+        let isn = syn_cookies.isn(&key(), PLAIN_ISN_TICK_NS, now);
+        let other_port = FlowKey {
+            scanner_port: 40001,
+            ..key()
+        };
+
+        assert!(!syn_cookies.validate(&other_port, isn, PLAIN_ISN_TICK_NS, now));
+        assert!(!syn_cookies.validate(&key(), isn.wrapping_add(1), PLAIN_ISN_TICK_NS, now));
+        assert_ne!(syn_cookies.isn(&other_port, PLAIN_ISN_TICK_NS, now), isn);
+    }
+
+    #[test]
+    fn test_cookies_in_one_slot_do_not_share_high_bits() {
+        let now = Instant::now();
+        let syn_cookies = SynCookies::new(now);
+
+        let high_bits = (40000..40064)
+            .map(|scanner_port| {
+                let key = FlowKey {
+                    scanner_port,
+                    ..key()
+                };
+                syn_cookies.isn(&key, PLAIN_ISN_TICK_NS, now) >> 27
+            })
+            .collect::<HashSet<_>>();
+
+        assert!(high_bits.len() > 1);
+    }
+
+    #[test]
+    fn test_isn_advances_with_clock() {
+        let now = Instant::now();
+        let syn_cookies = SynCookies::new(now);
+        let advance = |tick_ns| {
+            let isn = syn_cookies.isn(&key(), tick_ns, now);
+            syn_cookies
+                .isn(&key(), tick_ns, now + SLOT)
+                .wrapping_sub(isn)
+        };
+
+        assert_eq!(advance(PLAIN_ISN_TICK_NS), 16_000_000);
+        assert_eq!(advance(64), 1_000_000_000);
+        // A cookie only validates with the clock it was made with
+        let isn = syn_cookies.isn(&key(), 64, now + SLOT);
+        assert!(!syn_cookies.validate(&key(), isn, PLAIN_ISN_TICK_NS, now + SLOT));
+    }
+}
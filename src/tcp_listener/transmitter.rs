@@ -0,0 +1,242 @@
+use crate::packet_io::{self, Frame, PacketIo};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, error};
+
+/// Upper bound on replies waiting to be sent, frames beyond this are refused
+pub const DEFAULT_QUEUE_CAPACITY: usize = 65536;
+
+/// A frame waiting in the delay queue, ordered by send time and arrival order
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct ScheduledFrame {
+    send_at: Instant,
+    sequence: u64,
+    frame: Vec<u8>,
+}
+
+/// Handle to the single transmit thread of an interface
+#[derive(Clone)]
+pub struct Transmitter {
+    queue: mpsc::SyncSender<ScheduledFrame>,
+    /// Frames scheduled and not yet sent, in the channel or the delay queue
+    queued: Arc<AtomicUsize>,
+    capacity: usize,
+}
+
+impl Transmitter {
+    /// Queues a frame to be sent after delay, returns false when the queue
+    /// is full. A frame accepted here is sent.
+    pub fn schedule(&self, frame: Vec<u8>, delay: Duration) -> bool {
+        if self.queued.fetch_add(1, Ordering::Relaxed) >= self.capacity {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            debug!("Transmit queue full, dropping reply");
+            return false;
+        }
+        let scheduled = ScheduledFrame {
+            send_at: Instant::now() + delay,
+            sequence: 0,
+            frame,
+        };
+        match self.queue.try_send(scheduled) {
+            Ok(()) => true,
+            // Only reached once the thread has exited, queued keeps the channel from filling up
+            Err(_) => {
+                self.queued.fetch_sub(1, Ordering::Relaxed);
+                error!("Transmit thread has exited, dropping reply");
+                false
+            }
+        }
+    }
+}
+
+/// Spawns the thread that sends queued frames through packet_io
+/// once their delay has passed. The thread ends once every Transmitter
+/// is dropped and the frames still queued have been sent.
+pub fn start_transmitter(
+    mut packet_io: Box<dyn PacketIo>,
+    capacity: usize,
+) -> (Transmitter, thread::JoinHandle<()>) {
+    // Nothing is read from this handle, keep the kernel from queueing frames for it
+    if let Err(e) = packet_io.set_filter(packet_io::MATCH_NOTHING) {
+        error!("Failed to install transmit capture filter: {}", e);
+    }
+
+    let (queue, pending) = mpsc::sync_channel(capacity);
+    let queued = Arc::new(AtomicUsize::new(0));
+    let sent = queued.clone();
+    let thread = thread::spawn(move || run_transmitter(packet_io, pending, sent));
+
+    let transmitter = Transmitter {
+        queue,
+        queued,
+        capacity,
+    };
+    (transmitter, thread)
+}
+
+fn run_transmitter(
+    mut packet_io: Box<dyn PacketIo>,
+    pending: mpsc::Receiver<ScheduledFrame>,
+    queued: Arc<AtomicUsize>,
+) {
+    let mut delay_queue = DelayQueue::new();
+
+    loop {
+        let received = match delay_queue.next_deadline() {
+            Some(deadline) => {
+                pending.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            }
+            None => pending
+                .recv()
+                .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+
+        match received {
+            Ok(scheduled) => delay_queue.push(scheduled),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                debug!("All transmit handles dropped, exiting transmit thread");
+                drain(packet_io.as_mut(), &mut delay_queue, &queued);
+                break;
+            }
+        }
+
+        // Move everything already waiting in the channel to the heap so the
+        // channel keeps accepting new replies
+        while let Ok(scheduled) = pending.try_recv() {
+            delay_queue.push(scheduled);
+        }
+
+        send_due(packet_io.as_mut(), &mut delay_queue, &queued);
+    }
+}
+
+/// Sends the frames left in delay_queue, each at its send time
+fn drain(packet_io: &mut dyn PacketIo, delay_queue: &mut DelayQueue, queued: &AtomicUsize) {
+    while let Some(deadline) = delay_queue.next_deadline() {
+        thread::sleep(deadline.saturating_duration_since(Instant::now()));
+        send_due(packet_io, delay_queue, queued);
+    }
+}
+
+/// Sends the frames whose send time has passed and frees their place in the queue
+fn send_due(packet_io: &mut dyn PacketIo, delay_queue: &mut DelayQueue, queued: &AtomicUsize) {
+    let due = delay_queue.pop_due(Instant::now());
+    let frames: Vec<Frame<'_>> = due.iter().map(|frame| Frame::now(frame)).collect();
+    if let Err(e) = packet_io.send_batch(&frames) {
+        error!("Failed to send frames: {}", e);
+    }
+    queued.fetch_sub(due.len(), Ordering::Relaxed);
+}
+
+/// Min-heap of frames keyed on their send time, Transmitter::schedule
+/// keeps it within capacity
+struct DelayQueue {
+    heap: BinaryHeap<Reverse<ScheduledFrame>>,
+    next_sequence: u64,
+}
+
+impl DelayQueue {
+    fn new() -> Self {
+        DelayQueue {
+            heap: BinaryHeap::new(),
+            next_sequence: 0,
+        }
+    }
+
+    fn push(&mut self, mut scheduled: ScheduledFrame) {
+        scheduled.sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        self.heap.push(Reverse(scheduled));
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.heap.peek().map(|Reverse(scheduled)| scheduled.send_at)
+    }
+
+    fn pop_due(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut due = Vec::new();
+        while self
+            .heap
+            .peek()
+            .is_some_and(|Reverse(scheduled)| scheduled.send_at <= now)
+        {
+            let Reverse(scheduled) = self.heap.pop().unwrap();
+            due.push(scheduled.frame);
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_io::MemoryNetwork;
+
+    fn scheduled(send_at: Instant, frame: u8) -> ScheduledFrame {
+        ScheduledFrame {
+            send_at,
+            sequence: 0,
+            frame: vec![frame],
+        }
+    }
+
+    #[test]
+    fn test_delay_queue_orders_by_send_time() {
+        let mut delay_queue = DelayQueue::new();
+        let now = Instant::now();
+
+        delay_queue.push(scheduled(now + Duration::from_millis(500), 1));
+        delay_queue.push(scheduled(now, 2));
+
+        assert_eq!(delay_queue.next_deadline(), Some(now));
+        assert_eq!(delay_queue.pop_due(now), vec![vec![2]]);
+        assert!(delay_queue.pop_due(now).is_empty());
+        assert_eq!(
+            delay_queue.pop_due(now + Duration::from_millis(500)),
+            vec![vec![1]]
+        );
+    }
+
+    #[test]
+    fn test_delay_queue_keeps_arrival_order_for_same_deadline() {
+        let mut delay_queue = DelayQueue::new();
+        let now = Instant::now();
+
+        delay_queue.push(scheduled(now, 3));
+        delay_queue.push(scheduled(now, 1));
+        delay_queue.push(scheduled(now, 2));
+
+        assert_eq!(delay_queue.pop_due(now), vec![vec![3], vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn test_queued_frames_sent_before_exit() {
+        let (network, sent_frames) = MemoryNetwork::new();
+        let (transmitter, thread) = start_transmitter(Box::new(network.attach()), 10);
+
+        transmitter.schedule(vec![1], Duration::from_millis(50));
+        drop(transmitter);
+        thread.join().unwrap();
+
+        assert_eq!(sent_frames.try_recv(), Ok(vec![1]));
+    }
+
+    #[test]
+    fn test_schedule_refuses_beyond_capacity() {
+        let (network, sent_frames) = MemoryNetwork::new();
+        let (transmitter, thread) = start_transmitter(Box::new(network.attach()), 1);
+
+        assert!(transmitter.schedule(vec![1], Duration::from_millis(50)));
+        assert!(!transmitter.schedule(vec![2], Duration::ZERO));
+        drop(transmitter);
+        thread.join().unwrap();
+
+        assert_eq!(sent_frames.try_recv(), Ok(vec![1]));
+        assert!(sent_frames.try_recv().is_err());
+    }
+}
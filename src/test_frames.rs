@@ -0,0 +1,109 @@
+//! Frames a scanner sends, shared by tests that drive ANTS end to end
+
+use pnet_base::MacAddr;
+use pnet_packet::arp::{ArpHardwareTypes, ArpOperations, MutableArpPacket};
+use pnet_packet::ethernet::{EtherTypes, MutableEthernetPacket};
+use pnet_packet::icmp::{self, IcmpTypes, MutableIcmpPacket};
+use pnet_packet::ip::IpNextHeaderProtocols;
+use pnet_packet::ipv4::{self, MutableIpv4Packet};
+use pnet_packet::tcp::{self, MutableTcpPacket, TcpFlags};
+use std::net::Ipv4Addr;
+
+pub const SCANNER_MAC: MacAddr = MacAddr(0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB);
+pub const SCANNER_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 100);
+pub const DECOY_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+
+/// Broadcast ARP request from the scanner for the decoy IP
+pub fn arp_request() -> Vec<u8> {
+    let mut frame = vec![0u8; 42];
+    {
+        let mut ethernet_packet = MutableEthernetPacket::new(&mut frame).unwrap();
+        ethernet_packet.set_source(SCANNER_MAC);
+        ethernet_packet.set_destination(MacAddr::broadcast());
+        ethernet_packet.set_ethertype(EtherTypes::Arp);
+    }
+    let mut arp_packet = MutableArpPacket::new(&mut frame[14..]).unwrap();
+    arp_packet.set_hardware_type(ArpHardwareTypes::Ethernet);
+    arp_packet.set_protocol_type(EtherTypes::Ipv4);
+    arp_packet.set_hw_addr_len(6);
+    arp_packet.set_proto_addr_len(4);
+    arp_packet.set_operation(ArpOperations::Request);
+    arp_packet.set_sender_hw_addr(SCANNER_MAC);
+    arp_packet.set_sender_proto_addr(SCANNER_IP);
+    arp_packet.set_target_proto_addr(DECOY_IP);
+    frame
+}
+
+/// SYN from the scanner to port 22 of the decoy IP, sent to decoy_mac
+pub fn tcp_syn(decoy_mac: MacAddr) -> Vec<u8> {
+    tcp_segment(decoy_mac, TcpFlags::SYN, 41, 0)
+}
+
+/// ACK from the scanner completing the handshake the SYN above started
+pub fn tcp_ack(decoy_mac: MacAddr, acknowledgement: u32) -> Vec<u8> {
+    tcp_segment(decoy_mac, TcpFlags::ACK, 42, acknowledgement)
+}
+
+fn tcp_segment(decoy_mac: MacAddr, flags: u8, sequence: u32, acknowledgement: u32) -> Vec<u8> {
+    let mut frame = vec![0u8; 54];
+    {
+        let mut ethernet_packet = MutableEthernetPacket::new(&mut frame).unwrap();
+        ethernet_packet.set_source(SCANNER_MAC);
+        ethernet_packet.set_destination(decoy_mac);
+        ethernet_packet.set_ethertype(EtherTypes::Ipv4);
+    }
+    {
+        let mut tcp_packet = MutableTcpPacket::new(&mut frame[34..]).unwrap();
+        tcp_packet.set_source(40000);
+        tcp_packet.set_destination(22);
+        tcp_packet.set_sequence(sequence);
+        tcp_packet.set_acknowledgement(acknowledgement);
+        tcp_packet.set_data_offset(5);
+        tcp_packet.set_flags(flags);
+        tcp_packet.set_window(1024);
+        let checksum = tcp::ipv4_checksum(&tcp_packet.to_immutable(), &SCANNER_IP, &DECOY_IP);
+        tcp_packet.set_checksum(checksum);
+    }
+    let mut ipv4_packet = MutableIpv4Packet::new(&mut frame[14..34]).unwrap();
+    ipv4_packet.set_version(4);
+    ipv4_packet.set_header_length(5);
+    ipv4_packet.set_total_length(40);
+    ipv4_packet.set_ttl(64);
+    ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+    ipv4_packet.set_source(SCANNER_IP);
+    ipv4_packet.set_destination(DECOY_IP);
+    let checksum = ipv4::checksum(&ipv4_packet.to_immutable());
+    ipv4_packet.set_checksum(checksum);
+    frame
+}
+
+/// Ping from the scanner to the decoy IP carrying payload, sent to decoy_mac
+pub fn icmp_echo_request(decoy_mac: MacAddr, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0u8; 42 + payload.len()];
+    {
+        let mut ethernet_packet = MutableEthernetPacket::new(&mut frame).unwrap();
+        ethernet_packet.set_source(SCANNER_MAC);
+        ethernet_packet.set_destination(decoy_mac);
+        ethernet_packet.set_ethertype(EtherTypes::Ipv4);
+    }
+    {
+        // Identifier and sequence number
+        frame[38..42].copy_from_slice(&[0x12, 0x34, 0x00, 0x01]);
+        frame[42..].copy_from_slice(payload);
+        let mut icmp_packet = MutableIcmpPacket::new(&mut frame[34..]).unwrap();
+        icmp_packet.set_icmp_type(IcmpTypes::EchoRequest);
+        let checksum = icmp::checksum(&icmp_packet.to_immutable());
+        icmp_packet.set_checksum(checksum);
+    }
+    let mut ipv4_packet = MutableIpv4Packet::new(&mut frame[14..34]).unwrap();
+    ipv4_packet.set_version(4);
+    ipv4_packet.set_header_length(5);
+    ipv4_packet.set_total_length((28 + payload.len()) as u16);
+    ipv4_packet.set_ttl(64);
+    ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+    ipv4_packet.set_source(SCANNER_IP);
+    ipv4_packet.set_destination(DECOY_IP);
+    let checksum = ipv4::checksum(&ipv4_packet.to_immutable());
+    ipv4_packet.set_checksum(checksum);
+    frame
+}